@@ -0,0 +1,532 @@
+use bson::{Bson, Document};
+use std::collections::HashSet;
+use std::f64::consts::PI;
+use std::fmt;
+
+use crate::database::bson_to_f64;
+use crate::query::Aggregate;
+
+// t-digest compression: larger keeps more centroids and gives more accurate percentiles.
+const TDIGEST_COMPRESSION : f64 = 100.0;
+
+// How many raw values a t-digest buffers before merging them into its centroids.
+const TDIGEST_BUFFER_LEN : usize = 500;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AggregateFunction {
+    // Rows with any value for the column.
+    Count,
+    CountDistinct,
+    Sum,
+    Avg,
+    Min,
+    Max,
+    // Sample standard deviation and variance (dividing by n - 1).
+    Stddev,
+    Variance,
+    Median,
+    // Exact, interpolating between the two nearest values. Takes 0 to 100.
+    Percentile(f64),
+    // Estimated with a t-digest in bounded memory, for ranges too large to sort.
+    ApproxPercentile(f64),
+    // Value of the earliest and latest row, by timestamp.
+    First,
+    Last,
+    // MAX - MIN
+    Spread,
+}
+
+impl AggregateFunction {
+    pub const NAMES : [&'static str; 13] = [
+        "COUNT", "SUM", "AVG", "MIN", "MAX", "STDDEV", "VARIANCE", "MEDIAN",
+        "PERCENTILE", "APPROX_PERCENTILE", "FIRST", "LAST", "SPREAD",
+    ];
+
+    // From the query language: name(DISTINCT column) or name(column, argument).
+    pub fn from_parts(name: &str, distinct: bool, argument: Option<f64>) -> Result<AggregateFunction, String> {
+        let name = name.to_ascii_uppercase();
+
+        if distinct && name != "COUNT" {
+            return Err(format!("DISTINCT can only be used with COUNT, not {}", name));
+        }
+
+        let percentile = |p: Option<f64>| match p {
+            Some(p) if (0.0..=100.0).contains(&p) => Ok(p),
+            Some(p) => Err(format!("{} is not a percentile between 0 and 100", p)),
+            None => Err(format!("{} needs a percentile, e.g. {}(column, 95)", name, name)),
+        };
+
+        let function = match name.as_str() {
+            "COUNT" if distinct => AggregateFunction::CountDistinct,
+            "COUNT" => AggregateFunction::Count,
+            "SUM" => AggregateFunction::Sum,
+            "AVG" | "MEAN" => AggregateFunction::Avg,
+            "MIN" => AggregateFunction::Min,
+            "MAX" => AggregateFunction::Max,
+            "STDDEV" => AggregateFunction::Stddev,
+            "VARIANCE" => AggregateFunction::Variance,
+            "MEDIAN" => AggregateFunction::Median,
+            "PERCENTILE" => return Ok(AggregateFunction::Percentile(percentile(argument)?)),
+            "APPROX_PERCENTILE" => return Ok(AggregateFunction::ApproxPercentile(percentile(argument)?)),
+            "FIRST" => AggregateFunction::First,
+            "LAST" => AggregateFunction::Last,
+            "SPREAD" => AggregateFunction::Spread,
+            _ => return Err(format!("unknown aggregate {}, must be one of: {}", name, AggregateFunction::NAMES.join(", "))),
+        };
+
+        if argument.is_some() {
+            return Err(format!("{} does not take a second argument", name));
+        }
+
+        Ok(function)
+    }
+
+    // From the '::' syntax, which spells arguments like the rest of it: COUNT_DISTINCT,
+    // PERCENTILE=95. This is also what Display prints.
+    pub fn from_name(op: &str) -> Result<AggregateFunction, String> {
+        let (name, argument) = match op.split_once('=') {
+            Some((name, argument)) => match argument.parse::<f64>() {
+                Ok(a) => (name, Some(a)),
+                Err(_) => return Err(format!("'{}' is not a number", argument)),
+            },
+            None => (op, None),
+        };
+
+        if name.eq_ignore_ascii_case("COUNT_DISTINCT") {
+            AggregateFunction::from_parts("COUNT", true, argument)
+        } else {
+            AggregateFunction::from_parts(name, false, argument)
+        }
+    }
+
+    pub fn is_count(&self) -> bool {
+        matches!(self, AggregateFunction::Count | AggregateFunction::CountDistinct)
+    }
+
+    // Column heading for this function over a column, e.g. PERCENTILE(number_sold, 95).
+    pub fn label(&self, field: &str) -> String {
+        match self {
+            AggregateFunction::CountDistinct => format!("COUNT(DISTINCT {})", field),
+            AggregateFunction::Percentile(p) => format!("PERCENTILE({}, {})", field, p),
+            AggregateFunction::ApproxPercentile(p) => format!("APPROX_PERCENTILE({}, {})", field, p),
+            _ => format!("{}({})", self, field),
+        }
+    }
+}
+
+impl fmt::Display for AggregateFunction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AggregateFunction::Count => write!(f, "COUNT"),
+            AggregateFunction::CountDistinct => write!(f, "COUNT_DISTINCT"),
+            AggregateFunction::Sum => write!(f, "SUM"),
+            AggregateFunction::Avg => write!(f, "AVG"),
+            AggregateFunction::Min => write!(f, "MIN"),
+            AggregateFunction::Max => write!(f, "MAX"),
+            AggregateFunction::Stddev => write!(f, "STDDEV"),
+            AggregateFunction::Variance => write!(f, "VARIANCE"),
+            AggregateFunction::Median => write!(f, "MEDIAN"),
+            AggregateFunction::Percentile(p) => write!(f, "PERCENTILE={}", p),
+            AggregateFunction::ApproxPercentile(p) => write!(f, "APPROX_PERCENTILE={}", p),
+            AggregateFunction::First => write!(f, "FIRST"),
+            AggregateFunction::Last => write!(f, "LAST"),
+            AggregateFunction::Spread => write!(f, "SPREAD"),
+        }
+    }
+}
+
+// What an aggregate came to: None when no row had a value to give (rather than 0, or the
+// f64::MAX an empty MIN starts from), and how many rows contributed.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct AggregateResult {
+    pub value: Option<f64>,
+    pub count: usize,
+}
+
+impl AggregateResult {
+    // The columns an aggregate adds to a row of query results, e.g.
+    // { "AVG(number_sold)" : 2.5, "count" : 4 }, with null for no value. Everything that
+    // shows aggregates goes through this, so they print the same everywhere.
+    pub fn to_document(&self, label: &str) -> Document {
+        let mut row = Document::new();
+        row.insert(label, self.value.map_or(Bson::Null, Bson::Double));
+        row.insert("count", self.count as i64);
+        row
+    }
+}
+
+// Accumulators for several aggregates over the same rows, so they can all be computed in one
+// scan.
+#[derive(Clone, Debug)]
+pub struct Accumulators {
+    aggregates: Vec<(Aggregate, Accumulator)>,
+    // Rows that contributed to at least one of the aggregates.
+    count: usize,
+}
+
+impl Accumulators {
+    pub fn new(aggregates: &[Aggregate]) -> Accumulators {
+        Accumulators {
+            aggregates: aggregates.iter().map(|a| (a.clone(), Accumulator::new(a.function))).collect(),
+            count: 0,
+        }
+    }
+
+    pub fn push_row(&mut self, row: &Document) {
+        let mut contributed = false;
+        for (aggregate, accumulator) in &mut self.aggregates {
+            if let Some(value) = aggregate.value(row) {
+                accumulator.push(value);
+                contributed = true;
+            }
+        }
+        if contributed {
+            self.count += 1;
+        }
+    }
+
+    pub fn results(&self) -> Vec<AggregateResult> {
+        self.aggregates.iter().map(|(_, accumulator)| accumulator.finish()).collect()
+    }
+
+    pub fn count(&self) -> usize {
+        self.count
+    }
+
+    pub fn to_document(&self) -> Document {
+        let values = self.results().iter().map(|r| r.value).collect::<Vec<Option<f64>>>();
+        aggregate_columns(self.aggregates.iter().map(|(a, _)| a), &values, self.count)
+    }
+}
+
+// One column per aggregate, then "count", e.g.
+// { "MIN(number_sold)" : 1.0, "MAX(number_sold)" : 9.0, "count" : 12 }
+pub fn aggregate_columns<'a>(aggregates: impl Iterator<Item = &'a Aggregate>, values: &[Option<f64>], count: usize) -> Document {
+    let mut row = Document::new();
+    for (aggregate, value) in aggregates.zip(values) {
+        row.insert(aggregate.label(), value.map_or(Bson::Null, Bson::Double));
+    }
+    row.insert("count", count as i64);
+    row
+}
+
+// What to put in time buckets that had no rows, instead of leaving them out.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Fill {
+    Null,
+    Value(f64),
+    // The last value before the gap.
+    Previous,
+    // On the straight line between the values either side of the gap, or null at either end.
+    Linear,
+}
+
+impl Fill {
+    // series holds one entry per bucket, evenly spaced and in order: None for a gap, or the
+    // value of a bucket that had rows (which may itself be null, e.g. MIN of no numbers).
+    // Gaps are filled; buckets that had rows keep their value. Null values are never used to
+    // fill a gap.
+    pub fn apply(&self, series: &[Option<Option<f64>>]) -> Vec<Option<f64>> {
+        let mut filled = Vec::with_capacity(series.len());
+        let mut previous : Option<(usize, f64)> = None;
+
+        // Next bucket with a value, from each bucket on, for linear interpolation
+        let mut next = vec![None; series.len() + 1];
+        for i in (0..series.len()).rev() {
+            next[i] = series[i].flatten().map(|v| (i, v)).or(next[i + 1]);
+        }
+
+        for (i, bucket) in series.iter().enumerate() {
+            let value = match (bucket, self) {
+                (Some(value), _) => *value,
+                (None, Fill::Null) => None,
+                (None, Fill::Value(v)) => Some(*v),
+                (None, Fill::Previous) => previous.map(|(_, v)| v),
+                (None, Fill::Linear) => {
+                    match (previous, next[i]) {
+                        (Some((p, pv)), Some((n, nv))) => Some(pv + (nv - pv) * (i - p) as f64 / (n - p) as f64),
+                        _ => None,
+                    }
+                }
+            };
+
+            if let Some(Some(v)) = bucket {
+                previous = Some((i, *v));
+            }
+            filled.push(value);
+        }
+
+        filled
+    }
+}
+
+// Only keeps what its function needs: a count, running moments, every value (for exact
+// percentiles), or a t-digest.
+#[derive(Clone, Debug)]
+enum State {
+    Count(usize),
+    Distinct(HashSet<String>),
+    // Welford's running mean and sum of squared deviations, which stay accurate for variance
+    // where summing squares would not.
+    Moments { count: usize, sum: f64, mean: f64, m2: f64, min: f64, max: f64 },
+    Values(Vec<f64>),
+    Digest(TDigest),
+    First(Option<f64>),
+    Last(Option<f64>),
+}
+
+// Running state for one aggregate over a group of rows. Values are pushed in key order.
+#[derive(Clone, Debug)]
+pub struct Accumulator {
+    function: AggregateFunction,
+    state: State,
+    // Values that were used, i.e. not skipped for not being numbers.
+    count: usize,
+}
+
+impl Accumulator {
+    pub fn new(function: AggregateFunction) -> Accumulator {
+        let state = match function {
+            AggregateFunction::Count => State::Count(0),
+            AggregateFunction::CountDistinct => State::Distinct(HashSet::new()),
+            AggregateFunction::Median | AggregateFunction::Percentile(_) => State::Values(Vec::new()),
+            AggregateFunction::ApproxPercentile(_) => State::Digest(TDigest::new(TDIGEST_COMPRESSION)),
+            AggregateFunction::First => State::First(None),
+            AggregateFunction::Last => State::Last(None),
+            _ => State::Moments {
+                count: 0,
+                sum: 0.0,
+                mean: 0.0,
+                m2: 0.0,
+                min: f64::INFINITY,
+                max: f64::NEG_INFINITY,
+            },
+        };
+
+        Accumulator { function, state, count: 0 }
+    }
+
+    // COUNT and COUNT DISTINCT take values of any type; everything else ignores values that
+    // are not numbers.
+    pub fn push(&mut self, value: &Bson) {
+        match &mut self.state {
+            State::Count(count) => *count += 1,
+            State::Distinct(seen) => {
+                seen.insert(value.to_string());
+            }
+            state => match bson_to_f64(value) {
+                Some(v) => push_number(state, v),
+                None => return,
+            },
+        }
+        self.count += 1;
+    }
+
+    pub fn finish(&self) -> AggregateResult {
+        AggregateResult { value: self.result(), count: self.count }
+    }
+
+    // None when nothing was pushed (other than for COUNT), or for a sample statistic over a
+    // single value.
+    pub fn result(&self) -> Option<f64> {
+        match (&self.state, self.function) {
+            (State::Count(count), _) => Some(*count as f64),
+            (State::Distinct(seen), _) => Some(seen.len() as f64),
+            (State::Moments { count: 0, .. }, _) => None,
+            (State::Moments { count, sum, m2, min, max, .. }, function) => match function {
+                AggregateFunction::Sum => Some(*sum),
+                AggregateFunction::Avg => Some(sum / *count as f64),
+                AggregateFunction::Min => Some(*min),
+                AggregateFunction::Max => Some(*max),
+                AggregateFunction::Spread => Some(max - min),
+                AggregateFunction::Variance if *count > 1 => Some(m2 / (*count - 1) as f64),
+                AggregateFunction::Stddev if *count > 1 => Some((m2 / (*count - 1) as f64).sqrt()),
+                _ => None,
+            },
+            (State::Values(values), AggregateFunction::Percentile(p)) => exact_percentile(values, p),
+            (State::Values(values), _) => exact_percentile(values, 50.0),
+            (State::Digest(digest), AggregateFunction::ApproxPercentile(p)) => digest.quantile(p / 100.0),
+            (State::Digest(_), _) => None,
+            (State::First(first), _) => *first,
+            (State::Last(last), _) => *last,
+        }
+    }
+}
+
+fn push_number(state: &mut State, v: f64) {
+    match state {
+        State::Moments { count, sum, mean, m2, min, max } => {
+            *count += 1;
+            *sum += v;
+            let delta = v - *mean;
+            *mean += delta / *count as f64;
+            *m2 += delta * (v - *mean);
+            *min = min.min(v);
+            *max = max.max(v);
+        }
+        State::Values(values) => values.push(v),
+        State::Digest(digest) => digest.push(v),
+        State::First(first) => {
+            first.get_or_insert(v);
+        }
+        State::Last(last) => *last = Some(v),
+        State::Count(_) | State::Distinct(_) => {}
+    }
+}
+
+// Linear interpolation between the closest ranks, so the median of [1, 2, 3, 4] is 2.5.
+fn exact_percentile(values: &[f64], p: f64) -> Option<f64> {
+    if values.is_empty() {
+        return None;
+    }
+
+    let mut sorted = values.to_vec();
+    sorted.sort_by(|a, b| a.total_cmp(b));
+
+    let rank = p / 100.0 * (sorted.len() - 1) as f64;
+    let lower = rank.floor() as usize;
+    let upper = rank.ceil() as usize;
+    Some(sorted[lower] + (sorted[upper] - sorted[lower]) * (rank - lower as f64))
+}
+
+// Merging t-digest (Dunning & Ertl). Values are summarised as centroids (mean, weight) that
+// are kept small near the tails, so extreme percentiles stay accurate while memory stays
+// bounded by the compression, however many values are pushed.
+#[derive(Clone, Debug)]
+pub struct TDigest {
+    compression: f64,
+    centroids: Vec<(f64, f64)>,
+    buffer: Vec<f64>,
+    count: f64,
+    min: f64,
+    max: f64,
+}
+
+impl TDigest {
+    pub fn new(compression: f64) -> TDigest {
+        TDigest {
+            compression,
+            centroids: Vec::new(),
+            buffer: Vec::new(),
+            count: 0.0,
+            min: f64::INFINITY,
+            max: f64::NEG_INFINITY,
+        }
+    }
+
+    pub fn push(&mut self, value: f64) {
+        self.buffer.push(value);
+        self.count += 1.0;
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+
+        if self.buffer.len() >= TDIGEST_BUFFER_LEN {
+            self.merge();
+        }
+    }
+
+    // Number of centroids after merging everything buffered so far.
+    pub fn centroid_count(&mut self) -> usize {
+        self.merge();
+        self.centroids.len()
+    }
+
+    // Scale function k1: centroids may span one unit of k, which is narrow near q = 0 and 1.
+    fn k(&self, q: f64) -> f64 {
+        self.compression / (2.0 * PI) * (2.0 * q - 1.0).asin()
+    }
+
+    fn k_inverse(&self, k: f64) -> f64 {
+        ((2.0 * PI * k / self.compression).sin() + 1.0) / 2.0
+    }
+
+    fn merge(&mut self) {
+        if self.buffer.is_empty() {
+            return;
+        }
+
+        let mut all = std::mem::take(&mut self.centroids);
+        all.extend(self.buffer.drain(..).map(|v| (v, 1.0)));
+        all.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+        let mut merged : Vec<(f64, f64)> = Vec::new();
+        let mut current = all[0];
+        let mut weight_before = 0.0;
+        let mut q_limit = self.k_inverse(self.k(0.0) + 1.0) * self.count;
+
+        for &(mean, weight) in &all[1..] {
+            if weight_before + current.1 + weight <= q_limit {
+                let total = current.1 + weight;
+                current.0 += (mean - current.0) * weight / total;
+                current.1 = total;
+            } else {
+                weight_before += current.1;
+                merged.push(current);
+                q_limit = self.k_inverse(self.k(weight_before / self.count) + 1.0) * self.count;
+                current = (mean, weight);
+            }
+        }
+        merged.push(current);
+
+        self.centroids = merged;
+    }
+
+    // q between 0 and 1. Interpolates between centroid centres, and towards the exact min and
+    // max at either end.
+    pub fn quantile(&self, q: f64) -> Option<f64> {
+        let mut digest = self.clone();
+        digest.merge();
+
+        let centroids = &digest.centroids;
+        if centroids.is_empty() {
+            return None;
+        }
+
+        let target = q * digest.count;
+        let mut weight_before = 0.0;
+
+        for (i, &(mean, weight)) in centroids.iter().enumerate() {
+            let center = weight_before + weight / 2.0;
+            if target < center {
+                let (previous_mean, previous_center) = match i {
+                    0 => (digest.min, 0.0),
+                    _ => (centroids[i - 1].0, weight_before - centroids[i - 1].1 / 2.0),
+                };
+                let t = (target - previous_center) / (center - previous_center);
+                return Some(previous_mean + (mean - previous_mean) * t);
+            }
+            weight_before += weight;
+        }
+
+        let (last_mean, last_weight) = centroids[centroids.len() - 1];
+        let last_center = digest.count - last_weight / 2.0;
+        if digest.count <= last_center {
+            return Some(last_mean);
+        }
+        let t = (target - last_center) / (digest.count - last_center);
+        Some(last_mean + (digest.max - last_mean) * t)
+    }
+}
+
+// Start of the bucket of the given width holding key, with bucket boundaries falling on
+// origin + k * width. Buckets before the origin are fine; one that would begin before the
+// epoch begins at 0 instead.
+pub fn bucket_start(key: u128, width: u128, origin: u128) -> u128 {
+    if key >= origin {
+        origin + (key - origin) / width * width
+    } else {
+        (origin - key).div_ceil(width).checked_mul(width)
+            .and_then(|back| origin.checked_sub(back))
+            .unwrap_or(0)
+    }
+}
+
+// Start of the bucket after the one holding key, or None past the last representable key.
+pub fn next_bucket_start(key: u128, width: u128, origin: u128) -> Option<u128> {
+    if key >= origin {
+        (origin + (key - origin) / width * width).checked_add(width)
+    } else {
+        // The bucket holding key ends after key, so it is at most origin.
+        Some(origin - ((origin - key).div_ceil(width) - 1) * width)
+    }
+}
@@ -0,0 +1,153 @@
+use bson::{Bson, Document};
+use std::io::{Error, ErrorKind, Read};
+
+// BSON element type tags this reader understands. Database::save and checkpoints only ever
+// write these at the top level.
+const BSON_DOUBLE : u8 = 0x01;
+const BSON_STRING : u8 = 0x02;
+const BSON_DOCUMENT : u8 = 0x03;
+const BSON_BOOLEAN : u8 = 0x08;
+const BSON_INT32 : u8 = 0x10;
+const BSON_INT64 : u8 = 0x12;
+
+// Reads a .r2d2 save file one row at a time, instead of parsing the whole document up front.
+//
+// A save is one BSON document { schema, min_timestamp, max_timestamp, rows: { "<key>": row } }.
+// Every top-level field other than rows is collected into header(); rows are then yielded by
+// the iterator in file order, which for files written by Database::save is key order. Fields
+// stored after rows (such as a checkpoint's lsn) show up in header() once the iterator is done.
+pub struct BsonSaveReader<R: Read> {
+    input: R,
+    header: Document,
+    in_rows: bool,
+}
+
+impl<R: Read> BsonSaveReader<R> {
+    pub fn open(mut input: R) -> Result<BsonSaveReader<R>, Error> {
+        // Total document length; not needed when reading front to back.
+        read_i32(&mut input)?;
+
+        let mut reader = BsonSaveReader { input, header: Document::new(), in_rows: false };
+        reader.read_top_level()?;
+        Ok(reader)
+    }
+
+    pub fn header(&self) -> &Document {
+        &self.header
+    }
+
+    // Reads top-level fields into the header until reaching rows or the end of the document.
+    fn read_top_level(&mut self) -> Result<(), Error> {
+        loop {
+            let element_type = read_u8(&mut self.input)?;
+            if element_type == 0 {
+                return Ok(());
+            }
+
+            let key = read_cstring(&mut self.input)?;
+            if key == "rows" && element_type == BSON_DOCUMENT {
+                read_i32(&mut self.input)?;
+                self.in_rows = true;
+                return Ok(());
+            }
+
+            let value = read_value(&mut self.input, element_type)?;
+            self.header.insert(key, value);
+        }
+    }
+
+    fn next_row(&mut self) -> Result<Option<(u128, Document)>, Error> {
+        let element_type = read_u8(&mut self.input)?;
+        if element_type == 0 {
+            self.in_rows = false;
+            self.read_top_level()?;
+            return Ok(None);
+        }
+
+        let key = read_cstring(&mut self.input)?;
+        if element_type != BSON_DOCUMENT {
+            return Err(invalid(&format!("row {} is not a document", key)));
+        }
+
+        let key = key.parse::<u128>().map_err(|_| invalid(&format!("row key {} is not a timestamp", key)))?;
+        let row = Document::from_reader(&mut self.input).map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+
+        Ok(Some((key, row)))
+    }
+}
+
+impl<R: Read> Iterator for BsonSaveReader<R> {
+    type Item = Result<(u128, Document), Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if !self.in_rows {
+            return None;
+        }
+
+        match self.next_row() {
+            Ok(row) => row.map(Ok),
+            Err(e) => {
+                self.in_rows = false;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+fn invalid(message: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, format!("corrupt save file: {}", message))
+}
+
+fn read_value(input: &mut impl Read, element_type: u8) -> Result<Bson, Error> {
+    match element_type {
+        BSON_DOUBLE => {
+            let mut bytes = [0u8; 8];
+            input.read_exact(&mut bytes)?;
+            Ok(Bson::Double(f64::from_le_bytes(bytes)))
+        }
+        BSON_STRING => {
+            let len = read_i32(input)?;
+            if len < 1 {
+                return Err(invalid("bad string length"));
+            }
+            let mut bytes = vec![0u8; len as usize];
+            input.read_exact(&mut bytes)?;
+            bytes.pop();
+            Ok(Bson::String(String::from_utf8(bytes).map_err(|_| invalid("string is not UTF-8"))?))
+        }
+        BSON_DOCUMENT => {
+            Ok(Bson::Document(Document::from_reader(input).map_err(|e| Error::new(ErrorKind::InvalidData, e))?))
+        }
+        BSON_BOOLEAN => Ok(Bson::Boolean(read_u8(input)? != 0)),
+        BSON_INT32 => Ok(Bson::Int32(read_i32(input)?)),
+        BSON_INT64 => {
+            let mut bytes = [0u8; 8];
+            input.read_exact(&mut bytes)?;
+            Ok(Bson::Int64(i64::from_le_bytes(bytes)))
+        }
+        _ => Err(invalid(&format!("unsupported top-level element type {:#04x}", element_type))),
+    }
+}
+
+fn read_u8(input: &mut impl Read) -> Result<u8, Error> {
+    let mut byte = [0u8; 1];
+    input.read_exact(&mut byte)?;
+    Ok(byte[0])
+}
+
+fn read_i32(input: &mut impl Read) -> Result<i32, Error> {
+    let mut bytes = [0u8; 4];
+    input.read_exact(&mut bytes)?;
+    Ok(i32::from_le_bytes(bytes))
+}
+
+fn read_cstring(input: &mut impl Read) -> Result<String, Error> {
+    let mut bytes = Vec::new();
+    loop {
+        match read_u8(input)? {
+            0 => break,
+            b => bytes.push(b),
+        }
+    }
+    String::from_utf8(bytes).map_err(|_| invalid("field name is not UTF-8"))
+}
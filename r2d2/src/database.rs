@@ -1,12 +1,20 @@
 use bplustree::GenericBPlusTree;
 use bson::spec::ElementType;
 use bson::{doc, Bson, Document};
-use chrono::NaiveDateTime;
 use std::fs::File;
 use std::io::Write;
-use std::io::{Error, BufRead, BufReader};
+use std::io::{Error, ErrorKind, BufRead, BufReader, BufWriter};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::time::{SystemTime, UNIX_EPOCH};
 use std::fs;
+use std::fmt;
+
+use crate::aggregate::{aggregate_columns, bucket_start, next_bucket_start, Accumulators, AggregateFunction, AggregateResult, Fill};
+use crate::bson_stream::BsonSaveReader;
+use crate::query::{self, Aggregate, AsOf, BatchFormat, Delete, Latest, Literal, Order, Page, Predicate, Projection, Query, Select, Statement, TimeBucket};
+use crate::segment::{has_segment_magic, is_segment_file, SegmentReader, SegmentWriter, SEGMENT_EXTENSION};
+use crate::transform::{Change, Differ, TransformFunction, Transformer};
+use crate::wal::{CheckpointPolicy, SyncPolicy, WalRecord, WriteAheadLog};
 
 pub const FAN_OUT : usize = 2000;
 pub const DATA_PATH : &str = "./data";
@@ -15,20 +23,159 @@ pub const DATA_PATH : &str = "./data";
 pub const SCHEMA_MAX_NUM_COLUMNS : usize = 50000;
 pub const SCHEMA_MAX_COLUMN_LABEL_LEN : usize = 128;
 
+// How many checkpoints to keep around. Older ones are only a fallback in case the newest
+// cannot be read back.
+pub const CHECKPOINTS_KEPT : usize = 2;
+
+// What Database::bulk_load did with the rows it was given.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct BulkLoadSummary {
+    // Rows appended in key order through the sorted fast path.
+    pub appended: usize,
+    // Rows that arrived out of order and went through insert_to_database instead.
+    pub inserted: usize,
+    // Rows refused for their columns, dropped by the duplicate policy or not logged, by their
+    // position in the input, with the reason.
+    pub rejected: Vec<(usize, String)>,
+}
+
+// What an insert does when a row already has its timestamp.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum DuplicatePolicy {
+    // Move the new row to the next free millisecond.
+    #[default]
+    Shift,
+    // Refuse the new row with an error.
+    Reject,
+    // Replace the old row with the new one.
+    Overwrite,
+    // Keep the old row and drop the new one.
+    KeepFirst,
+    // Set the new row's columns on the old row, keeping the old row's other columns.
+    Merge,
+}
+
+impl DuplicatePolicy {
+    pub const NAMES : [&'static str; 5] = ["shift", "reject", "overwrite", "keep first", "merge"];
+
+    // The policy's display name, in any case, with an underscore allowed for the space.
+    pub fn from_name(name: &str) -> Result<DuplicatePolicy, String> {
+        match name.to_ascii_lowercase().replace('_', " ").as_str() {
+            "shift" => Ok(DuplicatePolicy::Shift),
+            "reject" => Ok(DuplicatePolicy::Reject),
+            "overwrite" => Ok(DuplicatePolicy::Overwrite),
+            "keep first" => Ok(DuplicatePolicy::KeepFirst),
+            "merge" => Ok(DuplicatePolicy::Merge),
+            _ => Err(format!("unknown duplicate policy {}, must be one of: {}", name, DuplicatePolicy::NAMES.join(", "))),
+        }
+    }
+}
+
+impl fmt::Display for DuplicatePolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DuplicatePolicy::Shift => write!(f, "shift"),
+            DuplicatePolicy::Reject => write!(f, "reject"),
+            DuplicatePolicy::Overwrite => write!(f, "overwrite"),
+            DuplicatePolicy::KeepFirst => write!(f, "keep first"),
+            DuplicatePolicy::Merge => write!(f, "merge"),
+        }
+    }
+}
+
+// Where an inserted row ended up, and the duplicate policy applied if its timestamp was taken.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Inserted {
+    pub key: u128,
+    pub duplicate: Option<DuplicatePolicy>,
+}
+
+// What is wrong with one column of a row that was refused.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ColumnError {
+    // The value does not fit the column's type.
+    Type { column: String, expected: String, found: String },
+    // The column is not in the schema.
+    Unknown { column: String },
+    // The column is required but the row has no value for it.
+    Missing { column: String },
+}
+
+impl ColumnError {
+    pub fn column(&self) -> &str {
+        match self {
+            ColumnError::Type { column, .. } | ColumnError::Unknown { column } | ColumnError::Missing { column } => column,
+        }
+    }
+}
+
+impl fmt::Display for ColumnError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ColumnError::Type { column, expected, found } => write!(f, "Column '{}' needs a {}, found {}", column, expected, found),
+            ColumnError::Unknown { column } => write!(f, "Column '{}' is not in the schema", column),
+            ColumnError::Missing { column } => write!(f, "Column '{}' is required", column),
+        }
+    }
+}
+
+// Why a row was not inserted.
+#[derive(Clone, Debug, PartialEq)]
+pub enum InsertError {
+    // Everything wrong with the row's columns, one entry per column.
+    Columns(Vec<ColumnError>),
+    // The timestamp was taken and the duplicate policy is Reject, or it is Shift and every later
+    // timestamp is taken too.
+    Duplicate { key: u128, policy: DuplicatePolicy },
+    // The row could not be written to the write-ahead log.
+    Log(String),
+}
+
+impl InsertError {
+    // The HTTP status the error is reported with.
+    pub fn status(&self) -> i32 {
+        match self {
+            InsertError::Columns(_) => 400,
+            InsertError::Duplicate { .. } => 409,
+            InsertError::Log(_) => 500,
+        }
+    }
+}
+
+impl fmt::Display for InsertError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InsertError::Columns(errors) => {
+                write!(f, "{}", errors.iter().map(|e| e.to_string()).collect::<Vec<String>>().join("; "))
+            }
+            InsertError::Duplicate { key, policy: DuplicatePolicy::Shift } => {
+                write!(f, "A row already exists at {} and so does one at every later timestamp (duplicate policy: {})", key, DuplicatePolicy::Shift)
+            }
+            InsertError::Duplicate { key, policy } => {
+                write!(f, "A row already exists at {} (duplicate policy: {})", key, policy)
+            }
+            InsertError::Log(e) => write!(f, "Failed to write insert to the write-ahead log: {}", e),
+        }
+    }
+}
+
+// Why Database::insert_batch stored nothing.
+#[derive(Clone, Debug, PartialEq)]
+pub enum BatchError {
+    // Rows that would not go in, by their index in the batch.
+    Rows(Vec<(usize, InsertError)>),
+    // The batch could not be written to the write-ahead log.
+    Log(String),
+}
+
 pub struct Database {
     bptree: Box<GenericBPlusTree<u128, bson::Document, FAN_OUT, FAN_OUT>>,
     schema: bson::Document,
     min_timestamp: u128,
     max_timestamp: u128,
-}
-
-fn type_string_to_bson_element (string: &str) -> Option<ElementType> {
-    match string {
-        "string" => Some(ElementType::String),
-        "number" => Some(ElementType::Double),
-        "boolean" => Some(ElementType::Boolean),
-        _ => { eprintln!("INVALID TYPE : {}", string); None }
-    }
+    wal: Option<WriteAheadLog>,
+    duplicate_policy: DuplicatePolicy,
+    required: Vec<String>,
 }
 
 fn document_to_csv_row(doc : Document, time : u128) -> String {
@@ -49,13 +196,13 @@ fn document_to_csv_row(doc : Document, time : u128) -> String {
         csv.push_str(format!(",{}", converted).as_str());
     }
 
-    csv.push_str("\n");
+    csv.push('\n');
 
-    return csv;
+    csv
 }
 
 fn notice_page(notice: String) -> Document {
-    return doc![
+    doc![
         "labels" : ["Notice"],
         "rows" : doc![
             "Notice" : notice,
@@ -63,6 +210,391 @@ fn notice_page(notice: String) -> Document {
     ]
 }
 
+fn current_timestamp() -> u128 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis()
+}
+
+// Parses one NDJSON line of a BULK body into a row. The optional "timestamp" field (a number,
+// or a string for values past 2^64) becomes the key; every other field must be a schema column
+// and is converted to that column's type. Every bad field on the line is reported, not just the
+// first.
+fn json_to_row(schema: &Document, line: &str) -> Result<(Option<u128>, Document), String> {
+    let value : serde_json::Value = serde_json::from_str(line).map_err(|e| format!("invalid JSON: {}", e))?;
+    let object = value.as_object().ok_or("expected a JSON object")?;
+
+    let mut timestamp = None;
+    let mut row = Document::new();
+    let mut errors = Vec::new();
+
+    for (column, value) in object {
+        if column == "timestamp" {
+            let parsed = match value {
+                serde_json::Value::Number(n) => n.as_u64().map(|t| t as u128),
+                serde_json::Value::String(t) => t.parse::<u128>().ok(),
+                _ => None,
+            };
+            match parsed {
+                Some(parsed) => timestamp = Some(parsed),
+                None => errors.push(String::from("timestamp must be a non-negative integer")),
+            }
+            continue;
+        }
+
+        let converted = match schema.get_str(column) {
+            Ok("number") => value.as_f64().map(Bson::Double),
+            Ok("boolean") => value.as_bool().map(Bson::Boolean),
+            Ok(_) => value.as_str().map(|v| Bson::String(String::from(v))),
+            Err(_) => {
+                errors.push(ColumnError::Unknown { column: column.clone() }.to_string());
+                continue;
+            }
+        };
+
+        match converted {
+            Some(converted) => {
+                row.insert(column.clone(), converted);
+            }
+            None => errors.push(ColumnError::Type {
+                column: column.clone(),
+                expected: schema.get_str(column).unwrap_or_default().to_string(),
+                found: value.to_string(),
+            }.to_string()),
+        }
+    }
+
+    if errors.is_empty() {
+        Ok((timestamp, row))
+    } else {
+        Err(errors.join("; "))
+    }
+}
+
+// A row of a batch body: its line number, its timestamp if it had one, and its columns.
+type ParsedRow = (usize, Option<u128>, Document);
+
+// The rows each key touched by a batch would end up holding, and where each row of it landed.
+type BatchPlan = (BTreeMap<u128, Document>, Vec<Inserted>);
+
+// Parses a body with one JSON object per line, collecting each line that doesn't parse.
+fn ndjson_to_rows(schema: &Document, body: &str) -> (Vec<ParsedRow>, Vec<(usize, String)>) {
+    let mut rows = Vec::new();
+    let mut errors = Vec::new();
+
+    for (line_num, line) in body.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        match json_to_row(schema, line) {
+            Ok((timestamp, row)) => rows.push((line_num + 1, timestamp, row)),
+            Err(e) => errors.push((line_num + 1, e)),
+        }
+    }
+
+    (rows, errors)
+}
+
+// Parses a CSV body whose first line names the columns, one of which may be "timestamp". Every
+// field is trimmed, whatever its column's type, and an empty field leaves its column out of the
+// row. Fields may be double-quoted to hold commas, with "" for a quote inside them.
+fn csv_to_rows(schema: &Document, body: &str) -> (Vec<ParsedRow>, Vec<(usize, String)>) {
+    let mut rows = Vec::new();
+    let mut errors = Vec::new();
+
+    let mut lines = body.lines().enumerate().filter(|(_, line)| !line.trim().is_empty());
+    let header = match lines.next() {
+        Some((line_num, line)) => match split_csv_line(line) {
+            Ok(header) => (line_num + 1, header.iter().map(|c| c.trim().to_string()).collect::<Vec<String>>()),
+            Err(e) => return (rows, vec![(line_num + 1, e)]),
+        },
+        None => return (rows, errors),
+    };
+
+    let (header_line, columns) = header;
+    if let Some(column) = columns.iter().find(|c| *c != "timestamp" && !schema.contains_key(c.as_str())) {
+        return (rows, vec![(header_line, ColumnError::Unknown { column: column.clone() }.to_string())]);
+    }
+
+    for (line_num, line) in lines {
+        match split_csv_line(line).and_then(|fields| csv_fields_to_row(schema, &columns, fields)) {
+            Ok((timestamp, row)) => rows.push((line_num + 1, timestamp, row)),
+            Err(e) => errors.push((line_num + 1, e)),
+        }
+    }
+
+    (rows, errors)
+}
+
+fn split_csv_line(line: &str) -> Result<Vec<String>, String> {
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = line.trim_end_matches('\r').chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match (c, quoted) {
+            // Whitespace before the opening quote is dropped like any other.
+            ('"', false) if field.trim().is_empty() => {
+                field.clear();
+                quoted = true;
+            }
+            ('"', true) if chars.peek() == Some(&'"') => {
+                chars.next();
+                field.push('"');
+            }
+            ('"', true) => quoted = false,
+            (',', false) => fields.push(std::mem::take(&mut field)),
+            (c, _) => field.push(c),
+        }
+    }
+
+    if quoted {
+        return Err(String::from("a quoted field is not closed"));
+    }
+    fields.push(field);
+    Ok(fields)
+}
+
+fn csv_fields_to_row(schema: &Document, columns: &[String], fields: Vec<String>) -> Result<(Option<u128>, Document), String> {
+    if fields.len() != columns.len() {
+        return Err(format!("expected {} fields, found {}", columns.len(), fields.len()));
+    }
+
+    let mut timestamp = None;
+    let mut row = Document::new();
+    let mut errors = Vec::new();
+
+    for (column, field) in columns.iter().zip(fields) {
+        let text = field.trim();
+        if text.is_empty() {
+            continue;
+        }
+
+        if column == "timestamp" {
+            match text.parse::<u128>() {
+                Ok(parsed) => timestamp = Some(parsed),
+                Err(_) => errors.push(String::from("timestamp must be a non-negative integer")),
+            }
+            continue;
+        }
+
+        let converted = match schema.get_str(column) {
+            Ok("number") => text.parse::<f64>().ok().map(Bson::Double),
+            Ok("boolean") => text.parse::<bool>().ok().map(Bson::Boolean),
+            _ => Some(Bson::String(String::from(text))),
+        };
+
+        match converted {
+            Some(converted) => {
+                row.insert(column.clone(), converted);
+            }
+            None => errors.push(ColumnError::Type {
+                column: column.clone(),
+                expected: schema.get_str(column).unwrap_or_default().to_string(),
+                found: format!("'{}'", text),
+            }.to_string()),
+        }
+    }
+
+    if errors.is_empty() {
+        Ok((timestamp, row))
+    } else {
+        Err(errors.join("; "))
+    }
+}
+
+// Numeric value of a stored field. Numbers may be stored as any BSON number type, or as a
+// string holding one.
+pub(crate) fn bson_to_f64(value: &Bson) -> Option<f64> {
+    match value.element_type() {
+        ElementType::Double => value.as_f64(),
+        ElementType::Int32 => value.as_i32().map(|v| v as f64),
+        ElementType::Int64 => value.as_i64().map(|v| v as f64),
+        ElementType::String => value.as_str().and_then(|v| v.parse::<f64>().ok()),
+        _ => None,
+    }
+}
+
+// Converts a query value to a column's type. The '::' syntax sends every value as a string,
+// so strings are parsed when the column wants something else; one that doesn't parse is an
+// error rather than a zero or false.
+fn literal_to_bson(column: &str, value: Literal, column_type: &str) -> Result<Bson, ColumnError> {
+    let converted = match (column_type, &value) {
+        ("number", Literal::Number(n)) => Some(Bson::Double(*n)),
+        ("number", Literal::Str(s)) => s.trim().parse::<f64>().ok().map(Bson::Double),
+        ("boolean", Literal::Bool(b)) => Some(Bson::Boolean(*b)),
+        ("boolean", Literal::Str(s)) => s.trim().parse::<bool>().ok().map(Bson::Boolean),
+        ("string", Literal::Str(s)) => Some(Bson::String(s.clone())),
+        _ => None,
+    };
+
+    converted.ok_or_else(|| ColumnError::Type {
+        column: String::from(column),
+        expected: String::from(column_type),
+        found: match value {
+            Literal::Number(n) => n.to_string(),
+            Literal::Str(s) => format!("'{}'", s),
+            Literal::Bool(b) => b.to_string(),
+        },
+    })
+}
+
+// Whether a stored value belongs in a column of column_type. Numbers may be any BSON number
+// type or a string holding one, as bson_to_f64 reads them. Null fits anywhere.
+fn value_fits(value: &Bson, column_type: &str) -> bool {
+    match (column_type, value) {
+        (_, Bson::Null) => true,
+        ("number", _) => bson_to_f64(value).is_some(),
+        ("boolean", Bson::Boolean(_)) => true,
+        ("string", Bson::String(_)) => true,
+        _ => false,
+    }
+}
+
+fn describe_value(value: &Bson) -> String {
+    match value {
+        Bson::String(s) => format!("'{}'", s),
+        _ => value.to_string(),
+    }
+}
+
+// A refused insert, with the HTTP status to report it with. Column errors are listed one per
+// column.
+fn insert_error_page(error: InsertError) -> Document {
+    let status = error.status();
+    let mut page = match error {
+        InsertError::Columns(errors) => {
+            let mut rows = Document::new();
+            for e in errors {
+                rows.insert(e.column().to_string(), doc![ "column" : e.column(), "error" : e.to_string() ]);
+            }
+            doc![
+                "labels" : ["column", "error"],
+                "rows" : rows,
+            ]
+        }
+        e => notice_page(e.to_string()),
+    };
+    page.insert("status", status);
+    page
+}
+
+// The group_by values shared by a group of rows, and the aggregates over them.
+type Group = (Vec<Bson>, Accumulators);
+
+// A finished group: its bucket start, group_by values, aggregate values and row count.
+type GroupResult = (u128, Vec<Bson>, Vec<Option<f64>>, usize);
+
+// Most buckets FILL will produce for one query, so a wide RANGE with a narrow time(...) is an
+// error rather than an enormous result.
+const MAX_FILLED_BUCKETS : u128 = 100000;
+
+// Key for one group of a grouped aggregate, e.g. "time=1733697000000,store=3,city=Moncton".
+fn group_id(bucket_start : Option<u128>, group_by : &[String], tags : &[Bson]) -> String {
+    let mut parts = Vec::new();
+    if let Some(start) = bucket_start {
+        parts.push(format!("time={}", start));
+    }
+    for (column, tag) in group_by.iter().zip(tags.iter()) {
+        let value = match tag {
+            Bson::String(s) => s.clone(),
+            other => other.to_string(),
+        };
+        parts.push(format!("{}={}", column, value));
+    }
+    parts.join(",")
+}
+
+// The given columns of row, in that order, or all of it. Columns the row has no value for are
+// left out, as they are from rows listed in full.
+fn project(row : &Document, columns : Option<&[String]>) -> Document {
+    match columns {
+        Some(columns) => columns.iter()
+            .filter_map(|column| row.get(column).map(|value| (column.clone(), value.clone())))
+            .collect(),
+        None => row.clone(),
+    }
+}
+
+// Gives every group a result for every bucket from first to last, filling in those it had no
+// rows in. Results are ordered by bucket, then by the order groups first appear.
+fn fill_buckets(
+    results : Vec<GroupResult>,
+    fill : Fill,
+    first : u128,
+    last : u128,
+    bucket : TimeBucket,
+    group_by : &[String],
+    aggregate_count : usize,
+) -> Result<Vec<GroupResult>, String> {
+    // The first bucket may be cut short at the epoch, so the rest step on from the one after it.
+    let second = next_bucket_start(first, bucket.width, bucket.origin);
+    let bucket_count = match second {
+        _ if last < first => 0,
+        Some(second) if second <= last => (last - second) / bucket.width + 2,
+        _ => 1,
+    };
+    let start_of = |b : u128| match b {
+        0 => first,
+        b => second.expect("only counted when there is a second bucket") + (b - 1) * bucket.width,
+    };
+
+    let mut groups : Vec<Vec<Bson>> = Vec::new();
+    let mut seen : HashSet<String> = HashSet::new();
+    let mut known : HashMap<(u128, String), GroupResult> = HashMap::new();
+    for result in results {
+        let id = group_id(None, group_by, &result.1);
+        if seen.insert(id.clone()) {
+            groups.push(result.1.clone());
+        }
+        known.insert((result.0, id), result);
+    }
+    // Without tag columns there is exactly one group, even when the range has no rows.
+    if group_by.is_empty() && groups.is_empty() {
+        groups.push(Vec::new());
+    }
+
+    if bucket_count.saturating_mul(groups.len() as u128) > MAX_FILLED_BUCKETS {
+        return Err(format!(
+            "FILL would produce more than {} rows; narrow the RANGE or widen time(...)", MAX_FILLED_BUCKETS
+        ));
+    }
+
+    // Filled values for each group, per aggregate, per bucket
+    let mut filled : Vec<Vec<Vec<Option<f64>>>> = Vec::new();
+    for tags in &groups {
+        let id = group_id(None, group_by, tags);
+        filled.push((0..aggregate_count).map(|a| {
+            let series = (0..bucket_count)
+                .map(|b| known.get(&(start_of(b), id.clone())).map(|(_, _, values, _)| values[a]))
+                .collect::<Vec<Option<Option<f64>>>>();
+            fill.apply(&series)
+        }).collect());
+    }
+
+    let mut results = Vec::new();
+    for b in 0..bucket_count {
+        let start = start_of(b);
+        for (g, tags) in groups.iter().enumerate() {
+            let count = known.get(&(start, group_id(None, group_by, tags))).map_or(0, |(_, _, _, count)| *count);
+            let values = filled[g].iter().map(|series| series[b as usize]).collect::<Vec<Option<f64>>>();
+            results.push((start, tags.clone(), values, count));
+        }
+    }
+
+    Ok(results)
+}
+
+// Splits a stored schema document back into the (fields, types) pair Database::new expects.
+fn schema_fields_and_types(schema: &Document) -> (Vec<String>, Vec<String>) {
+    let fields = schema.keys().cloned().collect::<Vec<String>>();
+    let types = schema.values()
+        .map(|t| t.as_str().map(String::from).unwrap_or_else(|| t.to_string()))
+        .collect::<Vec<String>>();
+
+    (fields, types)
+}
+
 impl Database {
     pub fn new(fields : Vec<String>, types : Vec<String>) -> Database {
         assert_eq!(fields.len(), types.len());
@@ -72,6 +604,9 @@ impl Database {
             schema: Document::new(),
             min_timestamp: u128::MAX,
             max_timestamp: u128::MIN,
+            wal: None,
+            duplicate_policy: DuplicatePolicy::default(),
+            required: Vec::new(),
         };
 
         for i in 0..fields.len() {
@@ -81,6 +616,228 @@ impl Database {
         db
     }
 
+    // Replaces the schema and empties the tree, keeping the write-ahead log attached, the
+    // duplicate policy as it was, and whichever required columns are still in the schema.
+    fn reset(&mut self, fields : Vec<String>, types : Vec<String>) {
+        let wal = self.wal.take();
+        let duplicate_policy = self.duplicate_policy;
+        let required = std::mem::take(&mut self.required);
+        *self = Database::new(fields, types);
+        self.wal = wal;
+        self.duplicate_policy = duplicate_policy;
+        self.required = required.into_iter().filter(|c| self.schema.contains_key(c)).collect();
+    }
+
+    // Sets the columns every inserted row must have a value for. Like the duplicate policy, this
+    // only affects inserts as they happen.
+    pub fn set_required_columns(&mut self, columns : Vec<String>) -> Result<(), String> {
+        if let Some(column) = columns.iter().find(|c| !self.schema.contains_key(c.as_str())) {
+            return Err(format!("Column '{}' is not in the schema", column));
+        }
+        self.required = columns;
+        Ok(())
+    }
+
+    // Sets what inserts do with a timestamp that is already taken. Only inserts from now on are
+    // affected: the write-ahead log records where each row ended up. The policy itself is logged
+    // too, and kept in saves and checkpoints, so it survives restarts like the data does.
+    pub fn set_duplicate_policy(&mut self, policy: DuplicatePolicy) -> Result<(), String> {
+        let mut settings = self.settings();
+        settings.insert("duplicate_policy", policy.to_string());
+
+        self.log(WalRecord::Settings { settings: settings.clone() })
+            .map_err(|e| format!("Failed to write setting to the write-ahead log: {}", e))?;
+        self.apply_settings(&settings);
+        Ok(())
+    }
+
+    // Settings stored alongside the data in saves, checkpoints and the write-ahead log.
+    fn settings(&self) -> Document {
+        doc![ "duplicate_policy" : self.duplicate_policy.to_string() ]
+    }
+
+    // Settings a file or log record doesn't have are left as they are.
+    fn apply_settings(&mut self, settings: &Document) {
+        if let Some(policy) = settings.get_str("duplicate_policy").ok().and_then(|p| DuplicatePolicy::from_name(p).ok()) {
+            self.duplicate_policy = policy;
+        }
+    }
+
+    pub fn duplicate_policy(&self) -> DuplicatePolicy {
+        self.duplicate_policy
+    }
+
+    // Opens the write-ahead log under DATA_PATH and recovers from it: the newest readable
+    // checkpoint is loaded first, then only the log records written after that checkpoint are
+    // replayed. All further mutations are logged. Call this once at startup, before serving any
+    // queries. Returns the number of records replayed.
+    pub fn open_wal(&mut self, filename: String, policy: SyncPolicy) -> Result<usize, Error> {
+        let (mut wal, records) = WriteAheadLog::open(&filename, policy)?;
+
+        // Replay must not append to the log it is reading from.
+        self.wal = None;
+
+        let mut checkpoint_lsn = 0;
+        for (lsn, checkpoint) in list_checkpoints(&filename)?.into_iter().rev() {
+            match self.restore_checkpoint(&checkpoint) {
+                Ok(_) => {
+                    checkpoint_lsn = lsn;
+                    break;
+                }
+                Err(e) => eprintln!("Skipping unreadable checkpoint '{}': {}", checkpoint, e),
+            }
+        }
+
+        if let Some((first_lsn, _)) = records.first() {
+            if *first_lsn > checkpoint_lsn + 1 {
+                eprintln!("Write-ahead log starts at record {}, but the checkpoint only covers up to {}; records in between are lost.", first_lsn, checkpoint_lsn);
+            }
+        }
+
+        let mut replayed = 0;
+        for (lsn, record) in records {
+            if lsn > checkpoint_lsn {
+                self.apply(record);
+                replayed += 1;
+            }
+        }
+
+        wal.advance_past(checkpoint_lsn);
+        self.wal = Some(wal);
+        Ok(replayed)
+    }
+
+    // Sets when maybe_checkpoint() actually takes one. Has no effect without an open log.
+    pub fn set_checkpoint_policy(&mut self, policy: CheckpointPolicy) {
+        if let Some(wal) = self.wal.as_mut() {
+            wal.set_checkpoint_policy(policy);
+        }
+    }
+
+    // Writes the whole database to a new checkpoint file next to the write-ahead log, then
+    // truncates the log, since every record in it is now part of the checkpoint. Checkpoints are
+    // segment files, streamed from the tree a block at a time like a .r2seg SAVE, so taking one
+    // needs no more memory than a block on top of the tree. The log sequence number it covers is
+    // in its name.
+    pub fn checkpoint(&mut self) -> Result<(), Error> {
+        let (filename, lsn) = match self.wal.as_ref() {
+            Some(wal) => (wal.filename().to_string(), wal.last_lsn()),
+            None => return Err(Error::new(ErrorKind::NotFound, "no write-ahead log is open")),
+        };
+
+        save_file_atomic(&checkpoint_filename(&filename, lsn), |out| self.write_segment(out).map(|_| ()))?;
+
+        self.wal.as_mut().unwrap().truncate()?;
+
+        let checkpoints = list_checkpoints(&filename)?;
+        let stale = checkpoints.len().saturating_sub(CHECKPOINTS_KEPT);
+        for (_, old) in &checkpoints[..stale] {
+            fs::remove_file(format!("{}/{}", DATA_PATH, old))?;
+        }
+
+        Ok(())
+    }
+
+    // Takes a checkpoint if the checkpoint policy says one is due. Called by the server
+    // after every request, and on a timer while it is idle.
+    pub fn maybe_checkpoint(&mut self) {
+        if self.wal.as_ref().is_some_and(|wal| wal.checkpoint_due()) {
+            if let Err(e) = self.checkpoint() {
+                eprintln!("Checkpoint failed: {}", e);
+            }
+        }
+    }
+
+    // A segment file only reads back whole, so a checkpoint cut short fails here. Checkpoints
+    // used to be .r2d2 saves with the sequence number after the rows; those are still read.
+    fn restore_checkpoint(&mut self, checkpoint: &str) -> Result<(), Error> {
+        if has_segment_magic(&format!("{}/{}", DATA_PATH, checkpoint))? {
+            return self.load_segment(checkpoint.to_string());
+        }
+
+        let header = self.restore(checkpoint)?;
+
+        if header.get_i64("lsn").is_err() {
+            return Err(Error::new(ErrorKind::InvalidData, "checkpoint has no log sequence number"));
+        }
+
+        Ok(())
+    }
+
+    // Appends a mutation to the write-ahead log, if one is attached. Callers must not apply the
+    // mutation if this fails, since it would not survive a restart.
+    fn log(&mut self, record: WalRecord) -> Result<(), Error> {
+        match self.wal.as_mut() {
+            Some(wal) => wal.append(&record),
+            None => Ok(()),
+        }
+    }
+
+    fn log_all(&mut self, records: &[WalRecord]) -> Result<(), Error> {
+        match self.wal.as_mut() {
+            Some(wal) if !records.is_empty() => wal.append_all(records),
+            _ => Ok(()),
+        }
+    }
+
+    // Applies a mutation without logging it. Used for replay; live queries go through the
+    // public methods, which log first.
+    fn apply(&mut self, record: WalRecord) {
+        match record {
+            WalRecord::Insert { key, row } => self.insert_unlogged(key, row),
+            WalRecord::Remove { key } => {
+                self.remove_unlogged(key);
+            }
+            WalRecord::Clear => self.clear_unlogged(),
+            WalRecord::Schema { schema } => {
+                let (fields, types) = schema_fields_and_types(&schema);
+                self.reset(fields, types);
+            }
+            WalRecord::Settings { settings } => self.apply_settings(&settings),
+            WalRecord::Load { filename } => {
+                if let Err(e) = self.load_unlogged(filename.clone()) {
+                    eprintln!("Could not replay LOAD of '{}': {}", filename, e);
+                }
+            }
+        }
+    }
+
+    fn insert_unlogged(&mut self, key : u128, val : bson::Document) {
+        if self.min_timestamp > key {
+            self.min_timestamp = key;
+        }
+
+        if self.max_timestamp < key {
+            self.max_timestamp = key;
+        }
+
+        self.bptree.insert(key, val);
+    }
+
+    // Keeps min_timestamp and max_timestamp to the keys that are left.
+    fn remove_unlogged(&mut self, key : u128) -> bool {
+        let removed = self.bptree.remove(&key).is_some();
+        if removed && (key == self.min_timestamp || key == self.max_timestamp) {
+            match (self.first_key(), self.last_key()) {
+                (Some(first), Some(last)) => {
+                    self.min_timestamp = first;
+                    self.max_timestamp = last;
+                }
+                _ => {
+                    self.min_timestamp = u128::MAX;
+                    self.max_timestamp = u128::MIN;
+                }
+            }
+        }
+        removed
+    }
+
+    fn clear_unlogged(&mut self) {
+        *self.bptree = bplustree::GenericBPlusTree::new();
+        self.min_timestamp = u128::MAX;
+        self.max_timestamp = u128::MIN;
+    }
+
     pub fn load_schema_from_file(&mut self, filename: String) -> Result<(), String> {
         let full_path = format!("{}/{}", DATA_PATH, filename);
 
@@ -94,7 +851,7 @@ impl Database {
         let line_count_file = File::open(&full_path)
             .map_err(|e| format!("Failed to open schema file: {}", e))?;
 
-        let mut line_count_reader = BufReader::new(line_count_file);
+        let line_count_reader = BufReader::new(line_count_file);
 
         let file_line_count = line_count_reader.lines().count();
         if file_line_count > SCHEMA_MAX_NUM_COLUMNS {
@@ -105,7 +862,7 @@ impl Database {
         let file = File::open(&full_path)
             .map_err(|e| format!("Failed to open schema file: {}", e))?;
 
-        let mut reader = BufReader::new(file);
+        let reader = BufReader::new(file);
 
         let mut fields = Vec::new();
         let mut types = Vec::new();
@@ -159,413 +916,1276 @@ impl Database {
         }
 
         // Create new database with the loaded schema
-        *self = Database::new(fields, types);
+        let schema = Database::new(fields.clone(), types.clone()).schema;
+        self.log(WalRecord::Schema { schema })
+            .map_err(|e| format!("Failed to write schema change to the write-ahead log: {}", e))?;
+        self.reset(fields, types);
 
         Ok(())
     }
 
-    pub fn insert_to_database(&mut self, mut key : u128, val : bson::Document) {
-        if self.min_timestamp > key {
-            self.min_timestamp = key;
+    // Inserts val at key, handling a row already there as the duplicate policy says. Returns
+    // where the row landed (later than key, for Shift) and the policy, if it was needed.
+    pub fn insert_to_database(&mut self, key : u128, val : bson::Document) -> Result<Inserted, InsertError> {
+        self.check_row(&val, true)?;
+
+        let existing = match self.get_one(key) {
+            Some(row) => row,
+            None => {
+                self.put(key, val)?;
+                return Ok(Inserted { key, duplicate: None });
+            }
+        };
+
+        let policy = self.duplicate_policy;
+        let key = match policy {
+            DuplicatePolicy::Shift => {
+                // One cursor walk along the run of taken keys, rather than a lookup per key. A run
+                // that reaches u128::MAX leaves nowhere to shift to.
+                let mut free = Some(key);
+                let mut iter = self.bptree.raw_iter();
+                iter.seek(&key);
+                while let Some((taken, _)) = iter.next() {
+                    if Some(*taken) != free {
+                        break;
+                    }
+                    free = taken.checked_add(1);
+                }
+                drop(iter);
+                let Some(free) = free else {
+                    return Err(InsertError::Duplicate { key, policy });
+                };
+
+                // The resolved key is logged, so replay lands the row in the same slot.
+                self.put(free, val)?;
+                free
+            }
+            DuplicatePolicy::Reject => return Err(InsertError::Duplicate { key, policy }),
+            DuplicatePolicy::Overwrite => {
+                self.put(key, val)?;
+                key
+            }
+            DuplicatePolicy::KeepFirst => key,
+            DuplicatePolicy::Merge => {
+                let mut merged = existing;
+                merged.extend(val);
+                self.put(key, merged)?;
+                key
+            }
+        };
+
+        Ok(Inserted { key, duplicate: Some(policy) })
+    }
+
+    // Like insert_to_database, but a row already at key is replaced instead of the new one
+    // moving to the next free key.
+    pub fn upsert(&mut self, key : u128, val : bson::Document) -> Result<(), InsertError> {
+        self.check_row(&val, true)?;
+        self.put(key, val)
+    }
+
+    // Sets the columns in patch on the row at key, keeping its other columns. Returns whether
+    // there was a row at key to update.
+    pub fn update(&mut self, key : u128, patch : bson::Document) -> Result<bool, InsertError> {
+        // The row being patched already has its required columns.
+        self.check_row(&patch, false)?;
+
+        let mut row = match self.get_one(key) {
+            Some(row) => row,
+            None => return Ok(false),
+        };
+        row.extend(patch);
+
+        // Logged as the whole new row, which replays like any other insert.
+        self.put(key, row)?;
+        Ok(true)
+    }
+
+    // Logs and stores val at key, replacing any row that was there.
+    fn put(&mut self, key : u128, val : bson::Document) -> Result<(), InsertError> {
+        self.log(WalRecord::Insert { key, row: val.clone() })
+            .map_err(|e| InsertError::Log(e.to_string()))?;
+
+        self.insert_unlogged(key, val);
+        Ok(())
+    }
+
+    // Every column of row must be in the schema and hold a value of its type. With
+    // check_required, every required column must also have a value.
+    fn check_row(&self, row : &Document, check_required : bool) -> Result<(), InsertError> {
+        let mut errors = Vec::new();
+
+        for (column, value) in row {
+            match self.schema.get_str(column) {
+                Ok(column_type) if !value_fits(value, column_type) => errors.push(ColumnError::Type {
+                    column: column.clone(),
+                    expected: String::from(column_type),
+                    found: describe_value(value),
+                }),
+                Ok(_) => {}
+                Err(_) => errors.push(ColumnError::Unknown { column: column.clone() }),
+            }
         }
 
-        if self.max_timestamp < key {
-            self.max_timestamp = key;
+        if check_required {
+            for column in &self.required {
+                if row.get(column).is_none_or(|value| *value == Bson::Null) {
+                    errors.push(ColumnError::Missing { column: column.clone() });
+                }
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(InsertError::Columns(errors))
+        }
+    }
+
+    fn first_key(&self) -> Option<u128> {
+        let mut iter = self.bptree.raw_iter();
+        iter.seek_to_first();
+        iter.next().map(|(key, _)| *key)
+    }
+
+    fn last_key(&self) -> Option<u128> {
+        let mut iter = self.bptree.raw_iter();
+        iter.seek_to_last();
+        iter.prev().map(|(key, _)| *key)
+    }
+
+    // Loads many rows at once. The longest leading run of rows whose keys are strictly ascending
+    // and after everything already in the tree is logged with a single write and appended in
+    // order through one cursor; once a row breaks that order, it and everything after it fall
+    // back to insert_to_database, duplicate policy included. Rows that insert_to_database would
+    // refuse for their columns are skipped, and returned with the reason.
+    //
+    // This does not build leaves bottom-up at FAN_OUT: the bplustree crate keeps its nodes
+    // private and always splits a full leaf in half, so appended leaves end up about half full,
+    // as with ordinary inserts. What the fast path saves is the per-row root descent, collision
+    // lookup and log write.
+    pub fn bulk_load<I>(&mut self, rows: I) -> BulkLoadSummary
+    where
+        I: IntoIterator<Item = (u128, Document)>,
+    {
+        let mut summary = BulkLoadSummary::default();
+        let mut sorted_run : Vec<(usize, u128, Document)> = Vec::new();
+        let mut rest : Vec<(usize, u128, Document)> = Vec::new();
+
+        let mut last_key = self.last_key();
+
+        for (i, (key, row)) in rows.into_iter().enumerate() {
+            if let Err(e) = self.check_row(&row, true) {
+                summary.rejected.push((i, e.to_string()));
+            } else if rest.is_empty() && last_key.is_none_or(|last| key > last) {
+                last_key = Some(key);
+                sorted_run.push((i, key, row));
+            } else {
+                rest.push((i, key, row));
+            }
+        }
+
+        let records = sorted_run.iter()
+            .map(|(_, key, row)| WalRecord::Insert { key: *key, row: row.clone() })
+            .collect::<Vec<WalRecord>>();
+
+        match self.log_all(&records) {
+            Ok(_) => {
+                summary.appended = sorted_run.len();
+                self.append_sorted(sorted_run.into_iter().map(|(_, key, row)| Ok((key, row)))).expect("rows are already in memory");
+            }
+            Err(e) => {
+                let error = InsertError::Log(e.to_string()).to_string();
+                summary.rejected.extend(sorted_run.into_iter().map(|(i, _, _)| (i, error.clone())));
+            }
+        }
+
+        for (i, key, row) in rest {
+            match self.insert_to_database(key, row) {
+                Ok(Inserted { duplicate: Some(DuplicatePolicy::KeepFirst), .. }) => {
+                    summary.rejected.push((i, format!("A row already exists at {} (duplicate policy: {})", key, DuplicatePolicy::KeepFirst)));
+                }
+                Ok(_) => summary.inserted += 1,
+                Err(e) => summary.rejected.push((i, e.to_string())),
+            }
+        }
+
+        summary.rejected.sort_by_key(|(i, _)| *i);
+        summary
+    }
+
+    // Inserts every row or none of them. The whole batch is resolved against the duplicate policy
+    // first, duplicates within the batch included, then logged with a single write-ahead log
+    // write and applied. If any row would fail (anything wrong with its columns, or a taken
+    // timestamp under Reject), nothing is stored and every failing row is returned by its index.
+    pub fn insert_batch(&mut self, rows : Vec<(u128, Document)>) -> Result<Vec<Inserted>, BatchError> {
+        let (writes, inserted) = self.plan_batch(rows).map_err(BatchError::Rows)?;
+        self.apply_batch(writes).map_err(|e| BatchError::Log(e.to_string()))?;
+        Ok(inserted)
+    }
+
+    fn plan_batch(&self, rows : Vec<(u128, Document)>) -> Result<BatchPlan, Vec<(usize, InsertError)>> {
+        let policy = self.duplicate_policy;
+        let mut writes : BTreeMap<u128, Document> = BTreeMap::new();
+        let mut inserted = Vec::with_capacity(rows.len());
+        let mut errors = Vec::new();
+
+        let taken = |writes : &BTreeMap<u128, Document>, key : u128| {
+            writes.contains_key(&key) || self.bptree.lookup(&key, |_| ()).is_some()
+        };
+
+        for (i, (key, row)) in rows.into_iter().enumerate() {
+            if let Err(e) = self.check_row(&row, true) {
+                errors.push((i, e));
+                continue;
+            }
+
+            if !taken(&writes, key) {
+                writes.insert(key, row);
+                inserted.push(Inserted { key, duplicate: None });
+                continue;
+            }
+
+            let key = match policy {
+                DuplicatePolicy::Shift => {
+                    let mut free = key.checked_add(1);
+                    while free.is_some_and(|free| taken(&writes, free)) {
+                        free = free.and_then(|free| free.checked_add(1));
+                    }
+                    let Some(free) = free else {
+                        errors.push((i, InsertError::Duplicate { key, policy }));
+                        continue;
+                    };
+                    writes.insert(free, row);
+                    free
+                }
+                DuplicatePolicy::Reject => {
+                    errors.push((i, InsertError::Duplicate { key, policy }));
+                    continue;
+                }
+                DuplicatePolicy::Overwrite => {
+                    writes.insert(key, row);
+                    key
+                }
+                DuplicatePolicy::KeepFirst => key,
+                DuplicatePolicy::Merge => {
+                    let mut merged = writes.remove(&key)
+                        .or_else(|| self.get_one(key))
+                        .expect("taken keys have a row");
+                    merged.extend(row);
+                    writes.insert(key, merged);
+                    key
+                }
+            };
+            inserted.push(Inserted { key, duplicate: Some(policy) });
+        }
+
+        if errors.is_empty() {
+            Ok((writes, inserted))
+        } else {
+            Err(errors)
+        }
+    }
+
+    fn apply_batch(&mut self, writes : BTreeMap<u128, Document>) -> Result<(), InsertError> {
+        let records = writes.iter()
+            .map(|(key, row)| WalRecord::Insert { key: *key, row: row.clone() })
+            .collect::<Vec<WalRecord>>();
+
+        self.log_all(&records)
+            .map_err(|e| InsertError::Log(e.to_string()))?;
+
+        for (key, row) in writes {
+            self.insert_unlogged(key, row);
+        }
+        Ok(())
+    }
+
+    // Returns document laid out as so:
+    // Document {
+    //    "labels" : vec<strings>,
+    //    "rows : Document...
+    // }
+    pub fn query(&mut self, query_string: String) -> Document {
+        self.query_with_body(query_string, "")
+    }
+
+    // Same as query(), for requests that carry a body (e.g. POST /BULK).
+    pub fn query_with_body(&mut self, query_string: String, body: &str) -> Document {
+        match query::parse(query_string.trim()) {
+            Ok(parsed) => self.execute(parsed, body),
+            Err(e) => notice_page(format!("Invalid query '{}': {}", query_string, e)),
+        }
+    }
+
+    pub fn execute(&mut self, parsed: Query, body: &str) -> Document {
+        let result = match parsed.statement {
+            Statement::Select(select) => self.handle_select(select),
+            Statement::Insert { values, at, overwrite } => self.handle_insert(values, at, overwrite),
+            Statement::Update { values, at } => self.handle_update(values, at),
+            Statement::Delete(delete) => self.handle_delete(delete),
+            Statement::Latest(latest) => self.handle_latest(latest),
+            Statement::ShowMetadata => {
+                // Timestamps can be past i64::MAX, so they are given as text.
+                let (min, max) = match self.time_bounds() {
+                    Some((min, max)) => (Bson::String(min.to_string()), Bson::String(max.to_string())),
+                    None => (Bson::Null, Bson::Null),
+                };
+                doc![
+                    "labels" : ["size", "min_timestamp", "max_timestamp", "schema", "duplicates", "required"],
+                    "rows" : doc![
+                        "size" : self.bptree.len() as i64,
+                        "min_timestamp" : min,
+                        "max_timestamp" : max,
+                        "schema" : self.schema.clone(),
+                        "duplicates" : self.duplicate_policy.to_string(),
+                        "required" : self.required.clone(),
+                    ]
+                ]
+            }
+            Statement::ShowSaved => {
+                let file_list = list_files(DATA_PATH.parse().unwrap()).unwrap_or(vec!["No saved databases found.".parse().unwrap()]);
+                let mut file_list_doc = bson::Document::new();
+                for (i, f) in file_list.iter().enumerate() {
+                    file_list_doc.insert(i.to_string(), f.clone());
+                }
+
+                doc![
+                    "labels" : ["Saved Databases"],
+                    "rows" : file_list_doc,
+                ]
+            }
+            Statement::SetDuplicatePolicy { policy } => match self.set_duplicate_policy(policy) {
+                Ok(()) => doc![
+                    "labels" : ["duplicates"],
+                    "rows" : doc![
+                        "duplicates" : self.duplicate_policy.to_string(),
+                    ]
+                ],
+                Err(e) => notice_page(e),
+            },
+            Statement::Time { timestamp } => doc![
+                "labels" : ["time"],
+                "rows" : doc![
+                    "time" : timestamp.to_string(),
+                ]
+            ],
+            Statement::Save { filename } => {
+                self.save(sanitize_filename::sanitize(filename));
+                self.query("LIST::SAVED".to_string())
+            }
+            Statement::SaveCsv => {
+                self.data_to_csv();
+                notice_page(String::from("Dumped database to CSV."))
+            }
+            Statement::Load { filename } => {
+                let result = self.load(sanitize_filename::sanitize(filename));
+                if result.is_ok() {
+                    self.query("LIST::ALL".to_string())
+                } else {
+                    notice_page(String::from("LOAD operation failed. See server logs."))
+                }
+            }
+            Statement::LoadSchema { filename } => match self.load_schema_from_file(filename) {
+                Ok(_) => {
+                    // Return metadata with success message
+                    doc![
+                        "labels" : ["message", "schema"],
+                        "rows" : doc![
+                            "message" : "Successfully loaded schema",
+                            "schema" : self.schema.clone(),
+                        ]
+                    ]
+                }
+                Err(error_msg) => {
+                    notice_page(format!("Error loading schema: {}", error_msg))
+                }
+            },
+            Statement::Bulk => self.handle_bulk_query(body),
+            Statement::InsertBatch { format } => self.handle_insert_batch(format, body),
+        };
+
+        // Notices and errors are shown as they are; anything else is reduced to one.
+        let is_notice = result.get_array("labels").is_ok_and(|l| l.len() == 1 && l[0].as_str() == Some("Notice"));
+        if parsed.hide && !is_notice && !result.contains_key("status") {
+            notice_page(String::from("Success!"))
+        } else {
+            result
         }
+    }
+
+    fn handle_select(&self, select: Select) -> Document {
+        if let Some(filter) = &select.filter {
+            if let Err(e) = filter.check(&self.schema) {
+                return notice_page(format!("Invalid WHERE clause: {}", e));
+            }
+        }
+
+        let aggregates = match select.projection {
+            Projection::All | Projection::Columns(_) => {
+                let columns = match &select.projection {
+                    Projection::Columns(columns) => Some(columns.as_slice()),
+                    _ => None,
+                };
+                if let Some(column) = columns.into_iter().flatten().find(|c| !self.schema.contains_key(c)) {
+                    return notice_page(format!("Column '{}' is not in the schema", column));
+                }
+
+                let (rows, last) = if let Some(key) = select.at {
+                    match self.get_as_of(key, select.as_of, select.tolerance, select.filter.as_ref()) {
+                        Some((key, row)) => (doc![key.to_string() : project(&row, columns)], None),
+                        None => return notice_page(String::from("Requested value could not be found.")),
+                    }
+                } else {
+                    let (lower, upper) = select.range.unwrap_or((u128::MIN, u128::MAX));
+                    self.get_page(lower, upper, select.filter.as_ref(), columns, &select.page)
+                };
+
+                let labels = match columns {
+                    Some(columns) => columns.to_vec(),
+                    None => self.schema.keys().cloned().collect::<Vec<String>>(),
+                };
+                let mut result = doc![
+                    "labels" : labels,
+                    "rows" : rows,
+                ];
+                // Only there when the page was cut short by LIMIT.
+                if let Some(last) = last {
+                    result.insert("next", query::continuation_token(last, select.page.order));
+                }
+                return result;
+            }
+            Projection::Aggregates(aggregates) => aggregates,
+            Projection::Transform { function, field } => {
+                let (lower, upper) = select.range.unwrap_or((u128::MIN, u128::MAX));
+                return match self.transform_range(function, &field, lower, upper, select.bucket, select.filter.as_ref()) {
+                    Ok(rows) => doc![
+                        "labels" : [function.label(&field)],
+                        "rows" : rows,
+                    ],
+                    Err(e) => notice_page(e),
+                };
+            }
+        };
+        let (lower, upper) = select.range.unwrap_or((u128::MIN, u128::MAX));
+
+        if select.bucket.is_some() || !select.group_by.is_empty() {
+            let groups = self.aggregate_groups(
+                &aggregates, lower, upper, select.bucket, &select.group_by, select.filter.as_ref(), select.fill
+            );
+
+            // Rows keyed by time get their Timestamp column from the renderer.
+            let mut labels = Vec::new();
+            if select.bucket.is_some() && !select.group_by.is_empty() {
+                labels.push(String::from("time"));
+            }
+            labels.extend(select.group_by.iter().cloned());
+            labels.extend(aggregates.iter().map(Aggregate::label));
+            labels.push(String::from("count"));
+
+            return match groups {
+                Ok(rows) => doc![
+                    "labels" : labels,
+                    "rows" : rows,
+                ],
+                Err(e) => notice_page(e),
+            };
+        }
+
+        // A single aggregate keeps the name it had in the '::' syntax, e.g. AVG.
+        if let [aggregate] = aggregates.as_slice() {
+            let result = self.aggregate_range(aggregate.function, &aggregate.field, lower, upper, select.filter.as_ref());
+            let op = aggregate.function.to_string();
+
+            return doc![
+                "labels" : [op.as_str(), "count"],
+                "rows" : doc![
+                    op.as_str() : result.to_document(&op),
+                ]
+            ];
+        }
+
+        // One row, keyed by its first column, with one column per aggregate
+        let accumulators = self.aggregate_many(&aggregates, lower, upper, select.filter.as_ref());
+        let mut labels = aggregates.iter().map(Aggregate::label).collect::<Vec<String>>();
+        let mut rows = Document::new();
+        rows.insert(labels[0].clone(), accumulators.to_document());
+        labels.push(String::from("count"));
+
+        doc![
+            "labels" : labels,
+            "rows" : rows,
+        ]
+    }
+
+    fn handle_insert(&mut self, values: Vec<(String, Literal)>, at: Option<u128>, overwrite: bool) -> Document {
+        let row_document = match self.literals_to_row(values, true) {
+            Ok(row) => row,
+            Err(e) => return insert_error_page(e),
+        };
+
+        let key = at.unwrap_or_else(current_timestamp);
+        let inserted = if overwrite {
+            self.upsert(key, row_document).map(|_| Inserted { key, duplicate: None })
+        } else {
+            self.insert_to_database(key, row_document)
+        };
+
+        match inserted {
+            Ok(inserted) => doc![
+                "labels" : ["timestamp", "duplicate", "policy"],
+                "rows" : doc![
+                    // Keys can be past i64::MAX, so they are given as text.
+                    "timestamp" : inserted.key.to_string(),
+                    "duplicate" : inserted.duplicate.map(|policy| policy.to_string()),
+                    "policy" : self.duplicate_policy.to_string(),
+                ],
+            ],
+            Err(e) => insert_error_page(e),
+        }
+    }
+
+    // The row an INSERT, UPSERT or UPDATE sets, or every column whose value it can't take. With
+    // check_required, required columns left out are reported too.
+    fn literals_to_row(&self, values: Vec<(String, Literal)>, check_required: bool) -> Result<Document, InsertError> {
+        let mut row = Document::new();
+        let mut errors = Vec::new();
+
+        let missing = self.required.iter()
+            .filter(|column| check_required && !values.iter().any(|(c, _)| c == *column))
+            .map(|column| ColumnError::Missing { column: column.clone() })
+            .collect::<Vec<ColumnError>>();
+
+        for (column, value) in values {
+            match self.schema.get_str(&column) {
+                Ok(column_type) => match literal_to_bson(&column, value, column_type) {
+                    Ok(converted) => {
+                        row.insert(column, converted);
+                    }
+                    Err(e) => errors.push(e),
+                },
+                Err(_) => errors.push(ColumnError::Unknown { column }),
+            }
+        }
+        errors.extend(missing);
+
+        if errors.is_empty() {
+            Ok(row)
+        } else {
+            Err(InsertError::Columns(errors))
+        }
+    }
+
+    fn handle_update(&mut self, values: Vec<(String, Literal)>, at: u128) -> Document {
+        let patch = match self.literals_to_row(values, false) {
+            Ok(patch) => patch,
+            Err(e) => return insert_error_page(e),
+        };
+
+        match self.update(at, patch) {
+            Ok(updated) => doc![
+                "labels" : ["updated"],
+                "rows" : doc![ "updated" : updated as i64 ],
+            ],
+            Err(e) => insert_error_page(e),
+        }
+    }
 
-        let mut already_in_tree = self.bptree.lookup(&key, |value| value.clone()).is_some();
-        while already_in_tree {
-            key = key + 1;
-            already_in_tree = self.bptree.lookup(&key, |value| value.clone()).is_some();
+    // Body is NDJSON, as for BULK, or CSV with a header line, e.g.
+    // timestamp,store,number_sold
+    // 1733697225084,1,5
+    // Every row is checked before any is stored; if any line is bad, nothing is stored and each
+    // bad line is listed with its error. Otherwise the result counts the rows inserted and how
+    // many of them found their timestamp taken.
+    fn handle_insert_batch(&mut self, format: BatchFormat, body: &str) -> Document {
+        if body.trim().is_empty() {
+            return notice_page(String::from("INSERT BATCH requires a POST body with one row per line"));
         }
 
+        let (rows, mut errors) = match format {
+            BatchFormat::Ndjson => ndjson_to_rows(&self.schema, body),
+            BatchFormat::Csv => csv_to_rows(&self.schema, body),
+        };
 
-        let entry_keys = val.keys();
-        for key in entry_keys {
-            if !self.schema.contains_key(&key) {
-                eprintln!("Missing key {} in database schema! Must be one of:", key);
-                for k in self.schema.keys() {
-                    eprintln!("{}", k);
-                }
+        // Rows without a timestamp each get their own millisecond from now on, so they don't all
+        // land on one key and go through the duplicate policy against each other.
+        let mut now = current_timestamp();
+        let lines = rows.iter().map(|(line, _, _)| *line).collect::<Vec<usize>>();
+        let requested = rows.into_iter()
+            .map(|(_, timestamp, row)| {
+                let key = timestamp.unwrap_or_else(|| {
+                    now += 1;
+                    now - 1
+                });
+                (key, row)
+            })
+            .collect::<Vec<(u128, Document)>>();
+
+        // Rows that parsed are still checked when others didn't, so every bad line is reported.
+        let planned = self.plan_batch(requested);
+
+        // A batch only refused for taken timestamps is a conflict, as it is for a single insert;
+        // anything else wrong with it makes it a bad request.
+        let status = match &planned {
+            Err(row_errors) if errors.is_empty() && row_errors.iter().all(|(_, e)| e.status() == 409) => 409,
+            _ => 400,
+        };
+        if let Err(row_errors) = &planned {
+            errors.extend(row_errors.iter().map(|(i, e)| (lines[*i], e.to_string())));
+        }
 
-                return;
+        if !errors.is_empty() {
+            errors.sort_by_key(|(line, _)| *line);
+            let mut rows = Document::new();
+            for (line, e) in errors {
+                rows.insert(format!("line {}", line), doc![ "line" : line as i64, "error" : e ]);
             }
+            return doc![
+                "labels" : ["line", "error"],
+                "rows" : rows,
+                "status" : status,
+            ];
         }
 
-        self.bptree.insert(key, val);
-    }
+        let (writes, inserted) = planned.expect("errors were checked above");
+        if let Err(e) = self.apply_batch(writes) {
+            return insert_error_page(e);
+        }
 
-    // Returns document laid out as so:
-    // Document {
-    //    "labels" : vec<strings>,
-    //    "rows : Document...
-    // }
-    pub fn query(&mut self, query_string: String) -> Document {
-        let options = query_string.trim().split("::").collect::<Vec<&str>>();
+        let duplicates = inserted.iter().filter(|i| i.duplicate.is_some()).count();
+        doc![
+            "labels" : ["inserted", "duplicates", "policy"],
+            "rows" : doc![
+                "inserted" : inserted.len() as i64,
+                "duplicates" : duplicates as i64,
+                "policy" : self.duplicate_policy.to_string(),
+            ],
+        ]
+    }
 
-        match options[0] {
-            "LIST" => self.handle_list_query(&options, &query_string),
-            "AGGREGATE" => self.handle_aggregate_query(&options),
-            "INSERT" => self.handle_insert_query(&options),
-            "REMOVE" => self.handle_remove_query(&options),
-            "TIME" => self.handle_time_query(&options),
-            "SAVE" => self.handle_save_query(&options),
-            "LOAD" => self.handle_load_query(&options),
-            _ => notice_page(format!("Invalid query: {}", query_string)),
+    // Body is newline-delimited JSON, one row per line, e.g.
+    // {"timestamp": 1733697225084, "store": 1, "number_sold": 5}
+    fn handle_bulk_query(&mut self, body: &str) -> Document {
+        if body.trim().is_empty() {
+            return notice_page(String::from("BULK requires a POST body with one JSON row per line"));
         }
-    }
 
-    fn handle_list_query(&mut self, options: &[&str], query_string: &str) -> Document {
-        if options.len() < 2 {
-            return notice_page("LIST command requires a category".to_string());
+        let (rows, mut line_errors) = ndjson_to_rows(&self.schema, body);
+        let lines = rows.iter().map(|(line, _, _)| *line).collect::<Vec<usize>>();
+
+        let summary = self.bulk_load(rows.into_iter().map(|(_, timestamp, row)| (timestamp.unwrap_or_else(current_timestamp), row)));
+
+        // Rows bulk_load refused are numbered by their place among the rows that parsed.
+        line_errors.extend(summary.rejected.iter().map(|(i, e)| (lines[*i], e.clone())));
+        line_errors.sort_by_key(|(line, _)| *line);
+
+        let mut result = doc![
+            "Notice" : format!(
+                "Loaded {} rows: {} appended in order, {} inserted individually, {} rejected.",
+                summary.appended + summary.inserted,
+                summary.appended,
+                summary.inserted,
+                line_errors.len(),
+            ),
+        ];
+        for (line, e) in line_errors {
+            result.insert(format!("line {}", line), e);
         }
 
-        match options[1] {
-            "ALL" => {
-                let results = self.get_range(u128::MIN, u128::MAX);
-                if query_string.contains("HIDE") {
-                    notice_page(String::from("Success!"))
-                } else {
-                    doc![
-                        "labels" : self.schema.keys().cloned().collect::<Vec<String>>(),
-                        "rows" : results,
-                    ]
-                }
+        doc![
+            "labels" : ["Notice"],
+            "rows" : result,
+        ]
+    }
+
+    fn handle_latest(&self, latest: Latest) -> Document {
+        if let Some(filter) = &latest.filter {
+            if let Err(e) = filter.check(&self.schema) {
+                return notice_page(format!("Invalid WHERE clause: {}", e));
             }
-            "ONE" => {
-                if options.len() < 3 {
-                    return notice_page("LIST::ONE requires a timestamp".to_string());
-                }
+        }
+        if let Some(column) = latest.by.as_ref().filter(|c| !self.schema.contains_key(c)) {
+            return notice_page(format!("Column '{}' is not in the schema", column));
+        }
 
-                let key = match options[2].parse::<u128>() {
-                    Ok(k) => k,
-                    Err(_) => return notice_page("Invalid timestamp format".to_string()),
-                };
+        let before = latest.before.unwrap_or_else(current_timestamp);
+        doc![
+            "labels" : self.schema.keys().cloned().collect::<Vec<String>>(),
+            "rows" : self.get_latest(latest.count, before, latest.filter.as_ref(), latest.by.as_deref()),
+        ]
+    }
 
-                let result = self.bptree.lookup(&key, |value| value.clone());
-                if result.is_some() {
-                    if query_string.contains("HIDE") {
-                        notice_page(String::from("Requested value found."))
-                    } else {
-                        doc![
-                            "labels" : self.schema.keys().cloned().collect::<Vec<String>>(),
-                            "rows" : doc![options[2] : result.unwrap()]
-                        ]
-                    }
-                } else {
-                    notice_page(String::from("Requested value could not be found."))
+    fn handle_delete(&mut self, delete: Delete) -> Document {
+        let removed = match delete {
+            Delete::At(timestamp) => self.remove_range(timestamp, timestamp, None),
+            Delete::Where { range, filter } => {
+                if let Some(Err(e)) = filter.as_ref().map(|p| p.check(&self.schema)) {
+                    return notice_page(format!("Invalid WHERE clause: {}", e));
                 }
+                let (lower, upper) = range.unwrap_or((u128::MIN, u128::MAX));
+                self.remove_range(lower, upper, filter.as_ref())
             }
-            "RANGE" => {
-                if options.len() < 3 {
-                    return notice_page("LIST::RANGE requires bounds in format 'lower,upper'".to_string());
+            Delete::All => {
+                if let Err(e) = self.log(WalRecord::Clear) {
+                    return notice_page(format!("Failed to write removal to the write-ahead log: {}", e));
                 }
 
-                let bounds = options[2].split(",").collect::<Vec<&str>>();
-                if bounds.len() != 2 {
-                    return notice_page("Invalid range format. Use 'lower,upper'".to_string());
-                }
-
-                let bound_0 = match bounds[0].parse::<u128>() {
-                    Ok(b) => b,
-                    Err(_) => return notice_page("Invalid lower bound".to_string()),
-                };
-                let bound_1 = match bounds[1].parse::<u128>() {
-                    Ok(b) => b,
-                    Err(_) => return notice_page("Invalid upper bound".to_string()),
-                };
-
-                let (lower, upper) = if bound_0 <= bound_1 { (bound_0, bound_1) } else { (bound_1, bound_0) };
-                let results = self.get_range(lower, upper);
-
-                if query_string.contains("HIDE") {
-                    notice_page(String::from("Success!"))
-                } else {
-                    doc![
-                        "labels" : self.schema.keys().cloned().collect::<Vec<String>>(),
-                        "rows" : results,
-                    ]
-                }
+                let size = self.bptree.len();
+                self.clear_unlogged();
+                Ok(size)
             }
-            "SAVED" => {
-                let file_list = list_files(DATA_PATH.parse().unwrap()).unwrap_or(vec!["No saved databases found.".parse().unwrap()]);
-                let mut file_list_doc = bson::Document::new();
-                for (i, f) in file_list.iter().enumerate() {
-                    file_list_doc.insert(&i.to_string(), f.clone());
-                }
+        };
 
-                doc![
-                    "labels" : ["Saved Databases"],
-                    "rows" : file_list_doc,
-                ]
+        match removed {
+            Ok(removed) => doc![
+                "labels" : ["removed"],
+                "rows" : doc![ "removed" : removed as i64 ],
+            ],
+            Err(e) => notice_page(e),
+        }
+    }
+
+    // Removes the rows with keys in [start_key, end_key] that the filter matches, returning how
+    // many there were. Their removals are logged in one write before any of them is removed.
+    pub fn remove_range(&mut self, start_key : u128, end_key : u128, filter : Option<&Predicate>) -> Result<usize, String> {
+        let mut keys = Vec::new();
+        let mut iter = self.bptree.raw_iter();
+        iter.seek(&start_key);
+        while let Some((key, row)) = iter.next() {
+            if *key > end_key {
+                break;
             }
-            _ => {
-                // METADATA or any other option
-                doc![
-                    "labels" : ["size", "schema"],
-                    "rows" : doc![
-                        "size" : self.bptree.len() as i64,
-                        "schema" : self.schema.clone(),
-                    ]
-                ]
+            if filter.is_none_or(|p| p.matches(row)) {
+                keys.push(*key);
             }
         }
+        drop(iter);
+
+        let records = keys.iter().map(|key| WalRecord::Remove { key: *key }).collect::<Vec<WalRecord>>();
+        self.log_all(&records).map_err(|e| format!("Failed to write removal to the write-ahead log: {}", e))?;
+
+        for key in &keys {
+            self.remove_unlogged(*key);
+        }
+        Ok(keys.len())
     }
 
-    fn handle_aggregate_query(&self, options: &[&str]) -> Document {
-        if options.len() < 3 {
-            return notice_page("AGGREGATE command requires field name and operation".to_string());
+    // Smallest and largest keys in the tree, or None when it is empty.
+    pub fn time_bounds(&self) -> Option<(u128, u128)> {
+        if self.min_timestamp > self.max_timestamp {
+            None
+        } else {
+            Some((self.min_timestamp, self.max_timestamp))
         }
+    }
 
-        let field_name = options[1].to_string();
-        let operation = options[2].to_string();
-        let result = self.aggregate(operation.clone(), field_name.clone());
+    pub fn get_one(&self, key : u128) -> Option<bson::Document> {
+        self.bptree.lookup(&key, |value| value.clone() )
+    }
 
-        doc![
-            "labels" : [operation.as_str()],
-            "rows" : doc![
-                operation.as_str() : result as i64,
-            ]
-        ]
+    // The row at key or, failing that, the closest one before it, at most tolerance away.
+    pub fn get_at_or_before(&self, key : u128, tolerance : Option<u128>) -> Option<(u128, Document)> {
+        self.get_as_of(key, AsOf::AtOrBefore, tolerance, None)
     }
 
-    fn handle_insert_query(&mut self, options: &[&str]) -> Document {
-        if options.len() < 2 {
-            return notice_page("INSERT command requires key-value pairs".to_string());
-        }
+    // The row at key or, failing that, the closest one after it, at most tolerance away.
+    pub fn get_at_or_after(&self, key : u128, tolerance : Option<u128>) -> Option<(u128, Document)> {
+        self.get_as_of(key, AsOf::AtOrAfter, tolerance, None)
+    }
 
-        let key_value_pairs = options[1].split(",").collect::<Vec<&str>>();
-        let mut row_document = Document::new();
+    // Whichever of those two is closer to key, the earlier one on a tie.
+    pub fn get_nearest(&self, key : u128, tolerance : Option<u128>) -> Option<(u128, Document)> {
+        self.get_as_of(key, AsOf::Nearest, tolerance, None)
+    }
 
-        for pair in key_value_pairs {
-            if let Some((k, v)) = pair.split_once("=") {
-                if self.schema.contains_key(k) {
-                    let bson_value = match self.schema.get_str(k).unwrap_or("string") {
-                        "bool" => Bson::Boolean(v.parse::<bool>().unwrap_or(false)),
-                        "number" => Bson::Double(v.parse::<f64>().unwrap_or(0.0)),
-                        "string" | _ => Bson::String(v.to_string()),
-                    };
-                    row_document.insert(k.to_string(), bson_value);
+    // The row for key found as as_of says, along with its own key. Rows the filter doesn't
+    // match are passed over, so e.g. the nearest row for one sensor can be asked for.
+    pub fn get_as_of(
+        &self,
+        key : u128,
+        as_of : AsOf,
+        tolerance : Option<u128>,
+        filter : Option<&Predicate>,
+    ) -> Option<(u128, Document)> {
+        let matches = |row: &Document| filter.is_none_or(|p| p.matches(row));
+        let within = |found: u128| tolerance.is_none_or(|t| found.abs_diff(key) <= t);
+
+        let before = || {
+            let mut iter = self.bptree.raw_iter();
+            iter.seek_for_prev(&key);
+            while let Some((found, row)) = iter.prev() {
+                // See get_page: the cursor can start just after a key past this one.
+                if *found > key {
+                    continue;
+                }
+                if !within(*found) {
+                    break;
+                }
+                if matches(row) {
+                    return Some((*found, row.clone()));
                 }
             }
-        }
-
-        let mut timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis();
-
-        if options.len() > 2 {
-            if let Some(t_str) = options[2].split("=").last() {
-                if let Ok(t) = t_str.parse::<u128>() {
-                    timestamp = t;
+            None
+        };
+        let after = || {
+            let mut iter = self.bptree.raw_iter();
+            iter.seek(&key);
+            while let Some((found, row)) = iter.next() {
+                if !within(*found) {
+                    break;
+                }
+                if matches(row) {
+                    return Some((*found, row.clone()));
                 }
             }
-        }
+            None
+        };
 
-        if row_document.len() > 0 {
-            self.insert_to_database(timestamp, row_document);
+        match as_of {
+            AsOf::Exact => self.get_one(key).filter(|row| matches(row)).map(|row| (key, row)),
+            AsOf::AtOrBefore => before(),
+            AsOf::AtOrAfter => after(),
+            AsOf::Nearest => match (before(), after()) {
+                (Some(b), Some(a)) if a.0 - key < key - b.0 => Some(a),
+                (b, a) => b.or(a),
+            },
         }
+    }
 
-        notice_page(String::from("Success!"))
+    pub fn get_range(&self, start_key : u128, end_key : u128) -> Document {
+        self.get_range_filtered(start_key, end_key, None)
+    }
+
+    // Like get_range, keeping only rows the predicate matches. The predicate is tested as the
+    // cursor walks the range, so rows that don't match are never copied.
+    pub fn get_range_filtered(&self, start_key : u128, end_key : u128, filter : Option<&Predicate>) -> Document {
+        self.get_page(start_key, end_key, filter, None, &Page::default()).0
     }
 
-    fn handle_remove_query(&mut self, options: &[&str]) -> Document {
-        if options.len() < 2 {
-            return notice_page("REMOVE command requires a category".to_string());
+    // Like get_range_filtered, listing the rows in page.order and only those page asks for,
+    // with only the given columns when there are some. Also returns the key of the last row
+    // listed when LIMIT left rows out, for the next page to continue after. A page that
+    // continues after a key seeks the cursor straight to it, so later pages cost no more than
+    // the first.
+    pub fn get_page(
+        &self,
+        start_key : u128,
+        end_key : u128,
+        filter : Option<&Predicate>,
+        columns : Option<&[String]>,
+        page : &Page,
+    ) -> (Document, Option<u128>) {
+        let mut result = doc![];
+        let descending = page.order == Order::Descending;
+
+        let (start_key, end_key) = match (page.after, descending) {
+            (None, _) => (start_key, end_key),
+            (Some(after), false) => match after.checked_add(1) {
+                Some(next) => (start_key.max(next), end_key),
+                None => return (result, None),
+            },
+            (Some(after), true) => match after.checked_sub(1) {
+                Some(next) => (start_key, end_key.min(next)),
+                None => return (result, None),
+            },
+        };
+
+        // Put the cursor immediately at the end it starts from, or the next available spot.
+        let mut iter = self.bptree.raw_iter();
+        if descending {
+            iter.seek_for_prev(&end_key);
+        } else {
+            iter.seek(&start_key);
         }
 
-        match options[1] {
-            "ONE" => {
-                if options.len() < 3 {
-                    return notice_page("REMOVE::ONE requires timestamp parameter".to_string());
-                }
+        let mut skip = page.offset;
+        let mut last = None;
+        loop {
+            let cursor = if descending { iter.prev() } else { iter.next() };
+            let (current_key, current_row) = match cursor {
+                // When end_key itself isn't in the tree, seek_for_prev leaves the cursor
+                // after the key following it rather than before.
+                Some((key, _)) if descending && *key > end_key => continue,
+                Some((key, row)) if *key >= start_key && *key <= end_key => (*key, row),
+                _ => break,
+            };
 
-                if options[2].contains("TIME") {
-                    if let Some(timestamp_str) = options[2].split("=").nth(1) {
-                        if let Ok(timestamp) = timestamp_str.parse::<u128>() {
-                            self.bptree.remove(&timestamp);
-                            return notice_page(String::from("Success!"));
-                        }
-                    }
-                }
-                notice_page(String::from("Entry not found."))
+            if filter.is_some_and(|p| !p.matches(current_row)) {
+                continue;
             }
-            "ALL" => {
-                self.bptree = Box::new(bplustree::GenericBPlusTree::new());
-                self.min_timestamp = u128::MAX;
-                self.max_timestamp = u128::MIN;
-                notice_page(String::from("Success!"))
+            if skip > 0 {
+                skip -= 1;
+                continue;
             }
-            _ => {
-                notice_page(String::from("Invalid query. Currently, only one entry can be removed at a time, by timestamp."))
+            if page.limit.is_some_and(|limit| result.len() >= limit) {
+                return (result, last);
             }
-        }
-    }
 
-    fn handle_time_query(&self, options: &[&str]) -> Document {
-        if options.len() < 2 {
-            return notice_page("TIME command requires a timestamp string".to_string());
+            match columns {
+                Some(_) => result.insert(current_key.to_string(), project(current_row, columns)),
+                None => result.insert(current_key.to_string(), current_row),
+            };
+            last = Some(current_key);
         }
 
-        let timestamp = match NaiveDateTime::parse_from_str(&options[1].replace("%20", " "), "%Y-%m-%d %H:%M:%S") {
-            Ok(dt) => dt.and_utc().timestamp() * 1000,
-            Err(_) => return notice_page("Invalid timestamp format. Use YYYY-MM-DD HH:MM:SS".to_string()),
-        };
-
-        doc![
-            "labels" : ["time"],
-            "rows" : doc![
-                "time" : timestamp.to_string(),
-            ]
-        ]
+        (result, None)
     }
 
-    fn handle_save_query(&mut self, options: &[&str]) -> Document {
-        if options.len() < 2 {
-            return notice_page(String::from("Provide a filename to save the database. Example: 'SAVE::database_name'"));
-        }
-
-        if options[1].contains("CSV") {
-            self.data_to_csv();
-            notice_page(String::from("Dumped database to CSV."))
-        } else {
-            let sanitized = sanitize_filename::sanitize(options[1].to_string());
-            self.save(sanitized);
-            self.query("LIST::SAVED".to_string())
+    // The newest count rows with keys before `before`, newest first, found by seeking to
+    // max_timestamp (or just before `before`) and walking backwards. With by, the newest count
+    // rows for each value of that column instead, rows without one sharing null. Any older row
+    // could hold a value not seen yet, so that walks back through every row.
+    pub fn get_latest(&self, count : usize, before : u128, filter : Option<&Predicate>, by : Option<&str>) -> Document {
+        let mut result = doc![];
+        if count == 0 || before == 0 {
+            return result;
         }
-    }
 
-    fn handle_load_query(&mut self, options: &[&str]) -> Document {
-        if options.len() < 2 {
-            return notice_page(String::from("Provide a filename to load. Example: 'LOAD::database_name' or 'LOAD::SCHEMA::schema_file.schema.r2d2'"));
-        }
+        let end_key = self.max_timestamp.min(before - 1);
+        let mut iter = self.bptree.raw_iter();
+        iter.seek_for_prev(&end_key);
 
-        match options[1] {
-            "SCHEMA" => {
-                if options.len() < 3 {
-                    return notice_page(String::from("Provide a schema filename. Example: 'LOAD::SCHEMA::schema_file.schema.r2d2'"));
-                }
+        let mut seen : HashMap<String, usize> = HashMap::new();
+        while let Some((key, row)) = iter.prev() {
+            // See get_page: the cursor can start just after a key past end_key.
+            if *key > end_key || filter.is_some_and(|p| !p.matches(row)) {
+                continue;
+            }
 
-                match self.load_schema_from_file(options[2].to_string()) {
-                    Ok(_) => {
-                        // Return metadata with success message
-                        let mut metadata_doc = doc![
-                            "labels" : ["message", "schema"],
-                            "rows" : doc![
-                                "message" : "Successfully loaded schema",
-                                "schema" : self.schema.clone(),
-                            ]
-                        ];
-                        metadata_doc
-                    }
-                    Err(error_msg) => {
-                        notice_page(format!("Error loading schema: {}", error_msg))
+            match by {
+                None => {
+                    result.insert(key.to_string(), row);
+                    if result.len() >= count {
+                        break;
                     }
                 }
-            }
-            _ => {
-                // Regular database load
-                let result = self.load(sanitize_filename::sanitize(options[1].to_string()));
-                if result.is_ok() {
-                    self.query("LIST::ALL".to_string())
-                } else {
-                    notice_page(String::from("LOAD operation failed. See server logs."))
+                Some(column) => {
+                    let tag = row.get(column).cloned().unwrap_or(Bson::Null);
+                    let listed = seen.entry(group_id(None, &[column.to_string()], &[tag])).or_default();
+                    if *listed < count {
+                        *listed += 1;
+                        result.insert(key.to_string(), row);
+                    }
                 }
             }
         }
+
+        result
     }
 
-    pub fn get_one(&self, key : u128) -> Option<bson::Document> {
-        self.bptree.lookup(&key, |value| value.clone() )
+    // operation is a name as in the '::' syntax, e.g. SUM, COUNT_DISTINCT or PERCENTILE=95.
+    pub fn aggregate(&self, operation: String, field_name: String) -> Result<AggregateResult, String> {
+        let function = AggregateFunction::from_name(&operation)?;
+        Ok(self.aggregate_range(function, &field_name, u128::MIN, u128::MAX, None))
     }
 
-    pub fn get_range(&self, start_key : u128, end_key : u128) -> Document {
-        let mut result = doc![];
+    // Like aggregate, over only the rows in [start_key, end_key] that the filter matches. The
+    // cursor is seeked to start_key, so rows before the range are never visited.
+    pub fn aggregate_range(
+        &self,
+        function: AggregateFunction,
+        field_name: &str,
+        start_key: u128,
+        end_key: u128,
+        filter: Option<&Predicate>,
+    ) -> AggregateResult {
+        let accumulators = self.aggregate_many(&[Aggregate::new(function, field_name)], start_key, end_key, filter);
+        accumulators.results()[0]
+    }
+
+    // Computes every aggregate over the rows in [start_key, end_key] that the filter matches,
+    // in a single pass of the cursor.
+    pub fn aggregate_many(
+        &self,
+        aggregates: &[Aggregate],
+        start_key: u128,
+        end_key: u128,
+        filter: Option<&Predicate>,
+    ) -> Accumulators {
+        let mut accumulators = Accumulators::new(aggregates);
+
         let mut iter = self.bptree.raw_iter();
 
         // Put the cursor immediately at the given start index, or the next available spot.
         iter.seek(&start_key);
         let mut cursor = iter.next();
 
-        while cursor.is_some() && cursor.unwrap().0 <= &end_key {
-            let (current_key, current_row) = cursor.unwrap();
-            result.insert(current_key.to_string(), current_row);
+        while let Some((current_key, current_row)) = cursor {
+            if current_key > &end_key {
+                break;
+            }
+
+            if filter.is_none_or(|p| p.matches(current_row)) {
+                accumulators.push_row(current_row);
+            }
 
             cursor = iter.next();
         }
 
-        result
+        accumulators
     }
 
-    pub fn aggregate(&self, operation: String, field_name: String) -> f64 {
-        let mut result : f64 = 0.0;
+    // Applies function to field separately for each time bucket in [start_key, end_key], over
+    // rows the filter matches. The result has one row per bucket that had a value, keyed by the
+    // bucket's start time, with a function.label(field) column and the number of rows that
+    // contributed to it: { "1733697000000" : { "AVG(number_sold)" : 4.5, "count" : 10 }, ... }
+    pub fn aggregate_buckets(
+        &self,
+        function : AggregateFunction,
+        field : &str,
+        start_key : u128,
+        end_key : u128,
+        bucket : TimeBucket,
+        filter : Option<&Predicate>,
+    ) -> Result<Document, String> {
+        self.aggregate_groups(&[Aggregate::new(function, field)], start_key, end_key, Some(bucket), &[], filter, None)
+    }
 
-        if operation == "MIN" {
-            result = f64::MAX;
+    // Like aggregate_buckets, additionally (or instead) splitting rows by the values of the
+    // group_by columns. With group_by columns, rows can no longer be keyed by time alone; each
+    // row holds its bucket start under "time" (when bucketing), then the group_by values, then
+    // a column per aggregate and "count", and is keyed by those values, e.g.
+    // "time=1733697000000,store=3". Groups are listed by bucket, then in the order they first
+    // appear. Rows without a value for a group_by column are grouped under null.
+    //
+    // With fill and a time bucket, buckets without rows are not left out: every bucket from
+    // start_key's to end_key's gets a row for every group, with a count of 0 and the aggregates
+    // filled in. Unbounded ends (u128::MIN, u128::MAX) stop at the first and last bucket with rows.
+    #[allow(clippy::too_many_arguments)]
+    pub fn aggregate_groups(
+        &self,
+        aggregates : &[Aggregate],
+        start_key : u128,
+        end_key : u128,
+        bucket : Option<TimeBucket>,
+        group_by : &[String],
+        filter : Option<&Predicate>,
+        fill : Option<Fill>,
+    ) -> Result<Document, String> {
+        if let Some(column) = aggregates.iter().map(|a| a.field.as_str()).chain(group_by.iter().map(String::as_str))
+            .find(|c| !self.schema.contains_key(c)) {
+            return Err(format!("Column '{}' is not in the schema", column));
         }
+        if let Some(filter) = filter {
+            filter.check(&self.schema).map_err(|e| format!("Invalid WHERE clause: {}", e))?;
+        }
+
+        // Groups of the current bucket, and every finished bucket's groups. Without time
+        // buckets, everything is one bucket.
+        let mut buckets : Vec<(u128, Vec<Group>)> = Vec::new();
+        let mut group_index : HashMap<String, usize> = HashMap::new();
 
         let mut iter = self.bptree.raw_iter();
+        iter.seek(&start_key);
 
-        // Put the cursor immediately at the given start index, or the next available spot.
-        iter.seek_to_first();
-        let mut cursor = iter.next();
+        // Keys come in order, so each row either joins the last bucket or starts a new one.
+        while let Some((key, row)) = iter.next() {
+            if *key > end_key {
+                break;
+            }
+            if filter.is_some_and(|p| !p.matches(row)) {
+                continue;
+            }
 
-        while cursor.is_some() {
-            let (_, current_row) = cursor.unwrap();
-            let value = current_row.get(&field_name);
+            if aggregates.iter().all(|a| a.value(row).is_none()) {
+                continue;
+            }
 
-            if value.is_some() {
-                let parsed_value = value.unwrap();
-                //print!("parsed -> {:?}", parsed_value);
+            let start = bucket.map_or(0, |b| bucket_start(*key, b.width, b.origin));
+            if buckets.last().is_none_or(|(last, _)| *last != start) {
+                buckets.push((start, Vec::new()));
+                group_index.clear();
+            }
+            let groups = &mut buckets.last_mut().unwrap().1;
+
+            let tags = group_by.iter().map(|c| row.get(c).cloned().unwrap_or(Bson::Null)).collect::<Vec<Bson>>();
+            let index = *group_index.entry(group_id(None, group_by, &tags)).or_insert_with(|| {
+                groups.push((tags, Accumulators::new(aggregates)));
+                groups.len() - 1
+            });
+            groups[index].1.push_row(row);
+        }
 
-                let converted;
-                if parsed_value.as_str().is_some() && parsed_value.as_str().unwrap().parse::<f64>().is_ok() {
-                    converted = parsed_value.as_str().unwrap().parse::<f64>().unwrap();
-                }
+        let mut results = buckets.into_iter()
+            .flat_map(|(start, groups)| groups.into_iter().map(move |(tags, accumulators)| {
+                let values = accumulators.results().iter().map(|r| r.value).collect::<Vec<Option<f64>>>();
+                (start, tags, values, accumulators.count())
+            }))
+            .collect::<Vec<GroupResult>>();
+
+        if let (Some(fill), Some(bucket)) = (fill, bucket) {
+            let first = match start_key {
+                u128::MIN => results.first().map(|r| r.0),
+                key => Some(bucket_start(key, bucket.width, bucket.origin)),
+            };
+            let last = match end_key {
+                u128::MAX => results.last().map(|r| r.0),
+                key => Some(bucket_start(key, bucket.width, bucket.origin)),
+            };
+            if let (Some(first), Some(last)) = (first, last) {
+                results = fill_buckets(results, fill, first, last, bucket, group_by, aggregates.len())?;
+            }
+        }
 
-                else {
-                    converted = match parsed_value.element_type() {
-                        ElementType::Double => parsed_value.as_f64().unwrap(),
-                        ElementType::Int32 => (parsed_value.as_i32().unwrap()) as f64,
-                        ElementType::Int64 => (parsed_value.as_i64().unwrap()) as f64,
-                        _ => 0.0
-                    };
-                }
+        let mut rows = Document::new();
 
-                match operation.as_str() {
-                    "MIN" => {
-                        if converted < result {
-                            result = converted;
-                        }
-                    }
+        for (start, tags, values, count) in results {
+            let columns = aggregate_columns(aggregates.iter(), &values, count);
+            if group_by.is_empty() {
+                rows.insert(start.to_string(), columns);
+                continue;
+            }
 
-                    "MAX" => {
-                        if converted > result {
-                            result = converted;
-                        }
-                    }
+            let mut row = Document::new();
+            // Bucket starts can be past i64::MAX, so they are given as text.
+            if bucket.is_some() {
+                row.insert("time", start.to_string());
+            }
+            for (column, tag) in group_by.iter().zip(tags.iter()) {
+                row.insert(column.clone(), tag.clone());
+            }
+            row.extend(columns);
+
+            rows.insert(group_id(bucket.map(|_| start), group_by, &tags), row);
+        }
+
+        Ok(rows)
+    }
 
-                    "SUM" | "AVG" | _ => {
-                        result += converted;
+    // Applies function to the readings of field in [start_key, end_key], over rows the filter
+    // matches, in key order. Rows without a numeric value for field are skipped over, so the
+    // change is always between consecutive readings. Gives a value per reading after the first,
+    // keyed by its timestamp: { "1733697000500" : { "RATE(requests)" : 0.2 }, ... }
+    // With a time bucket, the changes of every pair of readings ending in a bucket are added
+    // up, and the bucket gets the total change over the total time, keyed by its start. Buckets
+    // without such a pair are left out. Only change functions (see is_change) can be bucketed.
+    pub fn transform_range(
+        &self,
+        function : TransformFunction,
+        field : &str,
+        start_key : u128,
+        end_key : u128,
+        bucket : Option<TimeBucket>,
+        filter : Option<&Predicate>,
+    ) -> Result<Document, String> {
+        if !self.schema.contains_key(field) {
+            return Err(format!("Column '{}' is not in the schema", field));
+        }
+        if let Some(filter) = filter {
+            filter.check(&self.schema).map_err(|e| format!("Invalid WHERE clause: {}", e))?;
+        }
+        if bucket.is_some() && !function.is_change() {
+            return Err(format!("{} cannot be grouped by time", function));
+        }
+
+        let label = function.label(field);
+        let mut rows = Document::new();
+        let mut buckets : Vec<(u128, Change)> = Vec::new();
+        let mut differ = Differ::default();
+        let mut transformer = Transformer::new(function);
+
+        let mut iter = self.bptree.raw_iter();
+        iter.seek(&start_key);
+
+        while let Some((key, row)) = iter.next() {
+            if *key > end_key {
+                break;
+            }
+            if filter.is_some_and(|p| !p.matches(row)) {
+                continue;
+            }
+
+            let value = match row.get(field).and_then(bson_to_f64) {
+                Some(v) => v,
+                None => continue,
+            };
+
+            match bucket {
+                Some(b) => {
+                    let change = match differ.push(*key, value) {
+                        Some(change) => change,
+                        None => continue,
+                    };
+                    let start = bucket_start(*key, b.width, b.origin);
+                    match buckets.last_mut() {
+                        Some((last, total)) if *last == start => total.add(change),
+                        _ => buckets.push((start, change)),
+                    }
+                }
+                None => {
+                    if let Some(output) = transformer.push(*key, value) {
+                        rows.insert(key.to_string(), doc![label.as_str() : output]);
                     }
                 }
             }
-
-            cursor = iter.next();
         }
 
-        if operation == "AVG" {
-            result /= self.bptree.len() as f64;
+        for (start, change) in buckets {
+            if let Some(value) = function.apply(&change) {
+                rows.insert(start.to_string(), doc![label.as_str() : value]);
+            }
         }
 
-        result
+        Ok(rows)
     }
 
+    // Names ending in SEGMENT_EXTENSION are saved in the columnar segment format, anything
+    // else as a BSON document.
     pub fn save(&self, filename: String) {
+        if is_segment_file(&filename) {
+            if let Err(e) = self.save_segment(filename) {
+                eprintln!("Failed to save segment file: {}", e);
+            }
+        } else {
+            save_file_unique(filename, self.serialize()).unwrap();
+        }
+    }
+
+    fn save_segment(&self, filename: String) -> Result<(), Error> {
+        let new_filename = unique_filename(filename, SEGMENT_EXTENSION)?;
+        self.write_segment(BufWriter::new(File::create(format!("{}/{}", DATA_PATH, new_filename))?))?;
+        Ok(())
+    }
+
+    // Writes every row, in key order, as a segment file.
+    fn write_segment<W: Write>(&self, out: W) -> Result<W, Error> {
+        let mut writer = SegmentWriter::new(out, &self.schema, &self.settings(), self.min_timestamp, self.max_timestamp)?;
+
+        let mut cursor = self.bptree.raw_iter();
+        cursor.seek_to_first();
+
+        while let Some((key, row)) = cursor.next() {
+            writer.push(*key, row.clone())?;
+        }
+
+        writer.finish()
+    }
+
+    fn load_segment(&mut self, filename: String) -> Result<(), Error> {
+        let mut reader = SegmentReader::open(BufReader::new(File::open(format!("{DATA_PATH}/{filename}"))?))?;
+
+        let (schema_keys_s, schema_types_s) = schema_fields_and_types(reader.schema());
+        self.reset(schema_keys_s, schema_types_s);
+        let settings = reader.settings().clone();
+        self.apply_settings(&settings);
+
+        // append_sorted tracks the bounds of the rows actually loaded; the header's copy is left
+        // alone, since files saved before deletes kept the bounds right can have stale ones.
+        while let Some(block) = reader.next_block()? {
+            self.append_sorted(block.into_iter().map(Ok))?;
+        }
+
+        Ok(())
+    }
+
+    fn serialize(&self) -> Document {
         // Because the Rust BSON package itself does not support u128, we will need
         // to convert them to strings, and parse them when loading the database.
 
         let mut serialized = Document::new();
         let mut serialized_rows = Document::new();
         serialized.insert("schema", self.schema.clone());
+        serialized.insert("settings", self.settings());
         serialized.insert("min_timestamp", self.min_timestamp.clone().to_string());
         serialized.insert("max_timestamp", self.max_timestamp.clone().to_string());
 
@@ -581,39 +2201,75 @@ impl Database {
         }
 
         serialized.insert("rows", serialized_rows);
-        save_file_unique(filename, serialized).unwrap();
+        serialized
     }
 
-    pub fn load (&mut self, filename: String) -> Result<(), Error>{
-        if list_files(DATA_PATH.parse().unwrap())?.contains(&filename) {
-            let file_document = Document::from_reader(File::open(format!("{DATA_PATH}/{filename}"))?);
+    // Inverse of serialize(). Replaces the schema and every row with the contents of a .r2d2
+    // file under DATA_PATH, reading it one row at a time so that only the tree has to fit in
+    // memory. Returns the file's other top-level fields. The saved min_timestamp and
+    // max_timestamp are ignored in favour of the bounds of the rows read, as in load_segment().
+    fn restore(&mut self, filename: &str) -> Result<Document, Error> {
+        let mut reader = BsonSaveReader::open(BufReader::new(File::open(format!("{}/{}", DATA_PATH, filename))?))?;
 
-            if file_document.is_err() {
-                println!("{}", file_document.unwrap_err());
-            }
+        let schema = reader.header().get_document("schema")
+            .map_err(|_| Error::new(ErrorKind::InvalidData, "saved database has no valid schema"))?;
+
+        let (schema_keys_s, schema_types_s) = schema_fields_and_types(schema);
+        self.reset(schema_keys_s, schema_types_s);
 
-            else {
-                let deserialized_document = file_document.unwrap();
+        if let Ok(settings) = reader.header().get_document("settings") {
+            let settings = settings.clone();
+            self.apply_settings(&settings);
+        }
+
+        self.append_sorted(&mut reader)?;
 
-                let schema = deserialized_document.get("schema").unwrap().as_document().unwrap();
-                let schema_keys = schema.keys().collect::<Vec<&String>>();
-                let schema_types = schema.values();
+        Ok(reader.header().clone())
+    }
 
-                let schema_keys_s = schema_keys.iter().map(|k| k.to_string()).collect::<Vec<String>>();
-                let schema_types_s = schema_types.into_iter().map(|k| k.to_string()).collect::<Vec<String>>();
+    // Inserts rows through a single exclusive cursor. When keys arrive in ascending order, as
+    // they do from any save file, the cursor stays on the rightmost leaf and each row is appended
+    // there, rather than descending from the root and doing a collision lookup first. Rows out
+    // of order are still placed correctly, just without that shortcut.
+    fn append_sorted<I>(&mut self, rows: I) -> Result<(), Error>
+    where
+        I: Iterator<Item = Result<(u128, Document), Error>>,
+    {
+        let mut min_timestamp = self.min_timestamp;
+        let mut max_timestamp = self.max_timestamp;
+
+        {
+            let mut cursor = self.bptree.raw_iter_mut();
+            for row in rows {
+                let (key, row) = row?;
+                min_timestamp = min_timestamp.min(key);
+                max_timestamp = max_timestamp.max(key);
+                cursor.insert(key, row);
+            }
+        }
 
-                *self = Database::new(
-                    schema_keys_s, schema_types_s
-                );
+        self.min_timestamp = min_timestamp;
+        self.max_timestamp = max_timestamp;
 
-                self.min_timestamp = deserialized_document.get("min_timestamp").unwrap().as_str().unwrap().parse::<u128>().unwrap();
-                self.max_timestamp = deserialized_document.get("max_timestamp").unwrap().as_str().unwrap().parse::<u128>().unwrap();
+        Ok(())
+    }
 
-                let rows = deserialized_document.get("rows").unwrap().as_document().unwrap();
-                for (key, row) in rows.iter() {
-                    self.insert_to_database(key.parse::<u128>().unwrap(), row.as_document().unwrap().clone());
-                }
+    pub fn load (&mut self, filename: String) -> Result<(), Error>{
+        // Saved files are never overwritten, so logging the name is enough to replay the load.
+        if list_files(DATA_PATH.parse().unwrap())?.contains(&filename) {
+            self.log(WalRecord::Load { filename: filename.clone() })?;
+        }
+
+        self.load_unlogged(filename)
+    }
+
+    fn load_unlogged(&mut self, filename: String) -> Result<(), Error> {
+        if list_files(DATA_PATH.parse().unwrap())?.contains(&filename) {
+            if is_segment_file(&filename) {
+                return self.load_segment(filename);
             }
+
+            self.restore(&filename)?;
         }
 
         Ok(())
@@ -626,8 +2282,8 @@ impl Database {
             header.push_str(format!(",{}", &k).as_str());
         }
 
-        header.push_str("\n");
-        f.write(header.as_bytes()).expect("Unable to write header");
+        header.push('\n');
+        f.write_all(header.as_bytes()).expect("Unable to write header");
 
         let mut cursor = self.bptree.raw_iter();
         cursor.seek_to_first();
@@ -635,8 +2291,8 @@ impl Database {
         let mut current_row = cursor.next();
         while current_row.is_some() {
             let (key, row) = current_row.unwrap();
-            let s = document_to_csv_row(row.clone(), key.clone());
-            f.write(s.as_bytes()).expect("Unable to write data");
+            let s = document_to_csv_row(row.clone(), *key);
+            f.write_all(s.as_bytes()).expect("Unable to write data");
 
             current_row = cursor.next();
         }
@@ -659,11 +2315,13 @@ fn list_files(path: String) -> Result<Vec<String>, Error> {
     Ok(result)
 }
 
-fn save_file_unique(mut filename: String, data: Document) -> Result<(), Error> {
+// Replaces the extension of the given name, appending _1, _2, ... until it no longer
+// collides with an existing file.
+fn unique_filename(filename: String, extension: &str) -> Result<String, Error> {
     let directory_list = list_files(DATA_PATH.parse().unwrap())?;
 
     let mut new_filename = filename.clone().split(".").collect::<Vec<&str>>()[0].to_string();
-    new_filename.push_str(".r2d2");
+    new_filename.push_str(extension);
 
     if !directory_list.is_empty() {
         let mut postfix = 1;
@@ -673,14 +2331,19 @@ fn save_file_unique(mut filename: String, data: Document) -> Result<(), Error> {
             println!("{} exists...", new_filename);
 
             new_filename = filename.clone().split(".").collect::<Vec<&str>>()[0].to_string();
-            new_filename = new_filename.replace(".r2d2", "");
-            new_filename.push_str(format!("_{}.r2d2", postfix).as_str());
+            new_filename.push_str(format!("_{}{}", postfix, extension).as_str());
 
             exists = directory_list.contains(&new_filename);
             postfix += 1;
         }
     }
 
+    Ok(new_filename)
+}
+
+fn save_file_unique(filename: String, data: Document) -> Result<(), Error> {
+    let new_filename = unique_filename(filename, ".r2d2")?;
+
     let mut output_file = File::create(format!("{}/{}", DATA_PATH, new_filename))?;
     let mut v : Vec<u8> = Vec::new();
     data.to_writer(&mut v).unwrap();
@@ -689,3 +2352,44 @@ fn save_file_unique(mut filename: String, data: Document) -> Result<(), Error> {
 
     Ok(())
 }
+
+// Writes to a temporary file, fsyncs it, and renames it over the final name, so a crash leaves
+// either the complete file or nothing under that name.
+fn save_file_atomic<F>(filename: &str, write: F) -> Result<(), Error>
+where
+    F: FnOnce(&mut BufWriter<File>) -> Result<(), Error>,
+{
+    let final_path = format!("{}/{}", DATA_PATH, filename);
+    let temp_path = format!("{}.tmp", final_path);
+
+    let mut output = BufWriter::new(File::create(&temp_path)?);
+    write(&mut output)?;
+    output.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+
+    fs::rename(&temp_path, &final_path)?;
+
+    // The rename is only durable once the directory entry is.
+    File::open(DATA_PATH)?.sync_all()?;
+
+    Ok(())
+}
+
+fn checkpoint_filename(wal_filename: &str, lsn: u64) -> String {
+    format!("{}.{}.checkpoint", wal_filename, lsn)
+}
+
+// Checkpoints belonging to the given log, oldest first.
+fn list_checkpoints(wal_filename: &str) -> Result<Vec<(u64, String)>, Error> {
+    let prefix = format!("{}.", wal_filename);
+
+    let mut checkpoints = list_files(DATA_PATH.parse().unwrap())?
+        .into_iter()
+        .filter_map(|f| {
+            let lsn = f.strip_prefix(&prefix)?.strip_suffix(".checkpoint")?.parse::<u64>().ok()?;
+            Some((lsn, f))
+        })
+        .collect::<Vec<(u64, String)>>();
+
+    checkpoints.sort();
+    Ok(checkpoints)
+}
@@ -1,3 +1,9 @@
+pub mod aggregate;
+pub mod bson_stream;
 pub mod database;
+pub mod query;
+pub mod segment;
+pub mod transform;
+pub mod wal;
 
 // Re-export any other modules that might be needed for testing
@@ -1,13 +1,35 @@
-mod database;
-use crate::database::Database;
+use r2d2p2::database::{self, Database};
+use r2d2p2::wal::{self, CheckpointPolicy, SyncPolicy};
 use bson::spec::ElementType;
-use bson::Document;
+use bson::{doc, Document};
 use chrono::{DateTime, Local};
 use std::io::ErrorKind;
 use std::io::{BufRead, Read, Write};
-use std::net::{TcpListener, TcpStream};
+use std::net::TcpListener;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread;
+use std::time::Duration;
 use std::{fs, io};
 
+// How often the write-ahead log is fsynced. See wal::SyncPolicy.
+const WAL_SYNC_POLICY : SyncPolicy = SyncPolicy::Always;
+
+// When to write a checkpoint and truncate the write-ahead log. The policy is checked after each
+// request and every CHECKPOINT_CHECK_INTERVAL while idle, so an idle server still checkpoints.
+const CHECKPOINT_POLICY : CheckpointPolicy = CheckpointPolicy {
+    every_mutations: Some(100_000),
+    interval: Some(Duration::from_secs(5 * 60)),
+};
+
+const CHECKPOINT_CHECK_INTERVAL : Duration = Duration::from_secs(1);
+
+// Columns every inserted row must have a value for.
+const REQUIRED_COLUMNS : [&str; 0] = [];
+
+// Largest POST body (e.g. for BULK) the server will read. Bigger requests are refused with 413
+// before anything is allocated for them.
+const MAX_BODY_LEN : usize = 64 * 1024 * 1024;
+
 fn create_dir(path: &str) -> Result<(), io::Error> {
     match fs::create_dir_all(path) {
         Ok(_) => Ok(()),
@@ -17,21 +39,15 @@ fn create_dir(path: &str) -> Result<(), io::Error> {
 }
 
 
-fn handle_client(mut stream: TcpStream) {
-    println!("Connection from {}", stream.peer_addr().unwrap());
-    let buf = &mut [0; 512];
-
-    if let Ok(_) = stream.read(buf) {
-        println!("{:?}", buf.to_vec());
-    }
-}
-
-
-fn handle_request(_db: &mut Database, _req: String) -> Vec<u8> {
+fn handle_request(_db: &mut Database, _req: String, _body: String) -> Vec<u8> {
     // Query database and fetch result ////////////////////////
-    let result : Document = _db.query(_req.clone());
+    let result : Document = _db.query_with_body(_req.clone(), &_body);
     ///////////////////////////////////////////////////////////
 
+    render_response(_req, result)
+}
+
+fn render_response(_req: String, result: Document) -> Vec<u8> {
     let mut html : String = String::from(r#"
         <!DOCTYPE html>
         <html lang="en">
@@ -50,7 +66,8 @@ fn handle_request(_db: &mut Database, _req: String) -> Vec<u8> {
             <body>
                 <h1>"#);
 
-    html.push_str(_req.as_str());
+    // The request has been percent-decoded, so it may contain markup characters.
+    html.push_str(&_req.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;"));
     html.push_str( r#"</h1>
         <nav> Quick Actions :
             <a href="/LIST::ALL">List All</a> |
@@ -58,41 +75,38 @@ fn handle_request(_db: &mut Database, _req: String) -> Vec<u8> {
         </nav>
     </body>"#);
 
-    if _req.contains("::HIDE") {
-        html.push_str("Success");
-    }
-
-    else {
+    {
         html.push_str(r#"<table>"#);
+        let keyed_by_time = has_timestamp_rows(&result);
 
         // Header
-        if let header = result.get_array("labels").unwrap() {
-            let mut header_str : Vec<String> = Vec::from(
-                header.iter()
-                    .filter(|&s| s.element_type() == ElementType::String)
-                    .map(|s| {String::from(s.as_str().unwrap())})
-                    .collect::<Vec<String>>()
-            );
-
-            if (_req.as_str().contains("LIST") || _req.as_str().contains("INSERT"))
-                && !_req.as_str().contains("SAVED") {
+        {
+            let header = result.get_array("labels").unwrap();
+            let mut header_str : Vec<String> = header.iter()
+                .filter(|&s| s.element_type() == ElementType::String)
+                .map(|s| {String::from(s.as_str().unwrap())})
+                .collect::<Vec<String>>();
+
+            // Rows keyed by timestamp get that key as their first column.
+            if keyed_by_time {
                 header_str.insert(0, String::from("Timestamp"));
             }
 
             html.push_str(
-                &*vec_string_to_html_row(header_str, true)
+                &vec_string_to_html_row(header_str, true)
             );
         }
 
         // Contents
-        if let body = result.get_document("rows").unwrap() {
+        {
+            let body = result.get_document("rows").unwrap();
             for (label, content) in body.iter() {
                 match content.element_type() {
                     ElementType::EmbeddedDocument => {
                         html.push_str(
-                            &*document_to_html_row(
+                            &document_to_html_row(
                                 content.as_document().unwrap().clone(),
-                                label.parse::<u128>().unwrap_or(0)
+                                label.parse::<u128>().ok().filter(|_| keyed_by_time)
                             )
                         );
                     }
@@ -100,7 +114,7 @@ fn handle_request(_db: &mut Database, _req: String) -> Vec<u8> {
                     _ => {
                         html.push_str(format!(
                             "<tr><td>{}</td></tr>",
-                            content.to_string()
+                            content
                         ).as_str());
                     }
                 }
@@ -110,19 +124,91 @@ fn handle_request(_db: &mut Database, _req: String) -> Vec<u8> {
         }
 
         html.push_str( r#"</table>"#);
+
+        // Pages cut short by LIMIT link to the next one.
+        if let Ok(token) = result.get_str("next") {
+            html.push_str(&format!(
+                r#"<p><a href="/{}">Next page</a></p>"#,
+                percent_encode(&next_page_request(&_req, token))
+            ));
+        }
     }
 
     html.push_str(r#"</html>"#);
 
+    // Refused requests say which status to answer with.
+    let status = result.get_i32("status").unwrap_or(200);
+
     let mut header = String::new();
     header.push_str("HTTP/1.1 ");
-    header.push_str(&200.to_string());
-    header.push_str(" OK\r\n\r\n" );
+    header.push_str(&status.to_string());
+    header.push_str(&format!(" {}\r\n\r\n", status_reason(status)));
 
     let mut result:Vec<u8> = Vec::from(header.as_bytes());
     result = [result, Vec::from(html.as_bytes())].concat();
 
-    return result;
+    result
+}
+
+fn status_reason(status : i32) -> &'static str {
+    match status {
+        400 => "Bad Request",
+        409 => "Conflict",
+        413 => "Payload Too Large",
+        500 => "Internal Server Error",
+        _ => "OK",
+    }
+}
+
+fn has_timestamp_rows(result : &Document) -> bool {
+    result.get_document("rows").is_ok_and(|rows| {
+        rows.iter().any(|(label, content)| {
+            content.element_type() == ElementType::EmbeddedDocument && label.parse::<u128>().is_ok()
+        })
+    })
+}
+
+// The same query continuing after token, in place of any AFTER it already had.
+fn next_page_request(req : &str, token : &str) -> String {
+    let query = req.trim().trim_end_matches(';');
+    let query = match query.to_ascii_uppercase().rfind(" AFTER '") {
+        Some(i) => &query[..i],
+        None => query,
+    };
+    format!("{} AFTER '{}'", query, token)
+}
+
+fn percent_encode(s : &str) -> String {
+    s.bytes().map(|b| match b {
+        b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'*' | b',' | b':' | b'=' => (b as char).to_string(),
+        _ => format!("%{:02X}", b),
+    }).collect()
+}
+
+// Browsers send spaces, quotes and comparison operators in the path as %XX escapes.
+fn percent_decode(s : &str) -> String {
+    let bytes = s.as_bytes();
+    let mut decoded : Vec<u8> = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+
+    while i < bytes.len() {
+        let escaped = bytes.get(i + 1..i + 3)
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+
+        match (bytes[i], escaped) {
+            (b'%', Some(b)) => {
+                decoded.push(b);
+                i += 3;
+            }
+            (b, _) => {
+                decoded.push(b);
+                i += 1;
+            }
+        }
+    }
+
+    String::from_utf8_lossy(&decoded).to_string()
 }
 
 fn vec_string_to_html_row(v : Vec<String>, header : bool) -> String {
@@ -136,11 +222,14 @@ fn vec_string_to_html_row(v : Vec<String>, header : bool) -> String {
 
     html.push_str("</tr>");
 
-    return html;
+    html
 }
 
-fn document_to_html_row(doc : Document, time : u128) -> String {
-    let mut html = String::from(format!("<tr><td>{}</td>", time).as_str());
+fn document_to_html_row(doc : Document, time : Option<u128>) -> String {
+    let mut html = String::from("<tr>");
+    if let Some(time) = time {
+        html.push_str(format!("<td>{}</td>", time).as_str());
+    }
 
     for (_, content) in doc {
         // Don't want label! Ignore it
@@ -150,6 +239,7 @@ fn document_to_html_row(doc : Document, time : u128) -> String {
             ElementType::Int64 => content.as_i64().unwrap().to_string(),
             ElementType::String => content.as_str().unwrap().to_string(),
             ElementType::Boolean => content.as_bool().unwrap().to_string(),
+            ElementType::Null => String::from("null"),
             _ => String::from("None")
         };
 
@@ -158,7 +248,7 @@ fn document_to_html_row(doc : Document, time : u128) -> String {
 
     html.push_str("</tr>");
 
-    return html;
+    html
 }
 
 fn main() -> std::io::Result<()> {
@@ -180,6 +270,21 @@ fn main() -> std::io::Result<()> {
             String::from("number")]
     );
 
+    // Bring back everything that was logged before the last shutdown or crash. This has to
+    // finish before the listener below starts accepting connections. Without the log, writes
+    // would be acknowledged and then lost, so the server does not start.
+    match database.open_wal(String::from(wal::WAL_FILENAME), WAL_SYNC_POLICY) {
+        Ok(replayed) => println!("Replayed {} write-ahead log records.", replayed),
+        Err(e) => {
+            eprintln!("Error opening write-ahead log: {}", e);
+            return Err(e);
+        }
+    }
+    database.set_checkpoint_policy(CHECKPOINT_POLICY);
+    if let Err(e) = database.set_required_columns(REQUIRED_COLUMNS.iter().map(|c| c.to_string()).collect()) {
+        eprintln!("Error setting required columns: {}", e);
+    }
+
     println!("\n\n==========================================================");
     println!("Welcome to R2D2!");
     let current_local: DateTime<Local> = Local::now();
@@ -191,8 +296,27 @@ fn main() -> std::io::Result<()> {
     let listener = TcpListener::bind("127.0.0.1:6969")?;
     println!(">> Listening for requests at http://127.0.0.1:6969/...");
 
-    // accept connections and process them serially
-    for mut stream in listener.incoming().flatten() {
+    // Connections are accepted on their own thread and handed over here, so that waiting for the
+    // next one can time out to check whether a checkpoint is due.
+    let (connections, incoming) = mpsc::channel();
+    thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            if connections.send(stream).is_err() {
+                break;
+            }
+        }
+    });
+
+    // process connections serially
+    loop {
+        let mut stream = match incoming.recv_timeout(CHECKPOINT_CHECK_INTERVAL) {
+            Ok(stream) => stream,
+            Err(RecvTimeoutError::Timeout) => {
+                database.maybe_checkpoint();
+                continue;
+            }
+            Err(RecvTimeoutError::Disconnected) => break,
+        };
         // Inside this loop, someone has connected.
 
         // You can kind of think of this line of code as if it were
@@ -205,25 +329,56 @@ fn main() -> std::io::Result<()> {
         // an empty line, we break out of the loop.
         let mut i = 0;
         let mut requested_resource: String = String::new();
+        let mut content_length: usize = 0;
 
         loop {
             let mut l = String::new();
             rdr.read_line(&mut l).unwrap();
             if l.trim().is_empty() { break; }
 
+            // POST requests (e.g. BULK) say how much body follows the headers.
+            if let Some((name, value)) = l.split_once(':') {
+                if name.trim().eq_ignore_ascii_case("content-length") {
+                    content_length = value.trim().parse::<usize>().unwrap_or(0);
+                }
+            }
+
             if i == 0 {
                 i = 1;
-                requested_resource = l
-                    .split(" ").collect::<Vec<&str>>()[1].to_string()
-                    .split("/").collect::<Vec<&str>>()[1].to_string();
+                requested_resource = percent_decode(l
+                    .split(" ").collect::<Vec<&str>>()[1]
+                    .split("/").collect::<Vec<&str>>()[1]);
                 if !requested_resource.contains("HIDE") {
                     println!("REQUESTED RESOURCE: {}", requested_resource);
                 }
             }
         }
 
-        let response = handle_request(&mut database, requested_resource);
-        stream.write(&response).expect("TODO: panic message");
+        let response = if content_length > MAX_BODY_LEN {
+            render_response(requested_resource, doc![
+                "labels" : ["Notice"],
+                "rows" : doc![
+                    "Notice" : format!("Request body of {} bytes is larger than the limit of {} bytes", content_length, MAX_BODY_LEN),
+                ],
+                "status" : 413,
+            ])
+        } else {
+            // A body cut short is refused whole, rather than running the request on part of it.
+            let mut body = vec![0u8; content_length];
+            match rdr.read_exact(&mut body) {
+                Ok(()) => handle_request(&mut database, requested_resource, String::from_utf8_lossy(&body).to_string()),
+                Err(_) => render_response(requested_resource, doc![
+                    "labels" : ["Notice"],
+                    "rows" : doc![
+                        "Notice" : format!("Request body was shorter than its Content-Length of {} bytes", content_length),
+                    ],
+                    "status" : 400,
+                ]),
+            }
+        };
+        stream.write_all(&response).expect("TODO: panic message");
+
+        database.maybe_checkpoint();
 
     }
 
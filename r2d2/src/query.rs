@@ -0,0 +1,1364 @@
+use bson::{Bson, Document};
+use chrono::NaiveDateTime;
+use std::cmp::Ordering;
+use std::fmt;
+
+use crate::aggregate::{AggregateFunction, Fill};
+use crate::database::{bson_to_f64, DuplicatePolicy};
+use crate::transform::{Argument, TransformFunction};
+
+// Queries come in two syntaxes that parse to the same AST:
+//
+//   SELECT * RANGE 1733697225000, 1733697226000
+//   LIST::RANGE::1733697225000,1733697226000
+//
+// The first is the query language proper; the second is the original '::' syntax, kept as a
+// front-end so existing clients and bookmarks keep working. See src/queries for both grammars.
+
+// Operations understood by the '::' front-end. A query is only treated as '::' syntax when it
+// starts with one of these directly followed by '::', or is BULK on its own.
+const LEGACY_OPERATIONS : [&str; 9] = ["LIST", "AGGREGATE", "INSERT", "REMOVE", "TIME", "SAVE", "LOAD", "BULK", "SET"];
+
+// Multi-character symbols must come before their prefixes.
+const SYMBOLS : [&str; 12] = ["<=", ">=", "!=", "<>", "<", ">", "*", "(", ")", ",", "=", ";"];
+
+const DATETIME_FORMAT : &str = "%Y-%m-%d %H:%M:%S";
+
+const DURATION_UNITS : [(&str, u128); 6] = [
+    ("ms", 1),
+    ("s", 1000),
+    ("m", 60 * 1000),
+    ("h", 60 * 60 * 1000),
+    ("d", 24 * 60 * 60 * 1000),
+    ("w", 7 * 24 * 60 * 60 * 1000),
+];
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Query {
+    pub statement: Statement,
+    // Only report success instead of returning rows (the '::HIDE' suffix), for benchmarking.
+    pub hide: bool,
+}
+
+// Only one of these exists per query, so the size of the Select variant doesn't matter.
+#[allow(clippy::large_enum_variant)]
+#[derive(Clone, Debug, PartialEq)]
+pub enum Statement {
+    Select(Select),
+    // With overwrite (UPSERT), a row already at the timestamp is replaced rather than handled by
+    // the database's duplicate policy.
+    Insert { values: Vec<(String, Literal)>, at: Option<u128>, overwrite: bool },
+    // Rows come from the request body, in the given format.
+    InsertBatch { format: BatchFormat },
+    Update { values: Vec<(String, Literal)>, at: u128 },
+    Delete(Delete),
+    Latest(Latest),
+    ShowMetadata,
+    ShowSaved,
+    SetDuplicatePolicy { policy: DuplicatePolicy },
+    Time { timestamp: i64 },
+    Save { filename: String },
+    SaveCsv,
+    Load { filename: String },
+    LoadSchema { filename: String },
+    Bulk,
+}
+
+// How the rows of an INSERT BATCH body are written.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum BatchFormat {
+    // One JSON object per line.
+    #[default]
+    Ndjson,
+    // A header line naming the columns, then one row per line.
+    Csv,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Select {
+    pub projection: Projection,
+    pub at: Option<u128>,
+    // How the row for at is found, and how far from at it may be (for anything but Exact).
+    pub as_of: AsOf,
+    pub tolerance: Option<u128>,
+    // Inclusive bounds, lower first.
+    pub range: Option<(u128, u128)>,
+    pub filter: Option<Predicate>,
+    // For aggregates: one result per time bucket and/or per distinct combination of values in
+    // the group_by columns, instead of one overall.
+    pub bucket: Option<TimeBucket>,
+    pub group_by: Vec<String>,
+    // With a time bucket: how buckets without rows are filled in, or None to leave them out.
+    pub fill: Option<Fill>,
+    // For SELECT *: the order rows are listed in, and which of them.
+    pub page: Page,
+}
+
+// Sensors rarely write at the exact millisecond asked about, so a lookup can also take the
+// closest row on either side of it.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum AsOf {
+    #[default]
+    Exact,
+    AtOrBefore,
+    AtOrAfter,
+    // The closer of the two, the earlier one on a tie.
+    Nearest,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Order {
+    #[default]
+    Ascending,
+    Descending,
+}
+
+// The rows after the first offset, at most limit of them, continuing after the timestamp of
+// the last row of the previous page (in the direction of order) when given one.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Page {
+    pub order: Order,
+    pub limit: Option<usize>,
+    pub offset: usize,
+    pub after: Option<u128>,
+}
+
+// The token handed out with a page for fetching the next one. Clients pass it back as it is
+// in AFTER '<token>'; it records the last timestamp listed and which way the listing goes.
+pub fn continuation_token(last: u128, order: Order) -> String {
+    let direction = match order {
+        Order::Ascending => 'a',
+        Order::Descending => 'd',
+    };
+    format!("{}{:x}", direction, last)
+}
+
+pub fn parse_continuation_token(token: &str, order: Order) -> Result<u128, String> {
+    let invalid = || format!("'{}' is not a continuation token", token);
+    let (direction, last) = match token.char_indices().nth(1) {
+        Some((i, _)) => token.split_at(i),
+        None => return Err(invalid()),
+    };
+    let last = u128::from_str_radix(last, 16).map_err(|_| invalid())?;
+
+    match (direction, order) {
+        ("a", Order::Ascending) | ("d", Order::Descending) => Ok(last),
+        ("a", Order::Descending) => Err(String::from("continuation token is for ORDER BY time ASC")),
+        ("d", Order::Ascending) => Err(String::from("continuation token is for ORDER BY time DESC")),
+        _ => Err(invalid()),
+    }
+}
+
+// Buckets are [origin + k * width, origin + (k + 1) * width) for every whole k.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TimeBucket {
+    pub width: u128,
+    pub origin: u128,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub enum Projection {
+    #[default]
+    All,
+    // Only these columns of each row, in this order, e.g. store, number_sold
+    Columns(Vec<String>),
+    // One or more, all computed in the same scan, e.g.
+    // MIN(number_sold), MAX(number_sold), COUNT(DISTINCT store), PERCENTILE(price, 95)
+    Aggregates(Vec<Aggregate>),
+    // A value per row, or per time bucket, e.g. RATE(requests, 1s)
+    Transform { function: TransformFunction, field: String },
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Aggregate {
+    pub function: AggregateFunction,
+    pub field: String,
+}
+
+impl Aggregate {
+    pub fn new(function: AggregateFunction, field: &str) -> Aggregate {
+        Aggregate { function, field: field.to_string() }
+    }
+
+    pub fn label(&self) -> String {
+        self.function.label(&self.field)
+    }
+
+    // The row's value for this aggregate, if it has one the function can use. Only COUNT and
+    // COUNT DISTINCT take values that are not numbers.
+    pub fn value<'a>(&self, row: &'a Document) -> Option<&'a Bson> {
+        row.get(&self.field).filter(|v| self.function.is_count() || bson_to_f64(v).is_some())
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Delete {
+    All,
+    At(u128),
+    // Rows in the range (every row without one) that the filter matches (every row without one).
+    Where { range: Option<(u128, u128)>, filter: Option<Predicate> },
+}
+
+// The newest count rows from before a time (now, if not given), or the newest count rows for
+// each value of the by column.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Latest {
+    pub count: usize,
+    pub before: Option<u128>,
+    pub filter: Option<Predicate>,
+    pub by: Option<String>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Literal {
+    Number(f64),
+    Str(String),
+    Bool(bool),
+}
+
+impl fmt::Display for Literal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Literal::Number(n) => write!(f, "number {}", n),
+            Literal::Str(s) => write!(f, "string '{}'", s),
+            Literal::Bool(b) => write!(f, "boolean {}", b),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CompareOp {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+impl CompareOp {
+    fn holds(self, ordering: Ordering) -> bool {
+        match self {
+            CompareOp::Eq => ordering == Ordering::Equal,
+            CompareOp::Ne => ordering != Ordering::Equal,
+            CompareOp::Lt => ordering == Ordering::Less,
+            CompareOp::Le => ordering != Ordering::Greater,
+            CompareOp::Gt => ordering == Ordering::Greater,
+            CompareOp::Ge => ordering != Ordering::Less,
+        }
+    }
+}
+
+// A WHERE clause. NOT IN and NOT BETWEEN parse to Not(In) and Not(Between).
+#[derive(Clone, Debug, PartialEq)]
+pub enum Predicate {
+    Compare { column: String, op: CompareOp, value: Literal },
+    In { column: String, values: Vec<Literal> },
+    Between { column: String, low: Literal, high: Literal },
+    And(Box<Predicate>, Box<Predicate>),
+    Or(Box<Predicate>, Box<Predicate>),
+    Not(Box<Predicate>),
+}
+
+impl Predicate {
+    // Makes sure every column exists and every value has that column's type, so a typo is
+    // reported instead of quietly matching nothing.
+    pub fn check(&self, schema: &Document) -> Result<(), String> {
+        let column_type = |column: &str| {
+            schema.get_str(column).map_err(|_| format!("column '{}' is not in the schema", column))
+        };
+
+        let fits = |column: &str, column_type: &str, value: &Literal| {
+            let ok = matches!(
+                (column_type, value),
+                ("number", Literal::Number(_)) | ("boolean", Literal::Bool(_)) | ("string", Literal::Str(_))
+            );
+            if ok {
+                Ok(())
+            } else {
+                Err(format!("column '{}' is a {} column, but was compared with {}", column, column_type, value))
+            }
+        };
+
+        match self {
+            Predicate::Compare { column, op, value } => {
+                let column_type = column_type(column)?;
+                fits(column, column_type, value)?;
+                if column_type == "boolean" && !matches!(op, CompareOp::Eq | CompareOp::Ne) {
+                    return Err(format!("column '{}' is boolean and can only be compared with = or !=", column));
+                }
+                Ok(())
+            }
+            Predicate::In { column, values } => {
+                let column_type = column_type(column)?;
+                values.iter().try_for_each(|v| fits(column, column_type, v))
+            }
+            Predicate::Between { column, low, high } => {
+                let column_type = column_type(column)?;
+                if column_type == "boolean" {
+                    return Err(format!("column '{}' is boolean and cannot be used with BETWEEN", column));
+                }
+                fits(column, column_type, low)?;
+                fits(column, column_type, high)
+            }
+            Predicate::And(a, b) | Predicate::Or(a, b) => {
+                a.check(schema)?;
+                b.check(schema)
+            }
+            Predicate::Not(p) => p.check(schema),
+        }
+    }
+
+    pub fn matches(&self, row: &Document) -> bool {
+        self.evaluate(row) == Some(true)
+    }
+
+    // None means unknown, as when the row has no value for a column; like SQL's NULL, unknown
+    // is never a match, and NOT unknown is still unknown.
+    fn evaluate(&self, row: &Document) -> Option<bool> {
+        match self {
+            Predicate::Compare { column, op, value } => compare(row.get(column)?, value).map(|o| op.holds(o)),
+            Predicate::In { column, values } => {
+                let stored = row.get(column)?;
+                let mut result = Some(false);
+                for value in values {
+                    match compare(stored, value) {
+                        Some(Ordering::Equal) => return Some(true),
+                        None => result = None,
+                        _ => {}
+                    }
+                }
+                result
+            }
+            Predicate::Between { column, low, high } => {
+                let stored = row.get(column)?;
+                Some(compare(stored, low)? != Ordering::Less && compare(stored, high)? != Ordering::Greater)
+            }
+            Predicate::And(a, b) => match (a.evaluate(row), b.evaluate(row)) {
+                (Some(false), _) | (_, Some(false)) => Some(false),
+                (Some(true), Some(true)) => Some(true),
+                _ => None,
+            },
+            Predicate::Or(a, b) => match (a.evaluate(row), b.evaluate(row)) {
+                (Some(true), _) | (_, Some(true)) => Some(true),
+                (Some(false), Some(false)) => Some(false),
+                _ => None,
+            },
+            Predicate::Not(p) => p.evaluate(row).map(|b| !b),
+        }
+    }
+}
+
+fn compare(stored: &Bson, value: &Literal) -> Option<Ordering> {
+    match value {
+        Literal::Number(n) => bson_to_f64(stored)?.partial_cmp(n),
+        Literal::Str(s) => stored.as_str().map(|v| v.cmp(s.as_str())),
+        Literal::Bool(b) => stored.as_bool().map(|v| v.cmp(b)),
+    }
+}
+
+// Column is 1-based and counts characters of the query string as received.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ParseError {
+    pub message: String,
+    pub column: usize,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "syntax error at column {}: {}", self.column, self.message)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+fn error<T>(message: String, column: usize) -> Result<T, ParseError> {
+    Err(ParseError { message, column })
+}
+
+pub fn parse(input: &str) -> Result<Query, ParseError> {
+    if is_legacy(input) {
+        parse_legacy(input)
+    } else {
+        let tokens = tokenize(input)?;
+        Parser { tokens, pos: 0 }.query()
+    }
+}
+
+fn is_legacy(input: &str) -> bool {
+    let operation = match input.split_once("::") {
+        Some((operation, _)) => operation,
+        None => input,
+    };
+    LEGACY_OPERATIONS.contains(&operation) && (input.contains("::") || operation == "BULK")
+}
+
+// 'YYYY-MM-DD HH:MM:SS' in UTC to milliseconds since the epoch.
+pub fn parse_datetime(text: &str) -> Option<i64> {
+    NaiveDateTime::parse_from_str(text, DATETIME_FORMAT).ok().map(|dt| dt.and_utc().timestamp() * 1000)
+}
+
+///////////////////////////////////////////////////////////////////////////////
+// Tokenizer
+///////////////////////////////////////////////////////////////////////////////
+
+#[derive(Clone, Debug, PartialEq)]
+enum TokenKind {
+    // Keywords are words too; the parser decides by position, so columns can share their names.
+    Word(String),
+    // "double quoted", for column names that are not plain words.
+    QuotedIdent(String),
+    // 'single quoted'
+    Str(String),
+    Number(String),
+    Symbol(&'static str),
+    End,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct Token {
+    kind: TokenKind,
+    column: usize,
+}
+
+impl Token {
+    fn describe(&self) -> String {
+        match &self.kind {
+            TokenKind::Word(w) => format!("'{}'", w),
+            TokenKind::QuotedIdent(w) => format!("\"{}\"", w),
+            TokenKind::Str(s) => format!("string '{}'", s),
+            TokenKind::Number(n) => format!("number {}", n),
+            TokenKind::Symbol(s) => format!("'{}'", s),
+            TokenKind::End => String::from("end of query"),
+        }
+    }
+}
+
+fn tokenize(input: &str) -> Result<Vec<Token>, ParseError> {
+    let chars = input.chars().collect::<Vec<char>>();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+
+    while i < chars.len() {
+        let c = chars[i];
+        let column = i + 1;
+
+        if c.is_whitespace() {
+            i += 1;
+        } else if c.is_alphabetic() || c == '_' {
+            let start = i;
+            while i < chars.len() && (chars[i].is_alphanumeric() || chars[i] == '_') {
+                i += 1;
+            }
+            tokens.push(Token { kind: TokenKind::Word(chars[start..i].iter().collect()), column });
+        } else if c.is_ascii_digit() || (c == '-' && chars.get(i + 1).is_some_and(|d| d.is_ascii_digit())) {
+            let start = i;
+            i += 1;
+            while i < chars.len() && (chars[i].is_ascii_digit() || chars[i] == '.') {
+                i += 1;
+            }
+            tokens.push(Token { kind: TokenKind::Number(chars[start..i].iter().collect()), column });
+        } else if c == '\'' || c == '"' {
+            // A doubled quote inside the quotes stands for the quote itself.
+            let mut text = String::new();
+            i += 1;
+            loop {
+                match chars.get(i) {
+                    None => return error(String::from("unterminated quote"), column),
+                    Some(&q) if q == c && chars.get(i + 1) == Some(&c) => {
+                        text.push(c);
+                        i += 2;
+                    }
+                    Some(&q) if q == c => {
+                        i += 1;
+                        break;
+                    }
+                    Some(&other) => {
+                        text.push(other);
+                        i += 1;
+                    }
+                }
+            }
+            let kind = if c == '\'' { TokenKind::Str(text) } else { TokenKind::QuotedIdent(text) };
+            tokens.push(Token { kind, column });
+        } else {
+            let rest = chars[i..].iter().collect::<String>();
+            match SYMBOLS.iter().find(|s| rest.starts_with(**s)) {
+                Some(symbol) => {
+                    tokens.push(Token { kind: TokenKind::Symbol(symbol), column });
+                    i += symbol.chars().count();
+                }
+                None => return error(format!("unexpected character '{}'", c), column),
+            }
+        }
+    }
+
+    tokens.push(Token { kind: TokenKind::End, column: chars.len() + 1 });
+    Ok(tokens)
+}
+
+///////////////////////////////////////////////////////////////////////////////
+// Parser
+///////////////////////////////////////////////////////////////////////////////
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> &Token {
+        &self.tokens[self.pos]
+    }
+
+    fn advance(&mut self) -> Token {
+        let token = self.tokens[self.pos].clone();
+        if token.kind != TokenKind::End {
+            self.pos += 1;
+        }
+        token
+    }
+
+    fn unexpected<T>(&self, expected: &str) -> Result<T, ParseError> {
+        let token = self.peek();
+        error(format!("expected {}, found {}", expected, token.describe()), token.column)
+    }
+
+    fn is_keyword(&self, keyword: &str) -> bool {
+        matches!(&self.peek().kind, TokenKind::Word(w) if w.eq_ignore_ascii_case(keyword))
+    }
+
+    fn eat_keyword(&mut self, keyword: &str) -> bool {
+        let found = self.is_keyword(keyword);
+        if found {
+            self.advance();
+        }
+        found
+    }
+
+    fn expect_keyword(&mut self, keyword: &str) -> Result<(), ParseError> {
+        if self.eat_keyword(keyword) {
+            Ok(())
+        } else {
+            self.unexpected(keyword)
+        }
+    }
+
+    fn eat_symbol(&mut self, symbol: &str) -> bool {
+        let found = matches!(&self.peek().kind, TokenKind::Symbol(s) if *s == symbol);
+        if found {
+            self.advance();
+        }
+        found
+    }
+
+    fn expect_symbol(&mut self, symbol: &str) -> Result<(), ParseError> {
+        if self.eat_symbol(symbol) {
+            Ok(())
+        } else {
+            self.unexpected(&format!("'{}'", symbol))
+        }
+    }
+
+    fn identifier(&mut self) -> Result<String, ParseError> {
+        match self.peek().kind.clone() {
+            TokenKind::Word(w) | TokenKind::QuotedIdent(w) => {
+                self.advance();
+                Ok(w)
+            }
+            _ => self.unexpected("a column name"),
+        }
+    }
+
+    // A word or string, e.g. a file name.
+    fn name(&mut self, what: &str) -> Result<String, ParseError> {
+        match self.peek().kind.clone() {
+            TokenKind::Word(w) | TokenKind::Str(w) => {
+                self.advance();
+                Ok(w)
+            }
+            _ => self.unexpected(what),
+        }
+    }
+
+    // Milliseconds since the epoch, or a quoted 'YYYY-MM-DD HH:MM:SS' in UTC.
+    fn timestamp(&mut self) -> Result<u128, ParseError> {
+        let token = self.peek().clone();
+        let parsed = match &token.kind {
+            TokenKind::Number(n) => n.parse::<u128>().ok(),
+            TokenKind::Str(s) => parse_datetime(s).and_then(|t| u128::try_from(t).ok()),
+            _ => return self.unexpected("a timestamp"),
+        };
+
+        match parsed {
+            Some(t) => {
+                self.advance();
+                Ok(t)
+            }
+            None => error(format!("{} is not a valid timestamp", token.describe()), token.column),
+        }
+    }
+
+    fn literal(&mut self) -> Result<Literal, ParseError> {
+        let token = self.peek().clone();
+        let literal = match &token.kind {
+            TokenKind::Number(n) => match n.parse::<f64>() {
+                Ok(v) => Literal::Number(v),
+                Err(_) => return error(format!("{} is not a valid number", token.describe()), token.column),
+            },
+            TokenKind::Str(s) => Literal::Str(s.clone()),
+            TokenKind::Word(w) if w.eq_ignore_ascii_case("true") => Literal::Bool(true),
+            TokenKind::Word(w) if w.eq_ignore_ascii_case("false") => Literal::Bool(false),
+            _ => return self.unexpected("a value"),
+        };
+
+        self.advance();
+        Ok(literal)
+    }
+
+    fn query(&mut self) -> Result<Query, ParseError> {
+        let statement = self.statement()?;
+        self.eat_symbol(";");
+
+        if self.peek().kind != TokenKind::End {
+            return self.unexpected("end of query");
+        }
+
+        Ok(Query { statement, hide: false })
+    }
+
+    fn statement(&mut self) -> Result<Statement, ParseError> {
+        let token = self.advance();
+        let keyword = match &token.kind {
+            TokenKind::Word(w) => w.to_ascii_uppercase(),
+            _ => return error(format!("expected a statement, found {}", token.describe()), token.column),
+        };
+
+        match keyword.as_str() {
+            "SELECT" => self.select(),
+            "INSERT" => self.insert(false),
+            "UPSERT" => self.insert(true),
+            "UPDATE" => {
+                let values = self.assignments()?;
+                self.expect_keyword("AT")?;
+                Ok(Statement::Update { values, at: self.timestamp()? })
+            }
+            "DELETE" => self.delete(),
+            "LATEST" => self.latest(),
+            "SHOW" => {
+                if self.eat_keyword("METADATA") {
+                    Ok(Statement::ShowMetadata)
+                } else if self.eat_keyword("SAVED") {
+                    Ok(Statement::ShowSaved)
+                } else {
+                    self.unexpected("METADATA or SAVED")
+                }
+            }
+            "SET" => {
+                self.expect_keyword("DUPLICATES")?;
+                let token = self.peek().clone();
+                let mut name = self.name("a duplicate policy")?;
+                // KEEP FIRST is the one policy name with a space in it
+                if name.eq_ignore_ascii_case("KEEP") && self.eat_keyword("FIRST") {
+                    name.push_str(" FIRST");
+                }
+                match DuplicatePolicy::from_name(&name) {
+                    Ok(policy) => Ok(Statement::SetDuplicatePolicy { policy }),
+                    Err(e) => error(e, token.column),
+                }
+            }
+            "TIME" => {
+                let token = self.peek().clone();
+                match &token.kind {
+                    TokenKind::Str(s) => match parse_datetime(s) {
+                        Some(timestamp) => {
+                            self.advance();
+                            Ok(Statement::Time { timestamp })
+                        }
+                        None => error(String::from("invalid date, use 'YYYY-MM-DD HH:MM:SS'"), token.column),
+                    },
+                    _ => self.unexpected("a quoted 'YYYY-MM-DD HH:MM:SS'"),
+                }
+            }
+            "SAVE" => {
+                if self.eat_keyword("CSV") {
+                    Ok(Statement::SaveCsv)
+                } else {
+                    Ok(Statement::Save { filename: self.name("a file name")? })
+                }
+            }
+            "LOAD" => {
+                if self.eat_keyword("SCHEMA") {
+                    Ok(Statement::LoadSchema { filename: self.name("a schema file name")? })
+                } else {
+                    Ok(Statement::Load { filename: self.name("a file name")? })
+                }
+            }
+            "BULK" => Ok(Statement::Bulk),
+            _ => error(format!("unknown statement {}", token.describe()), token.column),
+        }
+    }
+
+    // SELECT (* | column {, column}) [lookup | RANGE start, end] [WHERE predicate]
+    //     [ORDER BY time [ASC | DESC]] [LIMIT n] [OFFSET n] [AFTER 'token']
+    // where lookup is AT t, or (AT OR BEFORE | AT OR AFTER | NEAREST) t [WITHIN duration]
+    // SELECT aggregate {, aggregate} [RANGE start, end] [WHERE predicate]
+    //     [GROUP BY group {, group} [FILL(fill)]]
+    // where each group is a column or time(width [, origin])
+    // SELECT transform [RANGE start, end] [WHERE predicate] [GROUP BY time(width [, origin])]
+    fn select(&mut self) -> Result<Statement, ParseError> {
+        let is_call = self.tokens.get(self.pos + 1).is_some_and(|t| t.kind == TokenKind::Symbol("("));
+        let projection = if self.eat_symbol("*") {
+            Projection::All
+        } else if !is_call {
+            let mut columns = vec![self.identifier()?];
+            while self.eat_symbol(",") {
+                columns.push(self.identifier()?);
+            }
+            Projection::Columns(columns)
+        } else if matches!(&self.peek().kind, TokenKind::Word(w) if TransformFunction::is_transform(w)) {
+            self.transform()?
+        } else {
+            let mut aggregates = vec![self.aggregate()?];
+            while self.eat_symbol(",") {
+                aggregates.push(self.aggregate()?);
+            }
+            Projection::Aggregates(aggregates)
+        };
+
+        let mut select = Select { projection, ..Select::default() };
+        let is_aggregate = matches!(select.projection, Projection::Aggregates(_) | Projection::Transform { .. });
+
+        if self.is_keyword("AT") || self.is_keyword("NEAREST") {
+            if is_aggregate {
+                return self.unexpected("end of query (aggregates cannot be used with AT or NEAREST)");
+            }
+            select.as_of = if self.eat_keyword("NEAREST") {
+                AsOf::Nearest
+            } else {
+                self.advance();
+                if !self.eat_keyword("OR") {
+                    AsOf::Exact
+                } else if self.eat_keyword("BEFORE") {
+                    AsOf::AtOrBefore
+                } else if self.eat_keyword("AFTER") {
+                    AsOf::AtOrAfter
+                } else {
+                    return self.unexpected("BEFORE or AFTER");
+                }
+            };
+            select.at = Some(self.timestamp()?);
+
+            if select.as_of != AsOf::Exact && self.eat_keyword("WITHIN") {
+                select.tolerance = Some(self.duration()?);
+            }
+        } else if self.eat_keyword("RANGE") {
+            let start = self.timestamp()?;
+            self.expect_symbol(",")?;
+            let end = self.timestamp()?;
+            select.range = Some((start.min(end), start.max(end)));
+        }
+
+        if self.eat_keyword("WHERE") {
+            select.filter = Some(self.predicate()?);
+        }
+
+        if self.is_keyword("GROUP") {
+            if !is_aggregate {
+                return self.unexpected("end of query (GROUP BY needs an aggregate such as AVG(column))");
+            }
+            self.advance();
+            self.expect_keyword("BY")?;
+
+            loop {
+                let is_time = self.is_keyword("time")
+                    && self.tokens.get(self.pos + 1).is_some_and(|t| t.kind == TokenKind::Symbol("("));
+
+                if !is_time && matches!(select.projection, Projection::Transform { .. }) {
+                    return self.unexpected("time(...) (only aggregates can be grouped by columns)");
+                } else if !is_time {
+                    select.group_by.push(self.identifier()?);
+                } else if matches!(select.projection, Projection::Transform { function, .. } if !function.is_change()) {
+                    return self.unexpected("end of query (only DIFFERENCE, DERIVATIVE and RATE can be grouped by time)");
+                } else if select.bucket.is_none() {
+                    select.bucket = Some(self.time_bucket()?);
+                } else {
+                    return self.unexpected("a column (only one time(...) per GROUP BY)");
+                }
+
+                if !self.eat_symbol(",") {
+                    break;
+                }
+            }
+
+            if self.is_keyword("FILL") {
+                if select.bucket.is_none() || !matches!(select.projection, Projection::Aggregates(_)) {
+                    return self.unexpected("end of query (FILL needs an aggregate grouped by time(...))");
+                }
+                self.advance();
+                select.fill = self.fill()?;
+            }
+        }
+
+        if ["ORDER", "LIMIT", "OFFSET", "AFTER"].iter().any(|k| self.is_keyword(k)) {
+            if is_aggregate || select.at.is_some() {
+                return self.unexpected("end of query (ORDER BY, LIMIT, OFFSET and AFTER are for listing rows without AT)");
+            }
+            select.page = self.page()?;
+        }
+
+        Ok(Statement::Select(select))
+    }
+
+    // [ORDER BY time [ASC | DESC]] [LIMIT n] [OFFSET n] [AFTER 'token']
+    fn page(&mut self) -> Result<Page, ParseError> {
+        let mut page = Page::default();
+
+        if self.eat_keyword("ORDER") {
+            self.expect_keyword("BY")?;
+            self.expect_keyword("time")?;
+            if self.eat_keyword("DESC") {
+                page.order = Order::Descending;
+            } else {
+                self.eat_keyword("ASC");
+            }
+        }
+        if self.eat_keyword("LIMIT") {
+            page.limit = Some(self.row_count()?);
+        }
+        if self.eat_keyword("OFFSET") {
+            page.offset = self.row_count()?;
+        }
+        if self.eat_keyword("AFTER") {
+            let token = self.peek().clone();
+            let last = match &token.kind {
+                TokenKind::Str(s) => parse_continuation_token(s, page.order),
+                _ => return self.unexpected("a continuation token in quotes"),
+            };
+            match last {
+                Ok(last) => page.after = Some(last),
+                Err(e) => return error(e, token.column),
+            }
+            self.advance();
+        }
+
+        Ok(page)
+    }
+
+    fn row_count(&mut self) -> Result<usize, ParseError> {
+        let token = self.peek().clone();
+        match &token.kind {
+            TokenKind::Number(n) => match n.parse::<usize>() {
+                Ok(count) => {
+                    self.advance();
+                    Ok(count)
+                }
+                Err(_) => error(format!("{} is not a valid number of rows", token.describe()), token.column),
+            },
+            _ => self.unexpected("a number of rows"),
+        }
+    }
+
+    // name(column) | COUNT(DISTINCT column) | name(column, number)
+    fn aggregate(&mut self) -> Result<Aggregate, ParseError> {
+        let column = self.peek().column;
+        let name = self.identifier()?;
+        self.expect_symbol("(")?;
+
+        // A column may itself be called distinct, as in COUNT(distinct).
+        let distinct = self.is_keyword("DISTINCT")
+            && !matches!(self.tokens[self.pos + 1].kind, TokenKind::Symbol(")"));
+        if distinct {
+            self.advance();
+        }
+        let field = self.identifier()?;
+
+        let argument = if self.eat_symbol(",") {
+            match self.literal()? {
+                Literal::Number(n) => Some(n),
+                _ => return error(String::from("expected a number"), self.tokens[self.pos - 1].column),
+            }
+        } else {
+            None
+        };
+        self.expect_symbol(")")?;
+
+        match AggregateFunction::from_parts(&name, distinct, argument) {
+            Ok(function) => Ok(Aggregate { function, field }),
+            Err(e) => error(e, column),
+        }
+    }
+
+    // name(column [, number | duration])
+    fn transform(&mut self) -> Result<Projection, ParseError> {
+        let column = self.peek().column;
+        let name = self.identifier()?;
+        self.expect_symbol("(")?;
+        let field = self.identifier()?;
+
+        let argument = if !self.eat_symbol(",") {
+            None
+        } else if self.tokens.get(self.pos + 1).is_some_and(|t| matches!(&t.kind,
+            TokenKind::Word(w) if DURATION_UNITS.iter().any(|(unit, _)| w.eq_ignore_ascii_case(unit)))) {
+            Some(Argument::Duration(self.duration()?))
+        } else {
+            match self.literal()? {
+                Literal::Number(n) => Some(Argument::Number(n)),
+                _ => return error(String::from("expected a number or a duration"), self.tokens[self.pos - 1].column),
+            }
+        };
+        self.expect_symbol(")")?;
+
+        match TransformFunction::from_parts(&name, argument) {
+            Ok(function) => Ok(Projection::Transform { function, field }),
+            Err(e) => error(e, column),
+        }
+    }
+
+    // (NULL | NONE | PREVIOUS | LINEAR | number), where NONE leaves the gaps
+    fn fill(&mut self) -> Result<Option<Fill>, ParseError> {
+        self.expect_symbol("(")?;
+        let fill = if self.eat_keyword("NULL") {
+            Some(Fill::Null)
+        } else if self.eat_keyword("NONE") {
+            None
+        } else if self.eat_keyword("PREVIOUS") {
+            Some(Fill::Previous)
+        } else if self.eat_keyword("LINEAR") {
+            Some(Fill::Linear)
+        } else if let TokenKind::Number(_) = self.peek().kind {
+            match self.literal()? {
+                Literal::Number(n) => Some(Fill::Value(n)),
+                _ => return self.unexpected("a number"),
+            }
+        } else {
+            return self.unexpected("NULL, NONE, PREVIOUS, LINEAR or a number");
+        };
+        self.expect_symbol(")")?;
+        Ok(fill)
+    }
+
+    // time(width [, origin])
+    fn time_bucket(&mut self) -> Result<TimeBucket, ParseError> {
+        self.expect_keyword("time")?;
+        self.expect_symbol("(")?;
+        let width = self.duration()?;
+        let origin = if self.eat_symbol(",") { self.timestamp()? } else { 0 };
+        self.expect_symbol(")")?;
+
+        Ok(TimeBucket { width, origin })
+    }
+
+    // A positive whole number of milliseconds, optionally followed by a unit: 500ms, 30s, 5m,
+    // 2h, 1d or 1w.
+    fn duration(&mut self) -> Result<u128, ParseError> {
+        let token = self.peek().clone();
+        let amount = match &token.kind {
+            TokenKind::Number(n) => n.parse::<u128>().ok().filter(|n| *n > 0),
+            _ => return self.unexpected("a duration such as 5m"),
+        };
+        let amount = match amount {
+            Some(a) => a,
+            None => return error(format!("{} is not a valid duration", token.describe()), token.column),
+        };
+        self.advance();
+
+        let unit = match &self.peek().kind {
+            TokenKind::Word(w) => DURATION_UNITS.iter().find(|(name, _)| w.eq_ignore_ascii_case(name)).map(|(_, ms)| *ms),
+            _ => Some(1),
+        };
+        match unit {
+            Some(ms) => {
+                if matches!(self.peek().kind, TokenKind::Word(_)) {
+                    self.advance();
+                }
+                match amount.checked_mul(ms) {
+                    Some(duration) => Ok(duration),
+                    None => error(format!("{} is too long a duration", token.describe()), token.column),
+                }
+            }
+            None => self.unexpected("a unit (ms, s, m, h, d or w)"),
+        }
+    }
+
+    // or_expr  := and_expr {OR and_expr}
+    // and_expr := not_expr {AND not_expr}
+    // not_expr := NOT not_expr | '(' or_expr ')' | column test
+    fn predicate(&mut self) -> Result<Predicate, ParseError> {
+        let mut left = self.and_predicate()?;
+        while self.eat_keyword("OR") {
+            left = Predicate::Or(Box::new(left), Box::new(self.and_predicate()?));
+        }
+        Ok(left)
+    }
+
+    fn and_predicate(&mut self) -> Result<Predicate, ParseError> {
+        let mut left = self.not_predicate()?;
+        while self.eat_keyword("AND") {
+            left = Predicate::And(Box::new(left), Box::new(self.not_predicate()?));
+        }
+        Ok(left)
+    }
+
+    fn not_predicate(&mut self) -> Result<Predicate, ParseError> {
+        if self.eat_keyword("NOT") {
+            return Ok(Predicate::Not(Box::new(self.not_predicate()?)));
+        }
+
+        if self.eat_symbol("(") {
+            let inner = self.predicate()?;
+            self.expect_symbol(")")?;
+            return Ok(inner);
+        }
+
+        let column = self.identifier()?;
+        let negated = self.eat_keyword("NOT");
+
+        let test = if self.eat_keyword("IN") {
+            self.expect_symbol("(")?;
+            let mut values = vec![self.literal()?];
+            while self.eat_symbol(",") {
+                values.push(self.literal()?);
+            }
+            self.expect_symbol(")")?;
+            Predicate::In { column, values }
+        } else if self.eat_keyword("BETWEEN") {
+            let low = self.literal()?;
+            self.expect_keyword("AND")?;
+            let high = self.literal()?;
+            Predicate::Between { column, low, high }
+        } else if negated {
+            return self.unexpected("IN or BETWEEN");
+        } else {
+            let op = match &self.peek().kind {
+                TokenKind::Symbol("=") => CompareOp::Eq,
+                TokenKind::Symbol("!=") | TokenKind::Symbol("<>") => CompareOp::Ne,
+                TokenKind::Symbol("<") => CompareOp::Lt,
+                TokenKind::Symbol("<=") => CompareOp::Le,
+                TokenKind::Symbol(">") => CompareOp::Gt,
+                TokenKind::Symbol(">=") => CompareOp::Ge,
+                _ => return self.unexpected("a comparison, IN or BETWEEN"),
+            };
+            self.advance();
+            Predicate::Compare { column, op, value: self.literal()? }
+        };
+
+        Ok(if negated { Predicate::Not(Box::new(test)) } else { test })
+    }
+
+    // (INSERT | UPSERT) assignments [AT t]
+    // INSERT BATCH [NDJSON | CSV]
+    fn insert(&mut self, overwrite: bool) -> Result<Statement, ParseError> {
+        // BATCH is only the keyword when it isn't a column being assigned
+        let is_batch = !overwrite && self.is_keyword("BATCH")
+            && self.tokens.get(self.pos + 1).is_none_or(|t| t.kind != TokenKind::Symbol("="));
+        if is_batch {
+            self.advance();
+            let format = if self.eat_keyword("CSV") {
+                BatchFormat::Csv
+            } else {
+                self.eat_keyword("NDJSON");
+                BatchFormat::Ndjson
+            };
+            return Ok(Statement::InsertBatch { format });
+        }
+
+        let values = self.assignments()?;
+        let at = if self.eat_keyword("AT") { Some(self.timestamp()?) } else { None };
+
+        Ok(Statement::Insert { values, at, overwrite })
+    }
+
+    // column = value {, column = value}
+    fn assignments(&mut self) -> Result<Vec<(String, Literal)>, ParseError> {
+        let mut values = Vec::new();
+        loop {
+            let column = self.identifier()?;
+            self.expect_symbol("=")?;
+            values.push((column, self.literal()?));
+
+            if !self.eat_symbol(",") {
+                break;
+            }
+        }
+        Ok(values)
+    }
+
+    // LATEST [n] [BEFORE t] [WHERE predicate] [BY column]
+    fn latest(&mut self) -> Result<Statement, ParseError> {
+        let count = if matches!(self.peek().kind, TokenKind::Number(_)) { self.row_count()? } else { 1 };
+        let before = if self.eat_keyword("BEFORE") { Some(self.timestamp()?) } else { None };
+        let filter = if self.eat_keyword("WHERE") { Some(self.predicate()?) } else { None };
+        let by = if self.eat_keyword("BY") { Some(self.identifier()?) } else { None };
+
+        Ok(Statement::Latest(Latest { count, before, filter, by }))
+    }
+
+    // DELETE ALL | DELETE AT t | DELETE RANGE start, end [WHERE predicate] | DELETE WHERE predicate
+    fn delete(&mut self) -> Result<Statement, ParseError> {
+        if self.eat_keyword("ALL") {
+            Ok(Statement::Delete(Delete::All))
+        } else if self.eat_keyword("AT") {
+            Ok(Statement::Delete(Delete::At(self.timestamp()?)))
+        } else if self.eat_keyword("RANGE") {
+            let start = self.timestamp()?;
+            self.expect_symbol(",")?;
+            let end = self.timestamp()?;
+            let filter = if self.eat_keyword("WHERE") { Some(self.predicate()?) } else { None };
+            Ok(Statement::Delete(Delete::Where { range: Some((start.min(end), start.max(end))), filter }))
+        } else if self.eat_keyword("WHERE") {
+            Ok(Statement::Delete(Delete::Where { range: None, filter: Some(self.predicate()?) }))
+        } else {
+            self.unexpected("ALL, AT, RANGE or WHERE")
+        }
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+// '::' front-end
+///////////////////////////////////////////////////////////////////////////////
+
+// One '::'-separated piece of a legacy query and the column it starts at.
+#[derive(Clone, Copy)]
+struct Segment<'a> {
+    text: &'a str,
+    column: usize,
+}
+
+impl<'a> Segment<'a> {
+    // "TIMESTAMP=123" and "123" both give "123", pointing at where the value starts.
+    fn value(self) -> Segment<'a> {
+        match self.text.split_once('=') {
+            Some((name, value)) => Segment { text: value, column: self.column + name.chars().count() + 1 },
+            None => self,
+        }
+    }
+
+    fn timestamp(self) -> Result<u128, ParseError> {
+        match self.text.parse::<u128>() {
+            Ok(t) => Ok(t),
+            Err(_) => error(format!("'{}' is not a valid timestamp", self.text), self.column),
+        }
+    }
+}
+
+fn parse_legacy(input: &str) -> Result<Query, ParseError> {
+    let mut segments = Vec::new();
+    let mut column = 1;
+    for text in input.split("::") {
+        segments.push(Segment { text, column });
+        column += text.chars().count() + 2;
+    }
+
+    // HIDE only counts as the last segment, so it can still be used as a column name.
+    let hide = segments.len() > 1 && segments[segments.len() - 1].text == "HIDE";
+    if hide {
+        segments.pop();
+    }
+
+    let end = input.chars().count() + 1;
+    let segment = |i: usize, expected: &str| -> Result<Segment, ParseError> {
+        match segments.get(i) {
+            Some(s) if !s.text.is_empty() => Ok(*s),
+            Some(s) => error(format!("expected {}", expected), s.column),
+            None => error(format!("expected {}", expected), end),
+        }
+    };
+
+    let operation = segments[0].text;
+    let statement = match operation {
+        "LIST" => {
+            let category = segment(1, "ALL, ONE, RANGE, METADATA or SAVED")?;
+
+            // Rows can be narrowed to some columns with a COLUMNS=a,b segment after the bounds.
+            let mut rest = if matches!(category.text, "ONE" | "RANGE") { 3 } else { 2 };
+            let projection = match segments.get(rest) {
+                Some(s) if s.text.starts_with("COLUMNS=") => {
+                    rest += 1;
+                    legacy_columns(*s)?
+                }
+                _ => Projection::All,
+            };
+
+            match category.text {
+                "ALL" => Statement::Select(Select { projection, filter: legacy_filter(segments.get(rest))?, ..Select::default() }),
+                "ONE" => {
+                    let at = segment(2, "a timestamp")?.value().timestamp()?;
+                    Statement::Select(Select { projection, at: Some(at), ..Select::default() })
+                }
+                "RANGE" => Statement::Select(Select {
+                    projection,
+                    range: Some(legacy_bounds(segment(2, "bounds in format 'lower,upper'")?)?),
+                    filter: legacy_filter(segments.get(rest))?,
+                    ..Select::default()
+                }),
+                "METADATA" => Statement::ShowMetadata,
+                "SAVED" => Statement::ShowSaved,
+                _ => return error(format!("unknown LIST category '{}'", category.text), category.column),
+            }
+        }
+        "AGGREGATE" => {
+            let field = segment(1, "a field name")?.text.to_string();
+            let op = segment(2, "an operation")?;
+            // Several operations over the same field are comma separated: MIN,MAX,AVG
+            let mut aggregates = Vec::new();
+            let mut column = op.column;
+            for name in op.text.split(',') {
+                match AggregateFunction::from_name(name) {
+                    Ok(function) => aggregates.push(Aggregate::new(function, &field)),
+                    Err(e) => return error(e, column),
+                }
+                column += name.chars().count() + 1;
+            }
+
+            // Optionally followed by bounds and/or a WHERE segment, in that order.
+            let mut rest = segments.get(3);
+            let range = match rest {
+                Some(s) if !s.text.starts_with("WHERE") => {
+                    rest = segments.get(4);
+                    Some(legacy_bounds(*s)?)
+                }
+                _ => None,
+            };
+
+            Statement::Select(Select {
+                projection: Projection::Aggregates(aggregates),
+                range,
+                filter: legacy_filter(rest)?,
+                ..Select::default()
+            })
+        }
+        "INSERT" if segments.get(1).is_some_and(|s| s.text == "BATCH") => {
+            let format = match segments.get(2) {
+                None => BatchFormat::Ndjson,
+                Some(s) if s.text == "NDJSON" => BatchFormat::Ndjson,
+                Some(s) if s.text == "CSV" => BatchFormat::Csv,
+                Some(s) => return error(format!("expected NDJSON or CSV, found '{}'", s.text), s.column),
+            };
+            Statement::InsertBatch { format }
+        }
+        "INSERT" => {
+            let pairs = segment(1, "column=value pairs")?;
+            let mut values = Vec::new();
+            let mut column = pairs.column;
+            for pair in pairs.text.split(',') {
+                match pair.split_once('=') {
+                    Some((k, v)) => values.push((k.to_string(), Literal::Str(v.to_string()))),
+                    None => return error(format!("expected column=value, found '{}'", pair), column),
+                }
+                column += pair.chars().count() + 1;
+            }
+
+            let at = match segments.get(2) {
+                Some(s) => Some(s.value().timestamp()?),
+                None => None,
+            };
+            Statement::Insert { values, at, overwrite: false }
+        }
+        "REMOVE" => {
+            let category = segment(1, "ALL, ONE or RANGE")?;
+            match category.text {
+                "ALL" if segments.len() > 2 => {
+                    Statement::Delete(Delete::Where { range: None, filter: legacy_filter(segments.get(2))? })
+                }
+                "ALL" => Statement::Delete(Delete::All),
+                "ONE" => Statement::Delete(Delete::At(segment(2, "TIMESTAMP=<timestamp>")?.value().timestamp()?)),
+                "RANGE" => Statement::Delete(Delete::Where {
+                    range: Some(legacy_bounds(segment(2, "bounds in format 'lower,upper'")?)?),
+                    filter: legacy_filter(segments.get(3))?,
+                }),
+                _ => return error(format!("unknown REMOVE category '{}'", category.text), category.column),
+            }
+        }
+        "TIME" => {
+            let date = segment(1, "a date in format YYYY-MM-DD HH:MM:SS")?;
+            match parse_datetime(&date.text.replace("%20", " ")) {
+                Some(timestamp) => Statement::Time { timestamp },
+                None => return error(String::from("invalid date, use YYYY-MM-DD HH:MM:SS"), date.column),
+            }
+        }
+        "SAVE" => {
+            let name = segment(1, "a file name")?;
+            if name.text.contains("CSV") {
+                Statement::SaveCsv
+            } else {
+                Statement::Save { filename: name.text.to_string() }
+            }
+        }
+        "LOAD" => {
+            let name = segment(1, "a file name")?;
+            if name.text == "SCHEMA" {
+                Statement::LoadSchema { filename: segment(2, "a schema file name")?.text.to_string() }
+            } else {
+                Statement::Load { filename: name.text.to_string() }
+            }
+        }
+        "BULK" => Statement::Bulk,
+        "SET" => {
+            let setting = segment(1, "DUPLICATES")?;
+            if setting.text != "DUPLICATES" {
+                return error(format!("unknown setting '{}'", setting.text), setting.column);
+            }
+            let name = segment(2, "a duplicate policy")?;
+            match DuplicatePolicy::from_name(&name.text.replace("%20", " ")) {
+                Ok(policy) => Statement::SetDuplicatePolicy { policy },
+                Err(e) => return error(e, name.column),
+            }
+        }
+        _ => return error(format!("unknown operation '{}'", operation), 1),
+    };
+
+    Ok(Query { statement, hide })
+}
+
+// "lower,upper", returned lower first.
+fn legacy_bounds(bounds: Segment) -> Result<(u128, u128), ParseError> {
+    let (lower_text, upper_text) = match bounds.text.split_once(',') {
+        Some(pair) => pair,
+        None => return error(String::from("expected bounds in format 'lower,upper'"), bounds.column),
+    };
+    let lower = Segment { text: lower_text, column: bounds.column }.timestamp()?;
+    let upper_column = bounds.column + lower_text.chars().count() + 1;
+    let upper = Segment { text: upper_text, column: upper_column }.timestamp()?;
+
+    Ok((lower.min(upper), lower.max(upper)))
+}
+
+// COLUMNS=a,b,c
+fn legacy_columns(segment: Segment) -> Result<Projection, ParseError> {
+    let names = segment.value();
+    let mut columns = Vec::new();
+    let mut column = names.column;
+    for name in names.text.split(',') {
+        if name.trim().is_empty() {
+            return error(String::from("expected a column name"), column);
+        }
+        columns.push(name.trim().to_string());
+        column += name.chars().count() + 1;
+    }
+    Ok(Projection::Columns(columns))
+}
+
+// An optional trailing "WHERE <predicate>" segment, e.g. LIST::ALL::WHERE store = 3. It is
+// parsed with the query language's own grammar, so errors still point into the full query.
+fn legacy_filter(segment: Option<&Segment>) -> Result<Option<Predicate>, ParseError> {
+    let segment = match segment {
+        Some(s) => s,
+        None => return Ok(None),
+    };
+
+    let offset = segment.column - 1;
+    let mut tokens = tokenize(segment.text).map_err(|e| ParseError { column: e.column + offset, ..e })?;
+    for token in tokens.iter_mut() {
+        token.column += offset;
+    }
+
+    let mut parser = Parser { tokens, pos: 0 };
+    parser.expect_keyword("WHERE")?;
+    let predicate = parser.predicate()?;
+    if parser.peek().kind != TokenKind::End {
+        return parser.unexpected("end of WHERE clause");
+    }
+
+    Ok(Some(predicate))
+}
@@ -0,0 +1,616 @@
+use bson::{doc, Bson, Document};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{Error, ErrorKind, Read, Write};
+
+use crate::database::FAN_OUT;
+use crate::wal::checksum;
+
+// Saves whose name ends in this are written in the columnar segment format; anything else
+// stays a single BSON document (.r2d2).
+pub const SEGMENT_EXTENSION : &str = ".r2seg";
+pub const SEGMENT_VERSION : u8 = 1;
+
+// Rows are encoded in blocks of one full tree leaf each, so a reader never needs more than
+// one block in memory.
+pub const SEGMENT_BLOCK_ROWS : usize = FAN_OUT;
+
+const SEGMENT_MAGIC : &[u8; 5] = b"R2SEG";
+
+// Every row gets a 2-bit tag per column, saying where (if anywhere) its value is stored.
+const TAG_BITS : u32 = 2;
+const TAG_ABSENT : u64 = 0;
+const TAG_NATIVE : u64 = 1;
+const TAG_OTHER : u64 = 2;
+
+// File layout, version 1:
+//
+//   "R2SEG" | version: u8 | header: BSON { schema, settings, min_timestamp, max_timestamp }
+//   block*  | end: u32 = 0
+//
+// settings may be missing from files written before it was added.
+//
+// Each block is [row count: u32 LE][payload length: u32 LE][checksum: u32 LE][payload]. The
+// payload holds the timestamps followed by one section per schema column, in schema order:
+//
+//   timestamps : first key as 16 bytes LE, then zigzag varints of the first delta and of
+//                every delta-of-delta after it
+//   column     : tags (2 bits per row), then the values tagged native, encoded by column type:
+//                  number  -> Gorilla XOR-compressed f64s
+//                  string  -> dictionary of distinct strings + bit-packed indexes
+//                  boolean -> one bit each
+//                then the values tagged other (wrong type for the column, e.g. an Int32 in a
+//                number column), as a BSON document keyed by row index within the block
+//
+// Every section is prefixed with its length as a varint.
+
+pub fn is_segment_file(filename: &str) -> bool {
+    filename.ends_with(SEGMENT_EXTENSION)
+}
+
+// Whether the file at path is in the segment format, whatever its name.
+pub fn has_segment_magic(path: &str) -> Result<bool, Error> {
+    let mut magic = [0u8; 5];
+    match File::open(path)?.read_exact(&mut magic) {
+        Ok(()) => Ok(&magic == SEGMENT_MAGIC),
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum ColumnType {
+    Number,
+    String,
+    Boolean,
+    Unknown,
+}
+
+impl ColumnType {
+    fn of(type_name: Option<&str>) -> ColumnType {
+        match type_name {
+            Some("number") => ColumnType::Number,
+            Some("string") => ColumnType::String,
+            Some("boolean") => ColumnType::Boolean,
+            _ => ColumnType::Unknown,
+        }
+    }
+
+    fn is_native(&self, value: &Bson) -> bool {
+        matches!(
+            (self, value),
+            (ColumnType::Number, Bson::Double(_)) | (ColumnType::String, Bson::String(_)) | (ColumnType::Boolean, Bson::Boolean(_))
+        )
+    }
+}
+
+fn schema_columns(schema: &Document) -> Vec<(String, ColumnType)> {
+    schema.iter().map(|(name, t)| (name.clone(), ColumnType::of(t.as_str()))).collect()
+}
+
+fn invalid(message: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, format!("corrupt segment file: {}", message))
+}
+
+pub struct SegmentWriter<W: Write> {
+    out: W,
+    columns: Vec<(String, ColumnType)>,
+    pending: Vec<(u128, Document)>,
+}
+
+impl<W: Write> SegmentWriter<W> {
+    pub fn new(mut out: W, schema: &Document, settings: &Document, min_timestamp: u128, max_timestamp: u128) -> Result<SegmentWriter<W>, Error> {
+        out.write_all(SEGMENT_MAGIC)?;
+        out.write_all(&[SEGMENT_VERSION])?;
+
+        let header = doc![
+            "schema" : schema.clone(),
+            "settings" : settings.clone(),
+            "min_timestamp" : min_timestamp.to_string(),
+            "max_timestamp" : max_timestamp.to_string(),
+        ];
+        header.to_writer(&mut out).map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+
+        Ok(SegmentWriter { out, columns: schema_columns(schema), pending: Vec::with_capacity(SEGMENT_BLOCK_ROWS) })
+    }
+
+    // Rows should be pushed in key order; anything else still round-trips, just less compactly.
+    pub fn push(&mut self, key: u128, row: Document) -> Result<(), Error> {
+        self.pending.push((key, row));
+        if self.pending.len() >= SEGMENT_BLOCK_ROWS {
+            self.flush_block()?;
+        }
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> Result<W, Error> {
+        self.flush_block()?;
+        self.out.write_all(&0u32.to_le_bytes())?;
+        self.out.flush()?;
+        Ok(self.out)
+    }
+
+    fn flush_block(&mut self) -> Result<(), Error> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+
+        let payload = encode_block(&self.columns, &self.pending)?;
+        self.out.write_all(&(self.pending.len() as u32).to_le_bytes())?;
+        self.out.write_all(&(payload.len() as u32).to_le_bytes())?;
+        self.out.write_all(&checksum(&payload).to_le_bytes())?;
+        self.out.write_all(&payload)?;
+
+        self.pending.clear();
+        Ok(())
+    }
+}
+
+pub struct SegmentReader<R: Read> {
+    input: R,
+    columns: Vec<(String, ColumnType)>,
+    schema: Document,
+    settings: Document,
+    min_timestamp: u128,
+    max_timestamp: u128,
+    finished: bool,
+}
+
+impl<R: Read> SegmentReader<R> {
+    // Reads the file header. Rows are then pulled one block at a time with next_block().
+    pub fn open(mut input: R) -> Result<SegmentReader<R>, Error> {
+        let mut magic = [0u8; 6];
+        input.read_exact(&mut magic)?;
+        if &magic[..5] != SEGMENT_MAGIC {
+            return Err(invalid("not a segment file"));
+        }
+        if magic[5] != SEGMENT_VERSION {
+            return Err(Error::new(ErrorKind::InvalidData, format!("unsupported segment version {}", magic[5])));
+        }
+
+        let header = Document::from_reader(&mut input).map_err(|_| invalid("unreadable header"))?;
+        let parse_timestamp = |field: &str| header.get_str(field).ok().and_then(|t| t.parse::<u128>().ok());
+
+        let schema = header.get_document("schema").map_err(|_| invalid("missing schema"))?.clone();
+        let settings = header.get_document("settings").cloned().unwrap_or_default();
+        let min_timestamp = parse_timestamp("min_timestamp").ok_or_else(|| invalid("missing min_timestamp"))?;
+        let max_timestamp = parse_timestamp("max_timestamp").ok_or_else(|| invalid("missing max_timestamp"))?;
+
+        Ok(SegmentReader { input, columns: schema_columns(&schema), schema, settings, min_timestamp, max_timestamp, finished: false })
+    }
+
+    pub fn schema(&self) -> &Document {
+        &self.schema
+    }
+
+    pub fn settings(&self) -> &Document {
+        &self.settings
+    }
+
+    pub fn min_timestamp(&self) -> u128 {
+        self.min_timestamp
+    }
+
+    pub fn max_timestamp(&self) -> u128 {
+        self.max_timestamp
+    }
+
+    // Returns the rows of the next block in the order they were written, or None after the last.
+    pub fn next_block(&mut self) -> Result<Option<Vec<(u128, Document)>>, Error> {
+        if self.finished {
+            return Ok(None);
+        }
+
+        let mut word = [0u8; 4];
+        self.input.read_exact(&mut word)?;
+        let row_count = u32::from_le_bytes(word) as usize;
+        if row_count == 0 {
+            self.finished = true;
+            return Ok(None);
+        }
+
+        self.input.read_exact(&mut word)?;
+        let payload_len = u32::from_le_bytes(word) as usize;
+        self.input.read_exact(&mut word)?;
+        let expected = u32::from_le_bytes(word);
+
+        let mut payload = vec![0u8; payload_len];
+        self.input.read_exact(&mut payload)?;
+        if checksum(&payload) != expected {
+            return Err(invalid("block checksum mismatch"));
+        }
+
+        decode_block(&self.columns, row_count, &payload).map(Some)
+    }
+}
+
+fn encode_block(columns: &[(String, ColumnType)], rows: &[(u128, Document)]) -> Result<Vec<u8>, Error> {
+    let mut out = Vec::new();
+    write_section(&mut out, &encode_timestamps(rows));
+
+    for (name, column_type) in columns {
+        let mut tags = BitWriter::new();
+        let mut native = Vec::new();
+        let mut other = Document::new();
+
+        for (i, (_, row)) in rows.iter().enumerate() {
+            match row.get(name) {
+                None => tags.write_bits(TAG_ABSENT, TAG_BITS),
+                Some(value) if column_type.is_native(value) => {
+                    tags.write_bits(TAG_NATIVE, TAG_BITS);
+                    native.push(value);
+                }
+                Some(value) => {
+                    tags.write_bits(TAG_OTHER, TAG_BITS);
+                    other.insert(i.to_string(), value.clone());
+                }
+            }
+        }
+
+        write_section(&mut out, &tags.into_bytes());
+        write_section(&mut out, &encode_native(*column_type, &native));
+
+        let mut other_bytes = Vec::new();
+        if !other.is_empty() {
+            other.to_writer(&mut other_bytes).map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+        }
+        write_section(&mut out, &other_bytes);
+    }
+
+    Ok(out)
+}
+
+fn decode_block(columns: &[(String, ColumnType)], row_count: usize, payload: &[u8]) -> Result<Vec<(u128, Document)>, Error> {
+    let mut pos = 0;
+    let keys = decode_timestamps(read_section(payload, &mut pos)?, row_count)?;
+    let mut rows = keys.into_iter().map(|k| (k, Document::new())).collect::<Vec<(u128, Document)>>();
+
+    for (name, column_type) in columns {
+        let mut tags = BitReader::new(read_section(payload, &mut pos)?);
+        let mut row_tags = Vec::with_capacity(row_count);
+        for _ in 0..row_count {
+            row_tags.push(tags.read_bits(TAG_BITS)?);
+        }
+
+        let native_count = row_tags.iter().filter(|t| **t == TAG_NATIVE).count();
+        let mut native = decode_native(*column_type, read_section(payload, &mut pos)?, native_count)?.into_iter();
+
+        let other_bytes = read_section(payload, &mut pos)?;
+        let other = if other_bytes.is_empty() {
+            Document::new()
+        } else {
+            Document::from_reader(other_bytes).map_err(|_| invalid("unreadable fallback values"))?
+        };
+
+        for (i, tag) in row_tags.into_iter().enumerate() {
+            let value = match tag {
+                TAG_ABSENT => continue,
+                TAG_NATIVE => native.next().ok_or_else(|| invalid("too few column values"))?,
+                TAG_OTHER => other.get(i.to_string()).cloned().ok_or_else(|| invalid("missing fallback value"))?,
+                _ => return Err(invalid("unknown value tag")),
+            };
+            rows[i].1.insert(name.clone(), value);
+        }
+    }
+
+    Ok(rows)
+}
+
+// Regular sampling intervals make the delta-of-delta zero, which is a single byte.
+fn encode_timestamps(rows: &[(u128, Document)]) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut previous_key = 0u128;
+    let mut previous_delta = 0u128;
+
+    for (i, (key, _)) in rows.iter().enumerate() {
+        if i == 0 {
+            out.extend_from_slice(&key.to_le_bytes());
+        } else {
+            let delta = key.wrapping_sub(previous_key);
+            if i == 1 {
+                write_varint(&mut out, zigzag(delta as i128));
+            } else {
+                write_varint(&mut out, zigzag((delta as i128).wrapping_sub(previous_delta as i128)));
+            }
+            previous_delta = delta;
+        }
+        previous_key = *key;
+    }
+
+    out
+}
+
+fn decode_timestamps(bytes: &[u8], row_count: usize) -> Result<Vec<u128>, Error> {
+    if bytes.len() < 16 {
+        return Err(invalid("truncated timestamps"));
+    }
+
+    let mut first = [0u8; 16];
+    first.copy_from_slice(&bytes[..16]);
+
+    let mut keys = Vec::with_capacity(row_count);
+    keys.push(u128::from_le_bytes(first));
+
+    let mut pos = 16;
+    let mut delta = 0i128;
+    for i in 1..row_count {
+        let encoded = unzigzag(read_varint(bytes, &mut pos)?);
+        delta = if i == 1 { encoded } else { delta.wrapping_add(encoded) };
+        keys.push(keys[i - 1].wrapping_add(delta as u128));
+    }
+
+    Ok(keys)
+}
+
+fn encode_native(column_type: ColumnType, values: &[&Bson]) -> Vec<u8> {
+    match column_type {
+        ColumnType::Number => {
+            gorilla_encode(&values.iter().filter_map(|v| v.as_f64()).collect::<Vec<f64>>())
+        }
+        ColumnType::String => {
+            let mut dictionary : Vec<&str> = Vec::new();
+            let mut indexes : HashMap<&str, u64> = HashMap::new();
+            let mut out = Vec::new();
+
+            let codes = values.iter().filter_map(|v| v.as_str()).map(|s| {
+                *indexes.entry(s).or_insert_with(|| {
+                    dictionary.push(s);
+                    (dictionary.len() - 1) as u64
+                })
+            }).collect::<Vec<u64>>();
+
+            write_varint(&mut out, dictionary.len() as u128);
+            for s in &dictionary {
+                write_varint(&mut out, s.len() as u128);
+                out.extend_from_slice(s.as_bytes());
+            }
+
+            let width = index_width(dictionary.len());
+            let mut bits = BitWriter::new();
+            for code in codes {
+                bits.write_bits(code, width);
+            }
+            out.extend_from_slice(&bits.into_bytes());
+            out
+        }
+        ColumnType::Boolean => {
+            let mut bits = BitWriter::new();
+            for v in values {
+                bits.write_bit(v.as_bool().unwrap_or(false));
+            }
+            bits.into_bytes()
+        }
+        ColumnType::Unknown => Vec::new(),
+    }
+}
+
+fn decode_native(column_type: ColumnType, bytes: &[u8], count: usize) -> Result<Vec<Bson>, Error> {
+    match column_type {
+        ColumnType::Number => {
+            Ok(gorilla_decode(bytes, count)?.into_iter().map(Bson::Double).collect())
+        }
+        ColumnType::String => {
+            let mut pos = 0;
+            let dictionary_len = read_varint(bytes, &mut pos)? as usize;
+            let mut dictionary = Vec::new();
+            for _ in 0..dictionary_len {
+                let len = read_varint(bytes, &mut pos)? as usize;
+                let raw = bytes.get(pos..pos + len).ok_or_else(|| invalid("truncated dictionary"))?;
+                dictionary.push(String::from_utf8(raw.to_vec()).map_err(|_| invalid("dictionary entry is not UTF-8"))?);
+                pos += len;
+            }
+
+            let width = index_width(dictionary_len);
+            let mut bits = BitReader::new(&bytes[pos..]);
+            let mut values = Vec::with_capacity(count);
+            for _ in 0..count {
+                let code = bits.read_bits(width)? as usize;
+                let s = dictionary.get(code).ok_or_else(|| invalid("dictionary index out of range"))?;
+                values.push(Bson::String(s.clone()));
+            }
+            Ok(values)
+        }
+        ColumnType::Boolean => {
+            let mut bits = BitReader::new(bytes);
+            let mut values = Vec::with_capacity(count);
+            for _ in 0..count {
+                values.push(Bson::Boolean(bits.read_bit()?));
+            }
+            Ok(values)
+        }
+        ColumnType::Unknown => Ok(Vec::new()),
+    }
+}
+
+// Bits needed to address every entry of a dictionary this size.
+fn index_width(dictionary_len: usize) -> u32 {
+    if dictionary_len <= 1 { 0 } else { usize::BITS - (dictionary_len - 1).leading_zeros() }
+}
+
+// Gorilla float compression (Pelkonen et al., VLDB 2015): each value is XORed with the previous
+// one. An identical value costs one bit; otherwise only the meaningful (non-zero) middle bits
+// of the XOR are stored, reusing the previous leading/trailing zero window when it fits.
+fn gorilla_encode(values: &[f64]) -> Vec<u8> {
+    let mut bits = BitWriter::new();
+    let mut previous = 0u64;
+    let mut window : Option<(u32, u32)> = None;
+
+    for (i, value) in values.iter().enumerate() {
+        let current = value.to_bits();
+        if i == 0 {
+            bits.write_bits(current, 64);
+            previous = current;
+            continue;
+        }
+
+        let xor = current ^ previous;
+        previous = current;
+
+        if xor == 0 {
+            bits.write_bit(false);
+            continue;
+        }
+
+        bits.write_bit(true);
+        let leading = xor.leading_zeros().min(31);
+        let trailing = xor.trailing_zeros();
+
+        match window {
+            Some((prev_leading, prev_trailing)) if leading >= prev_leading && trailing >= prev_trailing => {
+                bits.write_bit(false);
+                bits.write_bits(xor >> prev_trailing, 64 - prev_leading - prev_trailing);
+            }
+            _ => {
+                let meaningful = 64 - leading - trailing;
+                bits.write_bit(true);
+                bits.write_bits(leading as u64, 5);
+                bits.write_bits((meaningful - 1) as u64, 6);
+                bits.write_bits(xor >> trailing, meaningful);
+                window = Some((leading, trailing));
+            }
+        }
+    }
+
+    bits.into_bytes()
+}
+
+fn gorilla_decode(bytes: &[u8], count: usize) -> Result<Vec<f64>, Error> {
+    let mut bits = BitReader::new(bytes);
+    let mut values = Vec::with_capacity(count);
+    let mut previous = 0u64;
+    let mut window = (0u32, 0u32);
+
+    for i in 0..count {
+        if i == 0 {
+            previous = bits.read_bits(64)?;
+        } else if bits.read_bit()? {
+            let xor = if !bits.read_bit()? {
+                let (leading, trailing) = window;
+                bits.read_bits(64 - leading - trailing)? << trailing
+            } else {
+                let leading = bits.read_bits(5)? as u32;
+                let meaningful = bits.read_bits(6)? as u32 + 1;
+                let trailing = 64u32.checked_sub(leading + meaningful).ok_or_else(|| invalid("bad float window"))?;
+                window = (leading, trailing);
+                bits.read_bits(meaningful)? << trailing
+            };
+            previous ^= xor;
+        }
+
+        values.push(f64::from_bits(previous));
+    }
+
+    Ok(values)
+}
+
+fn write_section(out: &mut Vec<u8>, bytes: &[u8]) {
+    write_varint(out, bytes.len() as u128);
+    out.extend_from_slice(bytes);
+}
+
+fn read_section<'a>(bytes: &'a [u8], pos: &mut usize) -> Result<&'a [u8], Error> {
+    let len = read_varint(bytes, pos)? as usize;
+    let section = bytes.get(*pos..*pos + len).ok_or_else(|| invalid("truncated section"))?;
+    *pos += len;
+    Ok(section)
+}
+
+// LEB128.
+fn write_varint(out: &mut Vec<u8>, mut value: u128) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+fn read_varint(bytes: &[u8], pos: &mut usize) -> Result<u128, Error> {
+    let mut value = 0u128;
+    let mut shift = 0;
+
+    loop {
+        let byte = *bytes.get(*pos).ok_or_else(|| invalid("truncated varint"))?;
+        *pos += 1;
+
+        if shift >= 128 {
+            return Err(invalid("varint too long"));
+        }
+        value |= ((byte & 0x7f) as u128) << shift;
+
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+        shift += 7;
+    }
+}
+
+fn zigzag(value: i128) -> u128 {
+    ((value << 1) ^ (value >> 127)) as u128
+}
+
+fn unzigzag(value: u128) -> i128 {
+    ((value >> 1) as i128) ^ -((value & 1) as i128)
+}
+
+// Most-significant-bit-first bit packing.
+struct BitWriter {
+    bytes: Vec<u8>,
+    used: u32,
+}
+
+impl BitWriter {
+    fn new() -> BitWriter {
+        BitWriter { bytes: Vec::new(), used: 8 }
+    }
+
+    fn write_bit(&mut self, bit: bool) {
+        if self.used == 8 {
+            self.bytes.push(0);
+            self.used = 0;
+        }
+        if bit {
+            *self.bytes.last_mut().unwrap() |= 1 << (7 - self.used);
+        }
+        self.used += 1;
+    }
+
+    fn write_bits(&mut self, value: u64, count: u32) {
+        for i in (0..count).rev() {
+            self.write_bit((value >> i) & 1 == 1);
+        }
+    }
+
+    fn into_bytes(self) -> Vec<u8> {
+        self.bytes
+    }
+}
+
+struct BitReader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(bytes: &'a [u8]) -> BitReader<'a> {
+        BitReader { bytes, position: 0 }
+    }
+
+    fn read_bit(&mut self) -> Result<bool, Error> {
+        let byte = self.bytes.get(self.position / 8).ok_or_else(|| invalid("truncated bit stream"))?;
+        let bit = (byte >> (7 - (self.position % 8))) & 1 == 1;
+        self.position += 1;
+        Ok(bit)
+    }
+
+    fn read_bits(&mut self, count: u32) -> Result<u64, Error> {
+        let mut value = 0u64;
+        for _ in 0..count {
+            value = (value << 1) | self.read_bit()? as u64;
+        }
+        Ok(value)
+    }
+}
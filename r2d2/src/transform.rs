@@ -0,0 +1,231 @@
+use std::collections::VecDeque;
+use std::fmt;
+
+// Functions that turn a series into another series, rather than into one value: a result per
+// row (or per time bucket) instead of per range.
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TransformFunction {
+    // Change since the previous reading.
+    Difference,
+    // Change per unit milliseconds since the previous reading. Defaults to per millisecond.
+    Derivative { unit: u128 },
+    // As Derivative, leaving out decreases instead of reporting them.
+    NonNegativeDerivative { unit: u128 },
+    // As Derivative, for counters: a decrease is a counter reset, after which the counter
+    // went up from 0 to its current value.
+    Rate { unit: u128 },
+    // Mean of the last N readings, or of the readings in the window of time ending at each
+    // one. A row window only gives values once it has N readings.
+    MovingAverage(Window),
+    // Exponentially weighted moving average, starting at the first reading. alpha, between 0
+    // and 1, is the weight given to each new reading.
+    Ewma { alpha: f64 },
+    // Running total of the readings.
+    CumulativeSum,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Window {
+    Rows(usize),
+    // Milliseconds: the window ending at key holds readings with keys in (key - width, key].
+    Time(u128),
+}
+
+// Second argument of a transform: a bare number, or a duration with a unit (in milliseconds).
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Argument {
+    Number(f64),
+    Duration(u128),
+}
+
+impl TransformFunction {
+    pub const NAMES : [&'static str; 7] = [
+        "DIFFERENCE", "DERIVATIVE", "NON_NEGATIVE_DERIVATIVE", "RATE", "MOVING_AVERAGE", "EWMA", "CUMULATIVE_SUM",
+    ];
+
+    pub fn is_transform(name: &str) -> bool {
+        TransformFunction::NAMES.iter().any(|n| n.eq_ignore_ascii_case(name))
+    }
+
+    // name(column [, argument]). A bare number as a unit is in milliseconds.
+    pub fn from_parts(name: &str, argument: Option<Argument>) -> Result<TransformFunction, String> {
+        let name = name.to_ascii_uppercase();
+
+        let unit = match argument {
+            None => Ok(1),
+            Some(Argument::Duration(ms)) => Ok(ms),
+            Some(Argument::Number(n)) if n >= 1.0 && n.fract() == 0.0 => Ok(n as u128),
+            Some(Argument::Number(n)) => Err(format!("{} is not a valid unit for {}", n, name)),
+        };
+
+        match (name.as_str(), argument) {
+            ("DIFFERENCE", None) => Ok(TransformFunction::Difference),
+            ("CUMULATIVE_SUM", None) => Ok(TransformFunction::CumulativeSum),
+            ("DIFFERENCE" | "CUMULATIVE_SUM", Some(_)) => Err(format!("{} does not take a second argument", name)),
+            ("DERIVATIVE", _) => Ok(TransformFunction::Derivative { unit: unit? }),
+            ("NON_NEGATIVE_DERIVATIVE", _) => Ok(TransformFunction::NonNegativeDerivative { unit: unit? }),
+            ("RATE", _) => Ok(TransformFunction::Rate { unit: unit? }),
+            ("MOVING_AVERAGE", Some(Argument::Number(n))) if n >= 1.0 && n.fract() == 0.0 => {
+                Ok(TransformFunction::MovingAverage(Window::Rows(n as usize)))
+            }
+            ("MOVING_AVERAGE", Some(Argument::Duration(ms))) => Ok(TransformFunction::MovingAverage(Window::Time(ms))),
+            ("MOVING_AVERAGE", _) => {
+                Err(String::from("MOVING_AVERAGE needs a number of rows or a window of time, e.g. MOVING_AVERAGE(column, 5m)"))
+            }
+            ("EWMA", Some(Argument::Number(alpha))) if alpha > 0.0 && alpha <= 1.0 => Ok(TransformFunction::Ewma { alpha }),
+            ("EWMA", _) => Err(String::from("EWMA needs a weight between 0 and 1, e.g. EWMA(column, 0.3)")),
+            _ => Err(format!("unknown function {}, must be one of: {}", name, TransformFunction::NAMES.join(", "))),
+        }
+    }
+
+    // DIFFERENCE, DERIVATIVE, NON_NEGATIVE_DERIVATIVE and RATE only depend on the change from
+    // one reading to the next, which is what lets them be totalled per time bucket.
+    pub fn is_change(&self) -> bool {
+        !matches!(self, TransformFunction::MovingAverage(_) | TransformFunction::Ewma { .. } | TransformFunction::CumulativeSum)
+    }
+
+    // Column heading, e.g. RATE(requests, 1000ms). The unit is left out when it is 1ms.
+    pub fn label(&self, field: &str) -> String {
+        match self {
+            TransformFunction::Derivative { unit } | TransformFunction::NonNegativeDerivative { unit }
+            | TransformFunction::Rate { unit } if *unit != 1 => format!("{}({}, {}ms)", self, field, unit),
+            TransformFunction::MovingAverage(Window::Rows(n)) => format!("{}({}, {})", self, field, n),
+            TransformFunction::MovingAverage(Window::Time(ms)) => format!("{}({}, {}ms)", self, field, ms),
+            TransformFunction::Ewma { alpha } => format!("{}({}, {})", self, field, alpha),
+            _ => format!("{}({})", self, field),
+        }
+    }
+
+    // The value for a row or bucket from its change, or None when there isn't one (a
+    // decrease, for NON_NEGATIVE_DERIVATIVE, or any function that isn't a change function).
+    pub fn apply(&self, change: &Change) -> Option<f64> {
+        let per_unit = |delta: f64, unit: u128| delta / change.elapsed as f64 * unit as f64;
+
+        match *self {
+            TransformFunction::Difference => Some(change.delta),
+            _ if change.elapsed == 0 => None,
+            TransformFunction::Derivative { unit } => Some(per_unit(change.delta, unit)),
+            TransformFunction::NonNegativeDerivative { unit } if change.delta >= 0.0 => Some(per_unit(change.delta, unit)),
+            TransformFunction::NonNegativeDerivative { .. } => None,
+            TransformFunction::Rate { unit } => Some(per_unit(change.increase, unit)),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for TransformFunction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TransformFunction::Difference => write!(f, "DIFFERENCE"),
+            TransformFunction::Derivative { .. } => write!(f, "DERIVATIVE"),
+            TransformFunction::NonNegativeDerivative { .. } => write!(f, "NON_NEGATIVE_DERIVATIVE"),
+            TransformFunction::Rate { .. } => write!(f, "RATE"),
+            TransformFunction::MovingAverage(_) => write!(f, "MOVING_AVERAGE"),
+            TransformFunction::Ewma { .. } => write!(f, "EWMA"),
+            TransformFunction::CumulativeSum => write!(f, "CUMULATIVE_SUM"),
+        }
+    }
+}
+
+// Change between readings: from one to the next, or summed over every pair of consecutive
+// readings ending in the same bucket.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Change {
+    pub delta: f64,
+    // Like delta, but counting a decrease as a reset to 0 followed by a rise to the new value.
+    pub increase: f64,
+    pub elapsed: u128,
+}
+
+impl Change {
+    pub fn add(&mut self, other: Change) {
+        self.delta += other.delta;
+        self.increase += other.increase;
+        self.elapsed += other.elapsed;
+    }
+}
+
+// Turns readings, pushed in key order, into the change since the previous one.
+#[derive(Clone, Debug, Default)]
+pub struct Differ {
+    previous: Option<(u128, f64)>,
+}
+
+impl Differ {
+    // None for the first reading, which has nothing to compare to.
+    pub fn push(&mut self, key: u128, value: f64) -> Option<Change> {
+        let change = self.previous.map(|(previous_key, previous_value)| {
+            let delta = value - previous_value;
+            Change {
+                delta,
+                increase: if delta < 0.0 { value } else { delta },
+                elapsed: key - previous_key,
+            }
+        });
+
+        self.previous = Some((key, value));
+        change
+    }
+}
+
+// Running state for a transform over readings pushed in key order, giving the output for each.
+#[derive(Clone, Debug)]
+pub struct Transformer {
+    function: TransformFunction,
+    differ: Differ,
+    // Readings in the moving average's window, and their sum.
+    window: VecDeque<(u128, f64)>,
+    window_sum: f64,
+    // Last EWMA or cumulative sum.
+    running: Option<f64>,
+}
+
+impl Transformer {
+    pub fn new(function: TransformFunction) -> Transformer {
+        Transformer {
+            function,
+            differ: Differ::default(),
+            window: VecDeque::new(),
+            window_sum: 0.0,
+            running: None,
+        }
+    }
+
+    // None when this reading has no output, such as the first one for a change function.
+    pub fn push(&mut self, key: u128, value: f64) -> Option<f64> {
+        match self.function {
+            TransformFunction::MovingAverage(window) => {
+                self.window.push_back((key, value));
+                self.window_sum += value;
+
+                while let Some(&(first_key, first_value)) = self.window.front() {
+                    let expired = match window {
+                        Window::Rows(n) => self.window.len() > n,
+                        Window::Time(width) => key - first_key >= width,
+                    };
+                    if !expired {
+                        break;
+                    }
+                    self.window.pop_front();
+                    self.window_sum -= first_value;
+                }
+
+                match window {
+                    Window::Rows(n) if self.window.len() < n => None,
+                    _ => Some(self.window_sum / self.window.len() as f64),
+                }
+            }
+            TransformFunction::Ewma { alpha } => {
+                let average = self.running.map_or(value, |previous| alpha * value + (1.0 - alpha) * previous);
+                self.running = Some(average);
+                self.running
+            }
+            TransformFunction::CumulativeSum => {
+                self.running = Some(self.running.unwrap_or(0.0) + value);
+                self.running
+            }
+            function => self.differ.push(key, value).and_then(|change| function.apply(&change)),
+        }
+    }
+}
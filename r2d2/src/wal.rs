@@ -0,0 +1,283 @@
+use bson::{doc, Document};
+use std::fs::{File, OpenOptions};
+use std::io::{BufReader, Error, ErrorKind, Read, Write};
+use std::time::{Duration, Instant};
+
+use crate::database::DATA_PATH;
+
+pub const WAL_FILENAME : &str = "r2d2.wal";
+
+// Each record on disk is framed as [length: u32 LE][checksum: u32 LE][BSON document].
+const RECORD_HEADER_LEN : usize = 8;
+
+// Anything claiming to be bigger than this is treated as a corrupt frame, not an allocation request.
+const RECORD_MAX_LEN : usize = 64 * 1024 * 1024;
+
+// How often the log is forced to stable storage.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SyncPolicy {
+    // fsync after every record; an acknowledged mutation is never lost.
+    Always,
+    // fsync after every N records; a crash may lose up to N - 1 acknowledged mutations.
+    EveryN(usize),
+    // Leave flushing to the operating system.
+    Never,
+}
+
+// When the server should take a checkpoint on its own. Either trigger is enough; None disables it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CheckpointPolicy {
+    pub every_mutations: Option<usize>,
+    pub interval: Option<Duration>,
+}
+
+impl CheckpointPolicy {
+    pub const NEVER : CheckpointPolicy = CheckpointPolicy { every_mutations: None, interval: None };
+}
+
+// One logged mutation. Replaying every record in order rebuilds the in-memory tree.
+#[derive(Clone, Debug, PartialEq)]
+pub enum WalRecord {
+    Insert { key: u128, row: Document },
+    Remove { key: u128 },
+    Clear,
+    Schema { schema: Document },
+    Load { filename: String },
+    // Database settings (e.g. the duplicate policy), in the form Database saves them.
+    Settings { settings: Document },
+}
+
+impl WalRecord {
+    // u128 keys are stored as strings, for the same reason as in Database::save.
+    fn to_document(&self, lsn: u64) -> Document {
+        let mut doc = match self {
+            WalRecord::Insert { key, row } => doc![
+                "op" : "INSERT",
+                "key" : key.to_string(),
+                "row" : row.clone(),
+            ],
+            WalRecord::Remove { key } => doc![
+                "op" : "REMOVE",
+                "key" : key.to_string(),
+            ],
+            WalRecord::Clear => doc![
+                "op" : "CLEAR",
+            ],
+            WalRecord::Schema { schema } => doc![
+                "op" : "SCHEMA",
+                "schema" : schema.clone(),
+            ],
+            WalRecord::Load { filename } => doc![
+                "op" : "LOAD",
+                "filename" : filename.clone(),
+            ],
+            WalRecord::Settings { settings } => doc![
+                "op" : "SETTINGS",
+                "settings" : settings.clone(),
+            ],
+        };
+
+        doc.insert("lsn", lsn as i64);
+        doc
+    }
+
+    fn from_document(doc: &Document) -> Option<LoggedRecord> {
+        let key = || doc.get_str("key").ok().and_then(|k| k.parse::<u128>().ok());
+        let lsn = doc.get_i64("lsn").ok()? as u64;
+
+        let record = match doc.get_str("op").ok()? {
+            "INSERT" => Some(WalRecord::Insert { key: key()?, row: doc.get_document("row").ok()?.clone() }),
+            "REMOVE" => Some(WalRecord::Remove { key: key()? }),
+            "CLEAR" => Some(WalRecord::Clear),
+            "SCHEMA" => Some(WalRecord::Schema { schema: doc.get_document("schema").ok()?.clone() }),
+            "LOAD" => Some(WalRecord::Load { filename: doc.get_str("filename").ok()?.to_string() }),
+            "SETTINGS" => Some(WalRecord::Settings { settings: doc.get_document("settings").ok()?.clone() }),
+            _ => None,
+        };
+
+        record.map(|r| (lsn, r))
+    }
+}
+
+// A record together with its log sequence number.
+pub type LoggedRecord = (u64, WalRecord);
+
+pub struct WriteAheadLog {
+    file: File,
+    filename: String,
+    policy: SyncPolicy,
+    unsynced: usize,
+    // Log sequence number the next record will get. Numbers keep increasing across
+    // truncations, so a checkpoint can name exactly which records it already contains.
+    next_lsn: u64,
+    checkpoint_policy: CheckpointPolicy,
+    since_checkpoint: usize,
+    last_checkpoint: Instant,
+}
+
+impl WriteAheadLog {
+    // Opens (or creates) the log under DATA_PATH and returns it along with every intact record
+    // it already holds and its sequence number. A torn or corrupt tail, as left behind by a crash
+    // mid-write, is cut off so that new records are never appended after garbage.
+    pub fn open(filename: &str, policy: SyncPolicy) -> Result<(WriteAheadLog, Vec<LoggedRecord>), Error> {
+        let full_path = format!("{}/{}", DATA_PATH, filename);
+        let file = OpenOptions::new().read(true).append(true).create(true).open(&full_path)?;
+
+        let file_len = file.metadata()?.len();
+        let mut reader = BufReader::new(&file);
+        let mut records = Vec::new();
+        let mut valid_len : u64 = 0;
+
+        while let Some((record, frame_len)) = read_record(&mut reader)? {
+            records.push(record);
+            valid_len += frame_len as u64;
+        }
+
+        if valid_len < file_len {
+            eprintln!("Discarding {} bytes of incomplete write-ahead log in '{}'", file_len - valid_len, full_path);
+            file.set_len(valid_len)?;
+            file.sync_all()?;
+        }
+
+        let next_lsn = records.last().map(|(lsn, _)| lsn + 1).unwrap_or(1);
+        let wal = WriteAheadLog {
+            file,
+            filename: filename.to_string(),
+            policy,
+            unsynced: 0,
+            next_lsn,
+            checkpoint_policy: CheckpointPolicy::NEVER,
+            since_checkpoint: records.len(),
+            last_checkpoint: Instant::now(),
+        };
+
+        Ok((wal, records))
+    }
+
+    pub fn filename(&self) -> &str {
+        &self.filename
+    }
+
+    // Sequence number of the most recent record, or 0 if nothing was ever logged.
+    pub fn last_lsn(&self) -> u64 {
+        self.next_lsn - 1
+    }
+
+    // Makes sure new records are numbered after everything a checkpoint already covers, even
+    // when the log itself was truncated down to nothing.
+    pub fn advance_past(&mut self, lsn: u64) {
+        self.next_lsn = self.next_lsn.max(lsn + 1);
+    }
+
+    pub fn set_checkpoint_policy(&mut self, policy: CheckpointPolicy) {
+        self.checkpoint_policy = policy;
+    }
+
+    pub fn checkpoint_due(&self) -> bool {
+        if self.since_checkpoint == 0 {
+            return false;
+        }
+
+        let by_count = self.checkpoint_policy.every_mutations.is_some_and(|n| self.since_checkpoint >= n);
+        let by_time = self.checkpoint_policy.interval.is_some_and(|t| self.last_checkpoint.elapsed() >= t);
+
+        by_count || by_time
+    }
+
+    // Drops every record. Only call this once a checkpoint covering last_lsn() is on disk.
+    pub fn truncate(&mut self) -> Result<(), Error> {
+        self.file.set_len(0)?;
+        self.file.sync_all()?;
+        self.unsynced = 0;
+        self.since_checkpoint = 0;
+        self.last_checkpoint = Instant::now();
+        Ok(())
+    }
+
+    pub fn append(&mut self, record: &WalRecord) -> Result<(), Error> {
+        self.append_all(std::slice::from_ref(record))
+    }
+
+    // Appends several records with a single write, syncing at most once at the end.
+    pub fn append_all(&mut self, records: &[WalRecord]) -> Result<(), Error> {
+        let mut framed = Vec::new();
+        for (i, record) in records.iter().enumerate() {
+            framed.extend_from_slice(&encode_record(record, self.next_lsn + i as u64)?);
+        }
+        self.file.write_all(&framed)?;
+        self.next_lsn += records.len() as u64;
+        self.unsynced += records.len();
+        self.since_checkpoint += records.len();
+
+        let due = match self.policy {
+            SyncPolicy::Always => true,
+            SyncPolicy::EveryN(n) => self.unsynced >= n.max(1),
+            SyncPolicy::Never => false,
+        };
+
+        if due {
+            self.sync()?;
+        }
+
+        Ok(())
+    }
+
+    pub fn sync(&mut self) -> Result<(), Error> {
+        self.file.sync_data()?;
+        self.unsynced = 0;
+        Ok(())
+    }
+}
+
+fn encode_record(record: &WalRecord, lsn: u64) -> Result<Vec<u8>, Error> {
+    let mut body : Vec<u8> = Vec::new();
+    record.to_document(lsn).to_writer(&mut body).map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+
+    let mut framed = Vec::with_capacity(RECORD_HEADER_LEN + body.len());
+    framed.extend_from_slice(&(body.len() as u32).to_le_bytes());
+    framed.extend_from_slice(&checksum(&body).to_le_bytes());
+    framed.extend_from_slice(&body);
+
+    Ok(framed)
+}
+
+// Returns the record and the size of its frame, or Ok(None) at the end of the intact portion
+// of the log, whether that is a clean end of file or a frame that was only partially written.
+fn read_record(reader: &mut impl Read) -> Result<Option<(LoggedRecord, usize)>, Error> {
+    let mut header = [0u8; RECORD_HEADER_LEN];
+    match reader.read_exact(&mut header) {
+        Ok(_) => {}
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+
+    let len = u32::from_le_bytes([header[0], header[1], header[2], header[3]]) as usize;
+    let expected = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+    if len > RECORD_MAX_LEN {
+        return Ok(None);
+    }
+
+    let mut body = vec![0u8; len];
+    match reader.read_exact(&mut body) {
+        Ok(_) => {}
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+
+    if checksum(&body) != expected {
+        return Ok(None);
+    }
+
+    let record = Document::from_reader(&body[..]).ok().as_ref().and_then(WalRecord::from_document);
+    Ok(record.map(|r| (r, RECORD_HEADER_LEN + len)))
+}
+
+// FNV-1a; enough to tell a torn write from a complete one.
+pub(crate) fn checksum(bytes: &[u8]) -> u32 {
+    let mut hash : u32 = 0x811c9dc5;
+    for b in bytes {
+        hash ^= *b as u32;
+        hash = hash.wrapping_mul(0x01000193);
+    }
+    hash
+}
@@ -0,0 +1,108 @@
+use r2d2p2::aggregate::{Accumulator, AggregateFunction, AggregateResult, Fill, TDigest};
+use bson::Bson;
+
+#[cfg(test)]
+mod aggregate_tests {
+    use super::*;
+
+    fn aggregate(function: AggregateFunction, values: &[Bson]) -> Option<f64> {
+        let mut accumulator = Accumulator::new(function);
+        for value in values {
+            accumulator.push(value);
+        }
+        accumulator.result()
+    }
+
+    #[test]
+    fn test_functions_over_values() {
+        let values = [2, 4, 4, 4, 5, 5, 7, 9].map(Bson::Int32);
+
+        assert_eq!(aggregate(AggregateFunction::Count, &values), Some(8.0));
+        assert_eq!(aggregate(AggregateFunction::CountDistinct, &values), Some(5.0));
+        assert_eq!(aggregate(AggregateFunction::Avg, &values), Some(5.0));
+        assert_eq!(aggregate(AggregateFunction::Variance, &values), Some(32.0 / 7.0));
+        assert_eq!(aggregate(AggregateFunction::Stddev, &values), Some((32.0f64 / 7.0).sqrt()));
+        assert_eq!(aggregate(AggregateFunction::Median, &values), Some(4.5));
+        assert_eq!(aggregate(AggregateFunction::Percentile(0.0), &values), Some(2.0));
+        assert_eq!(aggregate(AggregateFunction::Percentile(100.0), &values), Some(9.0));
+        assert_eq!(aggregate(AggregateFunction::Percentile(75.0), &values), Some(5.5));
+        assert_eq!(aggregate(AggregateFunction::First, &values), Some(2.0));
+        assert_eq!(aggregate(AggregateFunction::Last, &values), Some(9.0));
+        assert_eq!(aggregate(AggregateFunction::Spread, &values), Some(7.0));
+
+        // Strings are counted, but ignored by everything numeric
+        let mixed = [Bson::String(String::from("a")), Bson::String(String::from("a")), Bson::Double(1.5)];
+        assert_eq!(aggregate(AggregateFunction::Count, &mixed), Some(3.0));
+        assert_eq!(aggregate(AggregateFunction::CountDistinct, &mixed), Some(2.0));
+        assert_eq!(aggregate(AggregateFunction::Sum, &mixed), Some(1.5));
+
+        // Only the values that were used count as contributing
+        let mut accumulator = Accumulator::new(AggregateFunction::Avg);
+        mixed.iter().for_each(|v| accumulator.push(v));
+        assert_eq!(accumulator.finish(), AggregateResult { value: Some(1.5), count: 1 });
+        assert_eq!(
+            accumulator.finish().to_document("AVG(n)"),
+            bson::doc! { "AVG(n)": 1.5, "count": 1i64 }
+        );
+        assert!(Accumulator::new(AggregateFunction::Min).finish().to_document("MIN").is_null("MIN"));
+
+        assert_eq!(aggregate(AggregateFunction::Count, &[]), Some(0.0));
+        assert_eq!(aggregate(AggregateFunction::Min, &[]), None);
+        assert_eq!(aggregate(AggregateFunction::Median, &[]), None);
+        assert_eq!(aggregate(AggregateFunction::Stddev, &[Bson::Int32(3)]), None);
+    }
+
+    #[test]
+    fn test_function_names() {
+        assert_eq!(AggregateFunction::from_name("avg"), Ok(AggregateFunction::Avg));
+        assert_eq!(AggregateFunction::from_name("COUNT_DISTINCT"), Ok(AggregateFunction::CountDistinct));
+        assert_eq!(AggregateFunction::from_name("PERCENTILE=99.9"), Ok(AggregateFunction::Percentile(99.9)));
+
+        // Display round-trips through from_name
+        let function = AggregateFunction::ApproxPercentile(95.0);
+        assert_eq!(function.to_string(), "APPROX_PERCENTILE=95");
+        assert_eq!(AggregateFunction::from_name(&function.to_string()), Ok(function));
+        assert_eq!(function.label("number_sold"), "APPROX_PERCENTILE(number_sold, 95)");
+
+        assert!(AggregateFunction::from_name("MODE").unwrap_err().contains("unknown aggregate MODE"));
+        assert!(AggregateFunction::from_name("PERCENTILE").is_err());
+        assert!(AggregateFunction::from_name("PERCENTILE=101").is_err());
+        assert!(AggregateFunction::from_name("SUM=3").is_err());
+    }
+
+    #[test]
+    fn test_tdigest_accuracy() {
+        let mut digest = TDigest::new(100.0);
+        assert_eq!(digest.quantile(0.5), None);
+
+        // A shuffled 0..100000, so values don't arrive sorted
+        let n = 100000u64;
+        for i in 0..n {
+            digest.push(((i * 7919) % n) as f64);
+        }
+
+        // Memory stays bounded however many values are pushed
+        assert!(digest.centroid_count() < 200);
+
+        for q in [0.01, 0.25, 0.5, 0.75, 0.95, 0.99, 0.999] {
+            let estimate = digest.quantile(q).unwrap();
+            let exact = q * (n - 1) as f64;
+            assert!((estimate - exact).abs() < n as f64 * 0.005, "q={} estimate={} exact={}", q, estimate, exact);
+        }
+        assert_eq!(digest.quantile(0.0), Some(0.0));
+        assert_eq!(digest.quantile(1.0), Some((n - 1) as f64));
+    }
+
+    #[test]
+    fn test_fill_gaps() {
+        // Gap, 2, gap, gap, null (a bucket with rows but no value), 8, gap
+        let series = [None, Some(Some(2.0)), None, None, Some(None), Some(Some(8.0)), None];
+
+        assert_eq!(Fill::Null.apply(&series), vec![None, Some(2.0), None, None, None, Some(8.0), None]);
+        assert_eq!(Fill::Value(0.0).apply(&series), vec![Some(0.0), Some(2.0), Some(0.0), Some(0.0), None, Some(8.0), Some(0.0)]);
+        assert_eq!(Fill::Previous.apply(&series), vec![None, Some(2.0), Some(2.0), Some(2.0), None, Some(8.0), Some(8.0)]);
+        // The line runs from 2 to 8 over four buckets, passing the null
+        assert_eq!(Fill::Linear.apply(&series), vec![None, Some(2.0), Some(3.5), Some(5.0), None, Some(8.0), None]);
+        assert!(Fill::Linear.apply(&[]).is_empty());
+    }
+}
@@ -12,7 +12,18 @@ mod api_tests {
 
     // Helper function to make HTTP GET requests
     fn make_request(endpoint: &str) -> Result<String, Box<dyn Error>> {
-        let url = format!("http://127.0.0.1:6969/{}", endpoint);
+        send_request(format!("GET /{} HTTP/1.1\r\nHost: 127.0.0.1:6969\r\nConnection: close\r\n\r\n", endpoint))
+    }
+
+    // Helper function to make HTTP POST requests with a body
+    fn make_post_request(endpoint: &str, body: &str) -> Result<String, Box<dyn Error>> {
+        send_request(format!(
+            "POST /{} HTTP/1.1\r\nHost: 127.0.0.1:6969\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            endpoint, body.len(), body
+        ))
+    }
+
+    fn send_request(request: String) -> Result<String, Box<dyn Error>> {
         let addr: SocketAddr = "127.0.0.1:6969".parse()?;
         let mut response = String::new();
 
@@ -26,7 +37,6 @@ mod api_tests {
         // Set a read timeout (optional, but recommended for incomplete reads)
         stream.set_read_timeout(Some(Duration::from_secs(1))).ok();
 
-        let request = format!("GET /{} HTTP/1.1\r\nHost: 127.0.0.1:6969\r\nConnection: close\r\n\r\n", endpoint);
         stream.write_all(request.as_bytes())?;
 
         match stream.read_to_string(&mut response) {
@@ -74,7 +84,7 @@ mod api_tests {
         assert!(response.is_ok());
 
         let content = response.unwrap();
-        assert!(content.contains("Success") || content.contains("rows") || content.len() > 0);
+        assert!(content.contains("Success") || content.contains("rows") || !content.is_empty());
     }
 
     fn test_api_insert_and_get_one() {
@@ -122,7 +132,7 @@ mod api_tests {
         let content = response.unwrap();
         // The response should contain data from the range
         // Check for presence of some expected values
-        assert!(content.len() > 0);
+        assert!(!content.is_empty());
     }
 
     fn test_api_aggregate_operations() {
@@ -182,7 +192,7 @@ mod api_tests {
         let all_response = make_request("LIST::ALL");
         assert!(all_response.is_ok());
         let all_content = all_response.unwrap();
-        assert!(all_content.len() > 0);
+        assert!(!all_content.is_empty());
 
         // Test LIST::ONE query
         let one_endpoint = format!("LIST::ONE::{}", timestamp);
@@ -195,7 +205,7 @@ mod api_tests {
         let agg_response = make_request("AGGREGATE::number_sold::AVG");
         assert!(agg_response.is_ok());
         let agg_content = agg_response.unwrap();
-        assert!(agg_content.len() > 0);
+        assert!(!agg_content.is_empty());
     }
 
     fn test_api_time_endpoint() {
@@ -218,7 +228,7 @@ mod api_tests {
         assert!(metadata_response.is_ok());
 
         let metadata_content = metadata_response.unwrap();
-        assert!(metadata_content.len() > 0);
+        assert!(!metadata_content.is_empty());
     }
 
     fn test_api_remove_operation() {
@@ -247,13 +257,29 @@ mod api_tests {
 
         // Verify it was removed (should return empty or error)
         let get_response = make_request(&get_endpoint);
-        if get_response.is_ok() {
-            let content = get_response.unwrap();
+        if let Ok(content) = get_response {
             // Should either be empty or not contain our data
             assert!(!content.contains("999") || content.trim().is_empty());
         }
     }
 
+    fn test_api_bulk_load() {
+        clear_and_wait_for_server();
+
+        let base_time = 1733697230000u128;
+        let body = (0..50)
+            .map(|i| format!("{{\"timestamp\": {}, \"store\": {}, \"number_sold\": {}}}", base_time + i, i, i * 2))
+            .collect::<Vec<String>>()
+            .join("\n");
+
+        let response = make_post_request("BULK", &body);
+        assert!(response.is_ok());
+        assert!(response.unwrap().contains("Loaded 50 rows"));
+
+        let sum_response = make_request("AGGREGATE::number_sold::SUM");
+        assert!(sum_response.unwrap().contains("2450"));
+    }
+
     fn test_api_load_schema() {
         clear_and_wait_for_server();
 
@@ -273,7 +299,7 @@ mod api_tests {
         for line in schema_reader.lines() {
             let line = line.unwrap();
             let column_name = line.split(',').next().unwrap();
-            assert!(content.contains(&column_name));
+            assert!(content.contains(column_name));
         }
     }
 
@@ -322,7 +348,7 @@ mod api_tests {
         for line in label_length_schema_reader.lines() {
             let line = line.unwrap();
             let column_name = line.split(',').next().unwrap();
-            assert!(!content.contains(&column_name));
+            assert!(!content.contains(column_name));
         }
 
 
@@ -367,6 +393,9 @@ mod api_tests {
         println!("✓ Passed data removal testing (\"test_api_remove_operation\")");
 
 
+        test_api_bulk_load();
+        println!("✓ Passed bulk load testing (\"test_api_bulk_load\")");
+
         // New unit tests
         test_api_load_schema();
         println!("✓ Passed schema loading testing (\"test_api_server_connection\")");
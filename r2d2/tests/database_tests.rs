@@ -1,5 +1,8 @@
-use r2d2p2::database::Database;
-use bson::doc;
+use r2d2p2::aggregate::{AggregateFunction, AggregateResult};
+use r2d2p2::database::{BatchError, BulkLoadSummary, ColumnError, Database, DuplicatePolicy, InsertError, Inserted};
+use r2d2p2::query::{Aggregate, TimeBucket};
+use r2d2p2::transform::TransformFunction;
+use bson::{doc, Bson};
 
 #[cfg(test)]
 mod database_tests {
@@ -25,7 +28,7 @@ mod database_tests {
     fn test_database_creation() {
         let db = create_test_db();
         // If the database was created without panicking, this test passes
-        assert!(true);
+        assert!(db.get_one(0).is_none());
     }
 
     #[test]
@@ -41,7 +44,7 @@ mod database_tests {
         };
 
         // Insert into database
-        db.insert_to_database(timestamp, test_doc.clone());
+        db.insert_to_database(timestamp, test_doc.clone()).unwrap();
 
         // Retrieve the document
         let retrieved = db.get_one(timestamp);
@@ -68,7 +71,7 @@ mod database_tests {
                 "number_sold": 5 * i
             };
             
-            db.insert_to_database(timestamp, doc);
+            db.insert_to_database(timestamp, doc).unwrap();
         }
 
         // Test retrieving a range of documents
@@ -92,21 +95,21 @@ mod database_tests {
                 "product": 100,
                 "number_sold": i
             };
-            db.insert_to_database(timestamp, doc);
+            db.insert_to_database(timestamp, doc).unwrap();
         }
 
         // Test aggregation functions
         let sum = db.aggregate(String::from("SUM"), String::from("number_sold"));
-        assert_eq!(sum, 15.0); // 1+2+3+4+5 = 15
+        assert_eq!(sum.unwrap().value, Some(15.0)); // 1+2+3+4+5 = 15
 
         let avg = db.aggregate(String::from("AVG"), String::from("number_sold"));
-        assert_eq!(avg, 3.0); // (1+2+3+4+5)/5 = 3
+        assert_eq!(avg.unwrap().value, Some(3.0)); // (1+2+3+4+5)/5 = 3
 
         let min = db.aggregate(String::from("MIN"), String::from("number_sold"));
-        assert_eq!(min, 1.0);
+        assert_eq!(min.unwrap().value, Some(1.0));
 
         let max = db.aggregate(String::from("MAX"), String::from("number_sold"));
-        assert_eq!(max, 5.0);
+        assert_eq!(max.unwrap().value, Some(5.0));
     }
 
     #[test]
@@ -120,7 +123,7 @@ mod database_tests {
             "product": 101,
             "number_sold": 5
         };
-        db.insert_to_database(timestamp, test_doc);
+        db.insert_to_database(timestamp, test_doc).unwrap();
 
         // Test LIST::ALL query
         let result = db.query(String::from("LIST::ALL"));
@@ -134,4 +137,1053 @@ mod database_tests {
         let result = db.query(String::from("AGGREGATE::number_sold::AVG"));
         assert!(result.contains_key("rows"));
     }
+
+    #[test]
+    fn test_bulk_load_sorted_and_fallback() {
+        let mut db = create_test_db();
+        db.insert_to_database(100, doc! { "store": 0 }).unwrap();
+
+        let rows = vec![
+            (101, doc! { "store": 1 }),
+            (102, doc! { "store": 2 }),
+            (103, doc! { "store": 3 }),
+            // Out of order: this and everything after goes through insert_to_database
+            (50, doc! { "store": 4 }),
+            (102, doc! { "store": 5 }),
+            (200, doc! { "not_a_column": 6 }),
+        ];
+
+        let summary = db.bulk_load(rows);
+        assert_eq!(summary, BulkLoadSummary {
+            appended: 3,
+            inserted: 2,
+            rejected: vec![(5, String::from("Column 'not_a_column' is not in the schema"))],
+        });
+
+        assert_eq!(db.get_one(50).unwrap().get_i32("store").unwrap(), 4);
+        assert_eq!(db.get_one(102).unwrap().get_i32("store").unwrap(), 2);
+        // The colliding row was shifted past the keys already taken, as with a normal insert
+        assert_eq!(db.get_one(104).unwrap().get_i32("store").unwrap(), 5);
+        assert!(db.get_one(200).is_none());
+        assert_eq!(db.get_range(u128::MIN, u128::MAX).len(), 6);
+    }
+
+    #[test]
+    fn test_bulk_query_with_ndjson_body() {
+        let mut db = create_test_db();
+
+        let body = "{\"timestamp\": 10, \"store\": 1, \"number_sold\": 2.5}\n\
+                    {\"timestamp\": \"11\", \"store\": 2}\n\
+                    \n\
+                    {\"timestamp\": 12, \"price\": 3}\n\
+                    not json\n\
+                    {\"timestamp\": 10, \"store\": 3}\n";
+
+        // Rows refused when loading are reported by line along with those that didn't parse
+        db.set_duplicate_policy(DuplicatePolicy::Reject).unwrap();
+        let result = db.query_with_body(String::from("BULK"), body);
+        let rows = result.get_document("rows").unwrap();
+        assert!(rows.get_str("Notice").unwrap().contains("Loaded 2 rows"));
+        assert!(rows.get_str("Notice").unwrap().contains("3 rejected"));
+        assert!(rows.get_str("line 4").unwrap().contains("price"));
+        assert!(rows.contains_key("line 5"));
+        assert_eq!(rows.get_str("line 6").unwrap(), "A row already exists at 10 (duplicate policy: reject)");
+
+        assert_eq!(db.get_one(10).unwrap().get_f64("number_sold").unwrap(), 2.5);
+        assert_eq!(db.get_one(11).unwrap().get_f64("store").unwrap(), 2.0);
+    }
+
+    #[test]
+    fn test_query_language_end_to_end() {
+        let mut db = create_test_db();
+
+        db.query(String::from("INSERT store = 1, number_sold = 4 AT 100"));
+        db.query(String::from("INSERT::store=2,number_sold=6::TIMESTAMP=200"));
+
+        let result = db.query(String::from("SELECT * AT 100"));
+        let rows = result.get_document("rows").unwrap();
+        assert_eq!(rows.get_document("100").unwrap().get_f64("number_sold").unwrap(), 4.0);
+
+        let result = db.query(String::from("SELECT * RANGE 150, 250"));
+        assert_eq!(result.get_document("rows").unwrap().len(), 1);
+
+        let result = db.query(String::from("SELECT avg(number_sold)"));
+        assert_eq!(result.get_document("rows").unwrap().get_document("AVG").unwrap().get_f64("AVG").unwrap(), 5.0);
+
+        let result = db.query(String::from("SELECT * RANGE 1"));
+        let notice = result.get_document("rows").unwrap().get_str("Notice").unwrap().to_string();
+        assert!(notice.contains("column 17"));
+
+        // Hidden queries only report success
+        let result = db.query(String::from("LIST::ALL::HIDE"));
+        assert_eq!(result.get_document("rows").unwrap().get_str("Notice").unwrap(), "Success!");
+    }
+
+    #[test]
+    fn test_column_named_hide() {
+        let mut db = Database::new(vec![String::from("HIDE")], vec![String::from("number")]);
+
+        db.query(String::from("INSERT::HIDE=5::TIMESTAMP=1"));
+        assert_eq!(db.get_one(1).unwrap().get_f64("HIDE").unwrap(), 5.0);
+
+        let result = db.query(String::from("SELECT * AT 1"));
+        assert!(result.get_document("rows").unwrap().contains_key("1"));
+    }
+
+    #[test]
+    fn test_where_filters_range_scan() {
+        let mut db = Database::new(
+            vec![String::from("store"), String::from("city"), String::from("open"), String::from("number_sold")],
+            vec![String::from("number"), String::from("string"), String::from("boolean"), String::from("number")],
+        );
+
+        db.insert_to_database(1, doc! { "store": 3, "city": "Fredericton", "open": true, "number_sold": 12.0 }).unwrap();
+        db.insert_to_database(2, doc! { "store": 3, "city": "Moncton", "open": false, "number_sold": 8.0 }).unwrap();
+        db.insert_to_database(3, doc! { "store": 4, "city": "Halifax", "open": true, "number_sold": 20.0 }).unwrap();
+        db.insert_to_database(4, doc! { "store": 3, "city": "Saint John", "number_sold": 15.0 }).unwrap();
+        db.insert_to_database(5, doc! { "store": 3, "city": "Fredericton", "open": true, "number_sold": 30.0 }).unwrap();
+
+        let mut keys = |query: &str| {
+            db.query(String::from(query)).get_document("rows").unwrap().keys().cloned().collect::<Vec<String>>()
+        };
+
+        assert_eq!(keys("SELECT * RANGE 1, 4 WHERE store = 3 AND number_sold > 10"), vec!["1", "4"]);
+        assert_eq!(keys("SELECT * WHERE city IN ('Moncton', 'Halifax')"), vec!["2", "3"]);
+        assert_eq!(keys("SELECT * WHERE number_sold BETWEEN 12 AND 20 AND NOT store = 4"), vec!["1", "4"]);
+        assert_eq!(keys("LIST::RANGE::1,5::WHERE city >= 'M'"), vec!["2", "4"]);
+
+        // Row 4 has no value for 'open', so neither the test nor its negation matches it
+        assert_eq!(keys("SELECT * WHERE open = true"), vec!["1", "3", "5"]);
+        assert_eq!(keys("SELECT * WHERE NOT open = true"), vec!["2"]);
+        assert_eq!(keys("SELECT * WHERE open = false OR store = 3"), vec!["1", "2", "4", "5"]);
+
+        assert_eq!(keys("SELECT * AT 2 WHERE open = false"), vec!["2"]);
+        assert_eq!(keys("SELECT * AT 1 WHERE open = false"), vec!["Notice"]);
+    }
+
+    #[test]
+    fn test_where_is_type_checked() {
+        let mut db = Database::new(
+            vec![String::from("store"), String::from("open")],
+            vec![String::from("number"), String::from("boolean")],
+        );
+
+        let notice = |db: &mut Database, query: &str| {
+            db.query(String::from(query)).get_document("rows").unwrap().get_str("Notice").unwrap().to_string()
+        };
+
+        assert!(notice(&mut db, "SELECT * WHERE stor = 3").contains("'stor' is not in the schema"));
+        assert!(notice(&mut db, "SELECT * WHERE store = '3'").contains("number column"));
+        assert!(notice(&mut db, "SELECT * WHERE store IN (1, true)").contains("boolean true"));
+        assert!(notice(&mut db, "SELECT * WHERE open > false").contains("= or !="));
+        assert!(notice(&mut db, "SELECT * WHERE open BETWEEN true AND false").contains("BETWEEN"));
+    }
+
+    #[test]
+    fn test_aggregate_time_buckets() {
+        let mut db = create_test_db();
+
+        // Two rows a minute, ten minutes from 12:00 UTC
+        let noon : u128 = 1733745600000;
+        for i in 0..20u128 {
+            db.insert_to_database(noon + i * 30000, doc! { "store": (i % 2) as i32, "number_sold": i as f64 }).unwrap();
+        }
+
+        let five_minutes : u128 = 5 * 60 * 1000;
+        let by_epoch = db.aggregate_buckets(AggregateFunction::Avg, "number_sold", noon, noon + 2 * five_minutes,
+            TimeBucket { width: five_minutes, origin: 0 }, None).unwrap();
+
+        assert_eq!(by_epoch.keys().cloned().collect::<Vec<String>>(),
+            vec![noon.to_string(), (noon + five_minutes).to_string()]);
+        assert_eq!(by_epoch.get_document(noon.to_string()).unwrap().get_f64("AVG(number_sold)").unwrap(), 4.5);
+
+        // Aligned to 12:02:30 instead, the first bucket starts at 11:57:30 and only catches 5 rows
+        let by_origin = db.aggregate_buckets(AggregateFunction::Sum, "number_sold", noon, noon + 2 * five_minutes,
+            TimeBucket { width: five_minutes, origin: noon + five_minutes / 2 }, None).unwrap();
+        let sums = by_origin.values().map(|row| row.as_document().unwrap().get_f64("SUM(number_sold)").unwrap())
+            .collect::<Vec<f64>>();
+        assert_eq!(sums, vec![10.0, 95.0, 85.0]);
+
+        // Through the query language, with range bounds and a filter
+        let result = db.query(format!(
+            "SELECT MAX(number_sold) RANGE {}, {} WHERE store = 0 GROUP BY time(5m)", noon, noon + five_minutes
+        ));
+        assert_eq!(result.get_array("labels").unwrap()[0].as_str().unwrap(), "MAX(number_sold)");
+        let rows = result.get_document("rows").unwrap();
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows.get_document((noon + five_minutes).to_string()).unwrap().get_f64("MAX(number_sold)").unwrap(), 10.0);
+
+        let result = db.query(String::from("SELECT MODE(number_sold) GROUP BY time(5m)"));
+        assert!(result.get_document("rows").unwrap().get_str("Notice").unwrap().contains("unknown aggregate MODE"));
+    }
+
+    #[test]
+    fn test_aggregate_group_by_tags() {
+        let mut db = Database::new(
+            vec![String::from("city"), String::from("store"), String::from("number_sold")],
+            vec![String::from("string"), String::from("number"), String::from("number")],
+        );
+
+        let hour : u128 = 3600000;
+        db.insert_to_database(0, doc! { "city": "Moncton", "store": 1.0, "number_sold": 5.0 }).unwrap();
+        db.insert_to_database(1, doc! { "city": "Halifax", "store": 2.0, "number_sold": 7.0 }).unwrap();
+        db.insert_to_database(2, doc! { "city": "Moncton", "store": 3.0, "number_sold": 1.0 }).unwrap();
+        db.insert_to_database(hour, doc! { "city": "Moncton", "store": 1.0, "number_sold": 2.0 }).unwrap();
+        db.insert_to_database(hour + 1, doc! { "store": 4.0, "number_sold": 10.0 }).unwrap();
+        db.insert_to_database(2 * hour, doc! { "city": "Halifax", "store": 2.0, "number_sold": 100.0 }).unwrap();
+
+        // One row per city, in order of first appearance, with no Timestamp column
+        let result = db.query(String::from("SELECT SUM(number_sold) RANGE 0, 3600001 GROUP BY city"));
+        assert_eq!(result.get_array("labels").unwrap().len(), 3);
+        let rows = result.get_document("rows").unwrap();
+        assert_eq!(rows.keys().cloned().collect::<Vec<String>>(), vec!["city=Moncton", "city=Halifax", "city=null"]);
+        assert_eq!(rows.get_document("city=Moncton").unwrap().get_f64("SUM(number_sold)").unwrap(), 8.0);
+        assert_eq!(rows.get_document("city=Moncton").unwrap().get_str("city").unwrap(), "Moncton");
+
+        // Composed with time buckets, every combination gets its own row
+        let result = db.query(String::from("SELECT AVG(number_sold) GROUP BY time(1h), city, store"));
+        let labels = result.get_array("labels").unwrap().iter()
+            .map(|l| l.as_str().unwrap().to_string()).collect::<Vec<String>>();
+        assert_eq!(labels, vec!["time", "city", "store", "AVG(number_sold)", "count"]);
+
+        let rows = result.get_document("rows").unwrap();
+        assert_eq!(rows.len(), 6);
+        let last = rows.get_document("time=7200000,city=Halifax,store=2").unwrap();
+        assert_eq!(last.get_str("time").unwrap(), "7200000");
+        assert_eq!(last.get_f64("AVG(number_sold)").unwrap(), 100.0);
+
+        // Bucket starts past i64::MAX are reported whole
+        db.insert_to_database(u128::MAX, doc! { "city": "Moncton", "number_sold": 1.0 }).unwrap();
+        let result = db.query(String::from("SELECT COUNT(number_sold) RANGE 3600002, 340282366920938463463374607431768211455 GROUP BY time(1ms), city"));
+        let rows = result.get_document("rows").unwrap();
+        let top = rows.get_document(format!("time={},city=Moncton", u128::MAX)).unwrap();
+        assert_eq!(top.get_str("time").unwrap(), u128::MAX.to_string());
+
+        let groups = db.aggregate_groups(&[Aggregate::new(AggregateFunction::Max, "number_sold")], 0, u128::MAX, None, &[String::from("store")], None, None).unwrap();
+        assert_eq!(groups.get_document("store=1").unwrap().get_f64("MAX(number_sold)").unwrap(), 5.0);
+
+        let result = db.query(String::from("SELECT SUM(number_sold) GROUP BY town"));
+        assert!(result.get_document("rows").unwrap().get_str("Notice").unwrap().contains("'town'"));
+    }
+
+    #[test]
+    fn test_aggregate_over_range() {
+        let mut db = create_test_db();
+
+        let hour : u128 = 3600000;
+        db.insert_to_database(0, doc! { "store": 1, "number_sold": 100 }).unwrap();
+        db.insert_to_database(hour, doc! { "store": 1, "number_sold": 2 }).unwrap();
+        db.insert_to_database(hour + 1, doc! { "store": 2 }).unwrap();
+        db.insert_to_database(hour + 2, doc! { "store": 2, "number_sold": 4 }).unwrap();
+        db.insert_to_database(2 * hour, doc! { "store": 1, "number_sold": 9 }).unwrap();
+
+        let last_hour = |function: AggregateFunction| {
+            db.aggregate_range(function, "number_sold", hour, 2 * hour, None).value.unwrap()
+        };
+        assert_eq!(last_hour(AggregateFunction::Max), 9.0);
+        assert_eq!(last_hour(AggregateFunction::Min), 2.0);
+        assert_eq!(last_hour(AggregateFunction::Sum), 15.0);
+
+        // The row without number_sold doesn't count towards the average, and neither does
+        // anything outside the range
+        assert_eq!(last_hour(AggregateFunction::Avg), 5.0);
+        assert_eq!(db.aggregate(String::from("AVG"), String::from("number_sold")).unwrap().value, Some(28.75));
+
+        let result = db.query(format!("AGGREGATE::number_sold::SUM::{},{}", hour, 2 * hour - 1));
+        assert_eq!(result.get_document("rows").unwrap().get_document("SUM").unwrap().get_f64("SUM").unwrap(), 6.0);
+
+        let result = db.query(format!("SELECT MAX(number_sold) RANGE 0, {} WHERE store = 2", 2 * hour));
+        assert_eq!(result.get_document("rows").unwrap().get_document("MAX").unwrap().get_f64("MAX").unwrap(), 4.0);
+    }
+
+    #[test]
+    fn test_aggregate_function_library() {
+        let mut db = Database::new(
+            vec![String::from("city"), String::from("number_sold")],
+            vec![String::from("string"), String::from("number")],
+        );
+
+        let hour : u128 = 3600000;
+        for (i, (city, sold)) in [("Moncton", 8.0), ("Halifax", 1.0), ("Moncton", 3.0), ("Saint John", 6.0)].iter().enumerate() {
+            db.insert_to_database(i as u128, doc! { "city": *city, "number_sold": *sold }).unwrap();
+        }
+        db.insert_to_database(hour, doc! { "city": "Halifax", "number_sold": 20.0 }).unwrap();
+        db.insert_to_database(hour + 1, doc! { "city": "Halifax" }).unwrap();
+
+        let mut value = |query: &str, column: &str| {
+            let result = db.query(String::from(query));
+            result.get_document("rows").unwrap().get_document(column).unwrap().get_f64(column).unwrap()
+        };
+        assert_eq!(value("SELECT COUNT(city)", "COUNT"), 6.0);
+        assert_eq!(value("SELECT COUNT(number_sold)", "COUNT"), 5.0);
+        assert_eq!(value("SELECT COUNT(DISTINCT city) RANGE 0, 3", "COUNT_DISTINCT"), 3.0);
+        assert_eq!(value("SELECT MEDIAN(number_sold) RANGE 0, 3", "MEDIAN"), 4.5);
+        assert_eq!(value("SELECT PERCENTILE(number_sold, 100)", "PERCENTILE=100"), 20.0);
+        assert_eq!(value("SELECT FIRST(number_sold) WHERE city = 'Halifax'", "FIRST"), 1.0);
+        assert_eq!(value("SELECT LAST(number_sold) RANGE 0, 3", "LAST"), 6.0);
+        assert_eq!(value("SELECT SPREAD(number_sold)", "SPREAD"), 19.0);
+        assert!((value("AGGREGATE::number_sold::STDDEV::0,3", "STDDEV") - (29.0f64 / 3.0).sqrt()).abs() < 1e-12);
+
+        // Per group, COUNT also counts rows whose value isn't a number
+        let result = db.query(String::from("SELECT COUNT(city) GROUP BY time(1h)"));
+        let counts = result.get_document("rows").unwrap().values()
+            .map(|row| row.as_document().unwrap().get_f64("COUNT(city)").unwrap())
+            .collect::<Vec<f64>>();
+        assert_eq!(counts, vec![4.0, 2.0]);
+
+        let result = db.query(String::from("SELECT APPROX_PERCENTILE(number_sold, 50) GROUP BY city"));
+        let halifax = result.get_document("rows").unwrap().get_document("city=Halifax").unwrap();
+        assert_eq!(halifax.get_f64("APPROX_PERCENTILE(number_sold, 50)").unwrap(), 10.5);
+
+        // Unknown operations are an error rather than a sum
+        assert!(db.aggregate(String::from("MODE"), String::from("number_sold")).is_err());
+        let result = db.query(String::from("AGGREGATE::number_sold::MODE"));
+        assert!(result.get_document("rows").unwrap().get_str("Notice").unwrap().contains("unknown aggregate MODE"));
+    }
+
+    #[test]
+    fn test_aggregate_results_are_typed() {
+        let mut db = create_test_db();
+        db.insert_to_database(1, doc! { "store": 1, "number_sold": 2 }).unwrap();
+        db.insert_to_database(2, doc! { "store": 1, "number_sold": 3 }).unwrap();
+        db.insert_to_database(3, doc! { "store": 2 }).unwrap();
+
+        // Averages keep their fraction, and say how many rows went into them
+        let result = db.query(String::from("SELECT AVG(number_sold)"));
+        let labels = result.get_array("labels").unwrap().iter()
+            .map(|l| l.as_str().unwrap().to_string()).collect::<Vec<String>>();
+        assert_eq!(labels, vec!["AVG", "count"]);
+        let row = result.get_document("rows").unwrap().get_document("AVG").unwrap();
+        assert_eq!(row.get_f64("AVG").unwrap(), 2.5);
+        assert_eq!(row.get_i64("count").unwrap(), 2);
+
+        // No matching rows is null, not 0 or f64::MAX
+        let result = db.query(String::from("AGGREGATE::number_sold::MIN::WHERE store = 2"));
+        let row = result.get_document("rows").unwrap().get_document("MIN").unwrap();
+        assert!(row.is_null("MIN"));
+        assert_eq!(row.get_i64("count").unwrap(), 0);
+
+        let empty = db.aggregate_range(AggregateFunction::Max, "number_sold", 10, 20, None);
+        assert_eq!(empty, AggregateResult { value: None, count: 0 });
+
+        let result = db.query(String::from("SELECT SUM(number_sold) GROUP BY store"));
+        let row = result.get_document("rows").unwrap().get_document("store=1").unwrap();
+        assert_eq!(row.get_f64("SUM(number_sold)").unwrap(), 5.0);
+        assert_eq!(row.get_i64("count").unwrap(), 2);
+    }
+
+    #[test]
+    fn test_multiple_aggregates_in_one_query() {
+        let mut db = create_test_db();
+        db.insert_to_database(1, doc! { "store": 1, "product": 10, "number_sold": 4 }).unwrap();
+        db.insert_to_database(2, doc! { "store": 2, "product": 30, "number_sold": 1 }).unwrap();
+        db.insert_to_database(3, doc! { "store": 1, "product": 20 }).unwrap();
+        db.insert_to_database(4, doc! { "store": 2, "number_sold": 7 }).unwrap();
+
+        let result = db.query(String::from("SELECT MIN(number_sold), MAX(number_sold), AVG(product) RANGE 1, 3"));
+        let labels = result.get_array("labels").unwrap().iter()
+            .map(|l| l.as_str().unwrap().to_string()).collect::<Vec<String>>();
+        assert_eq!(labels, vec!["MIN(number_sold)", "MAX(number_sold)", "AVG(product)", "count"]);
+
+        // One row, with a column per aggregate; the count is rows that fed any of them
+        let rows = result.get_document("rows").unwrap();
+        assert_eq!(rows.len(), 1);
+        let row = rows.get_document("MIN(number_sold)").unwrap();
+        assert_eq!(row.get_f64("MIN(number_sold)").unwrap(), 1.0);
+        assert_eq!(row.get_f64("MAX(number_sold)").unwrap(), 4.0);
+        assert_eq!(row.get_f64("AVG(product)").unwrap(), 20.0);
+        assert_eq!(row.get_i64("count").unwrap(), 3);
+
+        let accumulators = db.aggregate_many(
+            &[Aggregate::new(AggregateFunction::Sum, "number_sold"), Aggregate::new(AggregateFunction::Count, "product")],
+            0, u128::MAX, None,
+        );
+        let results = accumulators.results();
+        assert_eq!((results[0].value, results[0].count), (Some(12.0), 3));
+        assert_eq!((results[1].value, results[1].count), (Some(3.0), 3));
+
+        // Grouped, each group gets every column
+        let result = db.query(String::from("AGGREGATE::number_sold::MIN,MAX::0,10"));
+        assert_eq!(result.get_document("rows").unwrap().get_document("MIN(number_sold)").unwrap().get_f64("MAX(number_sold)").unwrap(), 7.0);
+
+        let result = db.query(String::from("SELECT SUM(number_sold), COUNT(product) GROUP BY store"));
+        let store_two = result.get_document("rows").unwrap().get_document("store=2").unwrap();
+        assert_eq!(store_two.get_f64("SUM(number_sold)").unwrap(), 8.0);
+        assert_eq!(store_two.get_f64("COUNT(product)").unwrap(), 1.0);
+        assert_eq!(store_two.get_i64("count").unwrap(), 2);
+
+        let result = db.query(String::from("SELECT SUM(number_sold), MAX(price) GROUP BY store"));
+        assert!(result.get_document("rows").unwrap().get_str("Notice").unwrap().contains("'price'"));
+    }
+
+    #[test]
+    fn test_counter_rates() {
+        let mut db = Database::new(
+            vec![String::from("host"), String::from("requests")],
+            vec![String::from("string"), String::from("number")],
+        );
+
+        // A counter on host a that resets after 3s, with host b's readings in between
+        for (key, host, requests) in [(0, "a", 10.0), (1000, "a", 30.0), (1500, "b", 900.0), (2000, "a", 60.0),
+                                      (4000, "a", 5.0), (5000, "a", 25.0)] {
+            db.insert_to_database(key, doc! { "host": host, "requests": requests }).unwrap();
+        }
+        db.insert_to_database(6000, doc! { "host": "a" }).unwrap();
+
+        let values = |result: &bson::Document, label: &str| result.get_document("rows").unwrap().iter()
+            .map(|(key, row)| (key.clone(), row.as_document().unwrap().get_f64(label).unwrap()))
+            .collect::<Vec<(String, f64)>>();
+
+        // Per row, keyed by the later reading, skipping the row without a reading
+        let result = db.query(String::from("SELECT RATE(requests, 1s) WHERE host = 'a'"));
+        assert_eq!(result.get_array("labels").unwrap()[0].as_str().unwrap(), "RATE(requests, 1000ms)");
+        assert_eq!(values(&result, "RATE(requests, 1000ms)"), vec![
+            (String::from("1000"), 20.0), (String::from("2000"), 30.0),
+            (String::from("4000"), 2.5), (String::from("5000"), 20.0),
+        ]);
+
+        let result = db.query(String::from("SELECT NON_NEGATIVE_DERIVATIVE(requests, 1s) RANGE 1000, 5000 WHERE host = 'a'"));
+        assert_eq!(values(&result, "NON_NEGATIVE_DERIVATIVE(requests, 1000ms)"), vec![
+            (String::from("2000"), 30.0), (String::from("5000"), 20.0),
+        ]);
+
+        // Per bucket: total change over total time of the pairs ending in each bucket
+        let rows = db.transform_range(
+            TransformFunction::Difference, "requests", 0, u128::MAX,
+            Some(TimeBucket { width: 3000, origin: 0 }), None,
+        ).unwrap();
+        let differences = rows.values().map(|row| row.as_document().unwrap().get_f64("DIFFERENCE(requests)").unwrap())
+            .collect::<Vec<f64>>();
+        assert_eq!(differences, vec![50.0, -35.0]);
+
+        let result = db.query(String::from("SELECT RATE(requests, 1s) WHERE host = 'a' GROUP BY time(3s)"));
+        assert_eq!(values(&result, "RATE(requests, 1000ms)"), vec![
+            (String::from("0"), 25.0), (String::from("3000"), 25.0 / 3.0),
+        ]);
+
+        let result = db.query(String::from("SELECT RATE(bytes)"));
+        assert!(result.get_document("rows").unwrap().get_str("Notice").unwrap().contains("'bytes'"));
+    }
+
+    #[test]
+    fn test_smoothing_sensor_data() {
+        let mut db = Database::new(
+            vec![String::from("sensor"), String::from("temperature")],
+            vec![String::from("string"), String::from("number")],
+        );
+
+        let minute : u128 = 60000;
+        for (i, temperature) in [20.0, 22.0, 30.0, 24.0, 26.0].iter().enumerate() {
+            db.insert_to_database(i as u128 * minute, doc! { "sensor": "a", "temperature": *temperature }).unwrap();
+        }
+        db.insert_to_database(10, doc! { "sensor": "b", "temperature": 100.0 }).unwrap();
+
+        let series = |result: &bson::Document| result.get_document("rows").unwrap().iter()
+            .map(|(key, row)| (key.parse::<u128>().unwrap(), row.as_document().unwrap().iter().next().unwrap().1.as_f64().unwrap()))
+            .collect::<Vec<(u128, f64)>>();
+
+        // Same shape as any row listing: keyed by timestamp, one column named after the function
+        let result = db.query(String::from("SELECT MOVING_AVERAGE(temperature, 3) WHERE sensor = 'a'"));
+        assert_eq!(result.get_array("labels").unwrap()[0].as_str().unwrap(), "MOVING_AVERAGE(temperature, 3)");
+        assert_eq!(series(&result), vec![(2 * minute, 24.0), (3 * minute, 76.0 / 3.0), (4 * minute, 80.0 / 3.0)]);
+
+        let result = db.query(String::from("SELECT MOVING_AVERAGE(temperature, 2m) RANGE 0, 120000 WHERE sensor = 'a'"));
+        assert_eq!(series(&result), vec![(0, 20.0), (minute, 21.0), (2 * minute, 26.0)]);
+
+        let result = db.query(String::from("SELECT EWMA(temperature, 0.5) WHERE sensor = 'a'"));
+        assert_eq!(series(&result).last(), Some(&(4 * minute, 25.375)));
+
+        let result = db.query(String::from("SELECT CUMULATIVE_SUM(temperature)"));
+        assert_eq!(series(&result)[..3], [(0, 20.0), (10, 120.0), (minute, 142.0)]);
+
+        let rows = db.transform_range(
+            TransformFunction::CumulativeSum, "temperature", 0, u128::MAX, Some(TimeBucket { width: minute, origin: 0 }), None,
+        );
+        assert!(rows.is_err());
+    }
+
+    #[test]
+    fn test_fill_empty_buckets() {
+        let mut db = Database::new(
+            vec![String::from("sensor"), String::from("temperature")],
+            vec![String::from("string"), String::from("number")],
+        );
+
+        let minute : u128 = 60000;
+        db.insert_to_database(minute, doc! { "sensor": "a", "temperature": 10.0 }).unwrap();
+        db.insert_to_database(4 * minute, doc! { "sensor": "a", "temperature": 16.0 }).unwrap();
+        db.insert_to_database(2 * minute, doc! { "sensor": "b", "temperature": 5.0 }).unwrap();
+
+        let column = |result: &bson::Document, name: &str| result.get_document("rows").unwrap().values()
+            .map(|row| row.as_document().unwrap().get(name).unwrap().as_f64())
+            .collect::<Vec<Option<f64>>>();
+
+        // Without FILL only buckets with rows are listed
+        let query = "SELECT AVG(temperature) WHERE sensor = 'a' GROUP BY time(1m)";
+        assert_eq!(column(&db.query(String::from(query)), "AVG(temperature)"), vec![Some(10.0), Some(16.0)]);
+        let result = db.query(format!("{} FILL(NONE)", query));
+        assert_eq!(column(&result, "AVG(temperature)"), vec![Some(10.0), Some(16.0)]);
+
+        let result = db.query(format!("{} FILL(NULL)", query));
+        assert_eq!(result.get_document("rows").unwrap().keys().cloned().collect::<Vec<String>>(),
+            (1..5).map(|m| (m * minute).to_string()).collect::<Vec<String>>());
+        assert_eq!(column(&result, "AVG(temperature)"), vec![Some(10.0), None, None, Some(16.0)]);
+        assert_eq!(result.get_document("rows").unwrap().get_document((2 * minute).to_string()).unwrap().get_i64("count").unwrap(), 0);
+
+        let result = db.query(format!("{} FILL(-1)", query));
+        assert_eq!(column(&result, "AVG(temperature)"), vec![Some(10.0), Some(-1.0), Some(-1.0), Some(16.0)]);
+
+        let result = db.query(format!("{} FILL(PREVIOUS)", query));
+        assert_eq!(column(&result, "AVG(temperature)"), vec![Some(10.0), Some(10.0), Some(10.0), Some(16.0)]);
+
+        let result = db.query(format!("{} FILL(LINEAR)", query));
+        assert_eq!(column(&result, "AVG(temperature)"), vec![Some(10.0), Some(12.0), Some(14.0), Some(16.0)]);
+
+        // A bounded RANGE is filled to its ends; linear has nothing to draw a line to there
+        let result = db.query(format!(
+            "SELECT AVG(temperature) RANGE 30000, {} WHERE sensor = 'a' GROUP BY time(1m) FILL(LINEAR)", 5 * minute
+        ));
+        assert_eq!(column(&result, "AVG(temperature)"), vec![None, Some(10.0), Some(12.0), Some(14.0), Some(16.0), None]);
+
+        // Every tag gets every bucket
+        let result = db.query(String::from("SELECT MAX(temperature) GROUP BY time(1m), sensor FILL(0)"));
+        let rows = result.get_document("rows").unwrap();
+        assert_eq!(rows.len(), 8);
+        let sensors = rows.values().map(|row| row.as_document().unwrap().get_str("sensor").unwrap().to_string())
+            .collect::<Vec<String>>();
+        assert_eq!(sensors[..4], ["a", "b", "a", "b"]);
+        assert_eq!(column(&result, "MAX(temperature)"),
+            vec![Some(10.0), Some(0.0), Some(0.0), Some(5.0), Some(0.0), Some(0.0), Some(16.0), Some(0.0)]);
+
+        let result = db.query(String::from("SELECT AVG(temperature) RANGE 0, 86400000 GROUP BY time(1ms) FILL(NULL)"));
+        assert!(result.get_document("rows").unwrap().get_str("Notice").unwrap().contains("FILL would produce more than"));
+    }
+
+    #[test]
+    fn test_fill_empty_bounded_range() {
+        let mut db = create_test_db();
+
+        // Every bucket of a bounded RANGE is listed even when it has no rows at all
+        let result = db.query(String::from("SELECT AVG(number_sold) RANGE 1000, 5000 GROUP BY time(1s) FILL(0)"));
+        let rows = result.get_document("rows").unwrap();
+        assert_eq!(rows.keys().cloned().collect::<Vec<String>>(), vec!["1000", "2000", "3000", "4000", "5000"]);
+        for row in rows.values() {
+            let row = row.as_document().unwrap();
+            assert_eq!(row.get_f64("AVG(number_sold)").unwrap(), 0.0);
+            assert_eq!(row.get_i64("count").unwrap(), 0);
+        }
+
+        // Grouped by a tag there are no groups to fill
+        let result = db.query(String::from("SELECT AVG(number_sold) RANGE 1000, 5000 GROUP BY time(1s), store FILL(0)"));
+        assert!(result.get_document("rows").unwrap().is_empty());
+
+        // A first bucket that would begin before the epoch begins at 0, and the rest stay on the grid
+        let result = db.query(String::from("SELECT AVG(number_sold) RANGE 1, 25 GROUP BY time(10ms, 3) FILL(0)"));
+        assert_eq!(result.get_document("rows").unwrap().keys().cloned().collect::<Vec<String>>(), vec!["0", "3", "13", "23"]);
+
+        db.insert_to_database(2500, doc! { "store": 1.0, "number_sold": 4.0 }).unwrap();
+        let result = db.query(String::from("SELECT AVG(number_sold) RANGE 1000, 5000 GROUP BY time(1s) FILL(NULL)"));
+        assert_eq!(result.get_document("rows").unwrap().len(), 5);
+    }
+
+    #[test]
+    fn test_paging() {
+        let mut db = Database::new(
+            vec![String::from("store"), String::from("number_sold")],
+            vec![String::from("number"), String::from("number")],
+        );
+        for i in 1..=10 {
+            db.insert_to_database(i * 10, doc! { "store": (i % 2) as f64, "number_sold": i as f64 }).unwrap();
+        }
+
+        let keys = |result: &bson::Document| result.get_document("rows").unwrap().keys()
+            .map(|key| key.parse::<u128>().unwrap())
+            .collect::<Vec<u128>>();
+
+        let result = db.query(String::from("SELECT * LIMIT 3"));
+        assert_eq!(keys(&result), vec![10, 20, 30]);
+        let token = result.get_str("next").unwrap().to_string();
+
+        // The next page starts after the last row of the one before
+        let result = db.query(format!("SELECT * LIMIT 3 AFTER '{}'", token));
+        assert_eq!(keys(&result), vec![40, 50, 60]);
+        let result = db.query(format!("SELECT * LIMIT 3 OFFSET 1 AFTER '{}'", token));
+        assert_eq!(keys(&result), vec![50, 60, 70]);
+
+        // Descending, from the end of a range whose bound falls between rows
+        let result = db.query(String::from("SELECT * RANGE 15, 75 ORDER BY time DESC LIMIT 4"));
+        assert_eq!(keys(&result), vec![70, 60, 50, 40]);
+        let token = result.get_str("next").unwrap().to_string();
+        let result = db.query(format!("SELECT * RANGE 15, 75 ORDER BY time DESC LIMIT 4 AFTER '{}'", token));
+        assert_eq!(keys(&result), vec![30, 20]);
+        assert!(result.get_str("next").is_err());
+
+        // WHERE is applied before LIMIT and OFFSET count rows
+        let result = db.query(String::from("SELECT * WHERE store = 0 ORDER BY time DESC OFFSET 1"));
+        assert_eq!(keys(&result), vec![80, 60, 40, 20]);
+        assert!(result.get_str("next").is_err());
+
+        // A last page that is exactly full has no rows left to continue to
+        let result = db.query(String::from("SELECT * RANGE 10, 30 LIMIT 3"));
+        assert_eq!(keys(&result), vec![10, 20, 30]);
+        assert!(result.get_str("next").is_err());
+
+        assert!(keys(&db.query(String::from("SELECT * LIMIT 0"))).is_empty());
+        assert_eq!(keys(&db.query(String::from("SELECT * ORDER BY time DESC"))).len(), 10);
+
+        let result = db.query(String::from("SELECT * AFTER 'nonsense'"));
+        assert!(result.get_document("rows").unwrap().get_str("Notice").unwrap().contains("not a continuation token"));
+    }
+
+    #[test]
+    fn test_column_projection() {
+        let mut db = Database::new(
+            vec![String::from("store"), String::from("product"), String::from("number_sold")],
+            vec![String::from("number"), String::from("number"), String::from("number")],
+        );
+        for i in 1..=4 {
+            db.insert_to_database(i, doc! { "store": i as f64, "product": 7.0, "number_sold": (i * 10) as f64 }).unwrap();
+        }
+
+        // Labels and rows follow the order the columns are listed in
+        let result = db.query(String::from("SELECT number_sold, store RANGE 2, 3"));
+        assert_eq!(result.get_array("labels").unwrap().iter().map(|l| l.as_str().unwrap()).collect::<Vec<&str>>(),
+            vec!["number_sold", "store"]);
+        let rows = result.get_document("rows").unwrap();
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows.get_document("2").unwrap(), &doc! { "number_sold": 20.0, "store": 2.0 });
+
+        // Filtering on a column that isn't listed still works
+        let result = db.query(String::from("SELECT store WHERE number_sold > 25 ORDER BY time DESC LIMIT 1"));
+        assert_eq!(result.get_document("rows").unwrap(), &doc! { "4": { "store": 4.0 } });
+        assert!(result.get_str("next").is_ok());
+
+        let result = db.query(String::from("LIST::ONE::3::COLUMNS=product"));
+        assert_eq!(result.get_document("rows").unwrap(), &doc! { "3": { "product": 7.0 } });
+
+        let result = db.query(String::from("SELECT store, price"));
+        assert_eq!(result.get_document("rows").unwrap().get_str("Notice").unwrap(), "Column 'price' is not in the schema");
+        let result = db.query(String::from("LIST::ALL::COLUMNS=Store"));
+        assert_eq!(result.get_document("rows").unwrap().get_str("Notice").unwrap(), "Column 'Store' is not in the schema");
+    }
+
+    #[test]
+    fn test_latest_rows() {
+        let mut db = Database::new(
+            vec![String::from("host"), String::from("cpu")],
+            vec![String::from("string"), String::from("number")],
+        );
+
+        let keys = |rows: &bson::Document| rows.keys().map(|key| key.parse::<u128>().unwrap()).collect::<Vec<u128>>();
+        assert!(db.get_latest(1, u128::MAX, None, None).is_empty());
+
+        for (key, host) in [(10, "a"), (20, "b"), (30, "a"), (40, "c"), (50, "a")] {
+            db.insert_to_database(key, doc! { "host": host, "cpu": key as f64 / 100.0 }).unwrap();
+        }
+        db.insert_to_database(60, doc! { "cpu": 0.9 }).unwrap();
+
+        assert_eq!(keys(&db.get_latest(1, u128::MAX, None, None)), vec![60]);
+        assert_eq!(keys(&db.get_latest(3, u128::MAX, None, None)), vec![60, 50, 40]);
+        // Before is exclusive, and needn't be a key
+        assert_eq!(keys(&db.get_latest(2, 50, None, None)), vec![40, 30]);
+        assert_eq!(keys(&db.get_latest(2, 45, None, None)), vec![40, 30]);
+        assert!(db.get_latest(1, 10, None, None).is_empty());
+        assert!(db.get_latest(0, u128::MAX, None, None).is_empty());
+
+        // Per host, newest first overall; the row without a host counts as null
+        assert_eq!(keys(&db.get_latest(1, u128::MAX, None, Some("host"))), vec![60, 50, 40, 20]);
+        assert_eq!(keys(&db.get_latest(2, 45, None, Some("host"))), vec![40, 30, 20, 10]);
+
+        // Through the query language; timestamps in the data are far in the past, so now is after all of them
+        let result = db.query(String::from("LATEST"));
+        assert_eq!(keys(result.get_document("rows").unwrap()), vec![60]);
+        let result = db.query(String::from("LATEST 2 WHERE host = 'a'"));
+        assert_eq!(keys(result.get_document("rows").unwrap()), vec![50, 30]);
+        let result = db.query(String::from("LATEST BEFORE 41 WHERE cpu < 0.45 BY host"));
+        assert_eq!(keys(result.get_document("rows").unwrap()), vec![40, 30, 20]);
+
+        let result = db.query(String::from("LATEST BY region"));
+        assert_eq!(result.get_document("rows").unwrap().get_str("Notice").unwrap(), "Column 'region' is not in the schema");
+    }
+
+    #[test]
+    fn test_as_of_lookups() {
+        let mut db = Database::new(
+            vec![String::from("sensor"), String::from("temperature")],
+            vec![String::from("string"), String::from("number")],
+        );
+
+        let key = |found: Option<(u128, bson::Document)>| found.map(|(key, _)| key);
+        assert_eq!(key(db.get_nearest(5, None)), None);
+
+        for (k, sensor) in [(100, "a"), (200, "b"), (300, "a"), (400, "b")] {
+            db.insert_to_database(k, doc! { "sensor": sensor, "temperature": k as f64 / 10.0 }).unwrap();
+        }
+
+        assert_eq!(db.get_at_or_before(250, None), Some((200, doc! { "sensor": "b", "temperature": 20.0 })));
+        assert_eq!(key(db.get_at_or_before(300, None)), Some(300));
+        assert_eq!(key(db.get_at_or_before(99, None)), None);
+        assert_eq!(key(db.get_at_or_before(1000, None)), Some(400));
+        assert_eq!(key(db.get_at_or_after(250, None)), Some(300));
+        assert_eq!(key(db.get_at_or_after(401, None)), None);
+        assert_eq!(key(db.get_at_or_after(0, None)), Some(100));
+
+        assert_eq!(key(db.get_nearest(260, None)), Some(300));
+        assert_eq!(key(db.get_nearest(240, None)), Some(200));
+        // A tie goes to the earlier row
+        assert_eq!(key(db.get_nearest(250, None)), Some(200));
+
+        // Rows further away than the tolerance don't count
+        assert_eq!(key(db.get_at_or_before(250, Some(49))), None);
+        assert_eq!(key(db.get_at_or_before(250, Some(50))), Some(200));
+        assert_eq!(key(db.get_nearest(500, Some(99))), None);
+        assert_eq!(key(db.get_nearest(500, Some(100))), Some(400));
+
+        // Through the query language, skipping rows WHERE doesn't match
+        let mut rows = |query: &str| db.query(String::from(query)).get_document("rows").unwrap().clone();
+        assert_eq!(rows("SELECT * AT OR BEFORE 250 WHERE sensor = 'a'").keys().collect::<Vec<&String>>(), vec!["100"]);
+        assert_eq!(rows("SELECT temperature AT OR AFTER 250"), doc! { "300": { "temperature": 30.0 } });
+        assert_eq!(rows("SELECT * NEAREST 390 WITHIN 5ms WHERE sensor = 'b'").get_str("Notice").unwrap(),
+            "Requested value could not be found.");
+        assert_eq!(rows("SELECT * NEAREST 390 WITHIN 100ms WHERE sensor = 'a'").keys().collect::<Vec<&String>>(), vec!["300"]);
+    }
+
+    #[test]
+    fn test_range_and_predicate_delete() {
+        let mut db = Database::new(
+            vec![String::from("store"), String::from("number_sold")],
+            vec![String::from("number"), String::from("number")],
+        );
+        assert_eq!(db.time_bounds(), None);
+        for i in 1..=10 {
+            db.insert_to_database(i * 10, doc! { "store": (i % 3) as f64, "number_sold": i as f64 }).unwrap();
+        }
+        assert_eq!(db.time_bounds(), Some((10, 100)));
+
+        let removed = |result: bson::Document| result.get_document("rows").unwrap().get_i64("removed").unwrap();
+
+        // Removing the newest row moves max_timestamp back to the next one
+        assert_eq!(removed(db.query(String::from("DELETE AT 100"))), 1);
+        assert_eq!(removed(db.query(String::from("REMOVE::ONE::TIMESTAMP=100"))), 0);
+        assert_eq!(db.time_bounds(), Some((10, 90)));
+
+        assert_eq!(removed(db.query(String::from("DELETE RANGE 5, 35"))), 3);
+        assert_eq!(db.time_bounds(), Some((40, 90)));
+        assert_eq!(db.get_range(0, u128::MAX).len(), 6);
+
+        // Only rows in the range that match are removed
+        assert_eq!(removed(db.query(String::from("DELETE RANGE 40, 70 WHERE store = 0"))), 1);
+        assert!(db.get_one(60).is_none());
+        assert!(db.get_one(90).is_some_and(|row| row.get_f64("store") == Ok(0.0)));
+
+        assert_eq!(removed(db.query(String::from("REMOVE::ALL::WHERE number_sold >= 8"))), 2);
+        assert_eq!(db.time_bounds(), Some((40, 70)));
+        let metadata = db.query(String::from("SHOW METADATA"));
+        assert_eq!(metadata.get_document("rows").unwrap().get_str("max_timestamp").unwrap(), "70");
+
+        // Bounds past i64::MAX are reported whole
+        db.insert_to_database(u128::MAX, doc! { "store": 1.0 }).unwrap();
+        let metadata = db.query(String::from("SHOW METADATA"));
+        assert_eq!(metadata.get_document("rows").unwrap().get_str("max_timestamp").unwrap(), u128::MAX.to_string());
+        assert_eq!(db.remove_range(u128::MAX, u128::MAX, None), Ok(1));
+
+        let result = db.query(String::from("DELETE WHERE city = 'Moncton'"));
+        assert!(result.get_document("rows").unwrap().get_str("Notice").unwrap().starts_with("Invalid WHERE clause"));
+        assert_eq!(db.get_range(0, u128::MAX).len(), 3);
+
+        assert_eq!(db.remove_range(0, u128::MAX, None), Ok(3));
+        assert_eq!(db.time_bounds(), None);
+
+        db.insert_to_database(5, doc! { "store": 1.0 }).unwrap();
+        assert_eq!(removed(db.query(String::from("DELETE ALL"))), 1);
+        assert_eq!(db.time_bounds(), None);
+    }
+
+    #[test]
+    fn test_update_and_upsert() {
+        let mut db = Database::new(
+            vec![String::from("store"), String::from("number_sold"), String::from("checked")],
+            vec![String::from("number"), String::from("number"), String::from("boolean")],
+        );
+        db.insert_to_database(10, doc! { "store": 1.0, "number_sold": 5.0 }).unwrap();
+
+        // Only the listed columns change
+        let result = db.query(String::from("UPDATE number_sold = 6, checked = true AT 10"));
+        assert_eq!(result.get_document("rows").unwrap().get_i64("updated").unwrap(), 1);
+        assert_eq!(db.get_one(10).unwrap(), doc! { "store": 1.0, "number_sold": 6.0, "checked": true });
+
+        let result = db.query(String::from("UPDATE number_sold = 6 AT 11"));
+        assert_eq!(result.get_document("rows").unwrap().get_i64("updated").unwrap(), 0);
+        assert!(db.get_one(11).is_none());
+
+        let result = db.query(String::from("UPDATE price = 2 AT 10"));
+        assert_eq!(result.get_i32("status").unwrap(), 400);
+        let error = result.get_document("rows").unwrap().get_document("price").unwrap();
+        assert_eq!(error.get_str("error").unwrap(), "Column 'price' is not in the schema");
+
+        // A plain insert at a taken timestamp moves along; an upsert replaces the row
+        db.query(String::from("INSERT store = 2 AT 10"));
+        assert_eq!(db.get_one(11).unwrap(), doc! { "store": 2.0 });
+        db.query(String::from("UPSERT store = 3 AT 10"));
+        assert_eq!(db.get_one(10).unwrap(), doc! { "store": 3.0 });
+        assert!(db.get_one(12).is_none());
+        assert_eq!(db.get_range(0, u128::MAX).len(), 2);
+
+        assert!(db.upsert(20, doc! { "price": 1.0 }).is_err());
+        assert_eq!(db.update(20, doc! { "store": 1.0 }), Ok(false));
+        assert_eq!(db.time_bounds(), Some((10, 11)));
+    }
+
+    #[test]
+    fn test_duplicate_policies() {
+        let mut db = create_test_db();
+        db.insert_to_database(10, doc! { "store": 1.0, "product": 1.0 }).unwrap();
+        db.insert_to_database(11, doc! { "store": 1.0, "product": 2.0 }).unwrap();
+
+        // Shift moves past the whole run of taken keys
+        assert_eq!(db.duplicate_policy(), DuplicatePolicy::Shift);
+        assert_eq!(db.insert_to_database(10, doc! { "store": 2.0 }), Ok(Inserted { key: 12, duplicate: Some(DuplicatePolicy::Shift) }));
+        assert_eq!(db.insert_to_database(20, doc! { "store": 2.0 }), Ok(Inserted { key: 20, duplicate: None }));
+
+        // ...unless the run reaches the last timestamp there is
+        db.insert_to_database(u128::MAX, doc! { "store": 1.0 }).unwrap();
+        assert_eq!(db.insert_to_database(u128::MAX, doc! { "store": 2.0 }), Err(InsertError::Duplicate { key: u128::MAX, policy: DuplicatePolicy::Shift }));
+        assert_eq!(db.insert_to_database(u128::MAX, doc! { "store": 2.0 }).unwrap_err().status(), 409);
+        assert_eq!(db.get_one(u128::MAX).unwrap(), doc! { "store": 1.0 });
+        db.remove_range(u128::MAX, u128::MAX, None).unwrap();
+
+        db.set_duplicate_policy(DuplicatePolicy::Reject).unwrap();
+        assert_eq!(db.insert_to_database(10, doc! { "store": 3.0 }), Err(InsertError::Duplicate { key: 10, policy: DuplicatePolicy::Reject }));
+        assert_eq!(db.get_one(10).unwrap(), doc! { "store": 1.0, "product": 1.0 });
+
+        db.set_duplicate_policy(DuplicatePolicy::Overwrite).unwrap();
+        assert_eq!(db.insert_to_database(10, doc! { "store": 4.0 }).unwrap().duplicate, Some(DuplicatePolicy::Overwrite));
+        assert_eq!(db.get_one(10).unwrap(), doc! { "store": 4.0 });
+
+        db.set_duplicate_policy(DuplicatePolicy::KeepFirst).unwrap();
+        assert_eq!(db.insert_to_database(10, doc! { "store": 5.0 }).unwrap().duplicate, Some(DuplicatePolicy::KeepFirst));
+        assert_eq!(db.get_one(10).unwrap(), doc! { "store": 4.0 });
+
+        db.set_duplicate_policy(DuplicatePolicy::Merge).unwrap();
+        assert_eq!(db.insert_to_database(11, doc! { "store": 6.0, "number_sold": 3.0 }).unwrap().key, 11);
+        assert_eq!(db.get_one(11).unwrap(), doc! { "store": 6.0, "product": 2.0, "number_sold": 3.0 });
+        assert_eq!(db.get_range(0, u128::MAX).len(), 4);
+
+        // The insert response says where the row went and which policy was applied
+        let result = db.query(String::from("INSERT product = 9 AT 12"));
+        let rows = result.get_document("rows").unwrap();
+        assert_eq!(rows.get_str("timestamp").unwrap(), "12");
+        assert_eq!(rows.get_str("duplicate").unwrap(), "merge");
+
+        let result = db.query(String::from("INSERT product = 9 AT 30"));
+        assert!(result.get_document("rows").unwrap().get("duplicate").unwrap().as_null().is_some());
+
+        // Keys past i64::MAX are reported whole
+        let result = db.query(String::from("INSERT product = 9 AT 18446744073709551616"));
+        assert_eq!(result.get_document("rows").unwrap().get_str("timestamp").unwrap(), "18446744073709551616");
+        assert!(db.get_one(1 << 64).is_some());
+
+        db.set_duplicate_policy(DuplicatePolicy::Reject).unwrap();
+        let result = db.query(String::from("INSERT product = 9 AT 30"));
+        assert!(result.get_document("rows").unwrap().get_str("Notice").unwrap().contains("already exists at 30"));
+
+        let result = db.query(String::from("SHOW METADATA"));
+        assert_eq!(result.get_document("rows").unwrap().get_str("duplicates").unwrap(), "reject");
+
+        // The policy can be changed with a query, and every insert response names it
+        let result = db.query(String::from("SET DUPLICATES KEEP FIRST"));
+        assert_eq!(result.get_document("rows").unwrap().get_str("duplicates").unwrap(), "keep first");
+        assert_eq!(db.duplicate_policy(), DuplicatePolicy::KeepFirst);
+        db.query(String::from("SET::DUPLICATES::MERGE"));
+        assert_eq!(db.duplicate_policy(), DuplicatePolicy::Merge);
+
+        let result = db.query(String::from("INSERT product = 9 AT 40"));
+        assert_eq!(result.get_document("rows").unwrap().get_str("policy").unwrap(), "merge");
+        let result = db.query_with_body(String::from("INSERT BATCH"), "{\"timestamp\": 40, \"store\": 1}\n");
+        assert_eq!(result.get_document("rows").unwrap(), &doc! { "inserted": 1_i64, "duplicates": 1_i64, "policy": "merge" });
+    }
+
+    #[test]
+    fn test_insert_batch_is_all_or_nothing() {
+        let mut db = create_test_db();
+        db.insert_to_database(10, doc! { "store": 1.0 }).unwrap();
+        db.set_duplicate_policy(DuplicatePolicy::Reject).unwrap();
+
+        // One bad row keeps every row out, and each bad row is reported
+        let rows = vec![
+            (20, doc! { "store": 2.0 }),
+            (10, doc! { "store": 3.0 }),
+            (21, doc! { "price": 4.0 }),
+            (20, doc! { "store": 5.0 }),
+        ];
+        match db.insert_batch(rows) {
+            Err(BatchError::Rows(errors)) => {
+                assert_eq!(errors.iter().map(|(i, _)| *i).collect::<Vec<usize>>(), vec![1, 2, 3]);
+                assert_eq!(errors[0].1, InsertError::Duplicate { key: 10, policy: DuplicatePolicy::Reject });
+                assert_eq!(errors[1].1, InsertError::Columns(vec![ColumnError::Unknown { column: String::from("price") }]));
+            }
+            other => panic!("expected row errors, got {:?}", other),
+        }
+        assert!(db.get_one(20).is_none());
+        assert_eq!(db.time_bounds(), Some((10, 10)));
+
+        // Duplicates within the batch are resolved like any other
+        db.set_duplicate_policy(DuplicatePolicy::Shift).unwrap();
+        let inserted = db.insert_batch(vec![(20, doc! { "store": 2.0 }), (20, doc! { "store": 3.0 }), (10, doc! { "store": 4.0 })]).unwrap();
+        assert_eq!(inserted.iter().map(|i| i.key).collect::<Vec<u128>>(), vec![20, 21, 11]);
+        assert_eq!(inserted[1].duplicate, Some(DuplicatePolicy::Shift));
+        match db.insert_batch(vec![(u128::MAX, doc! { "store": 1.0 }), (u128::MAX, doc! { "store": 2.0 })]) {
+            Err(BatchError::Rows(errors)) => {
+                assert_eq!(errors, vec![(1, InsertError::Duplicate { key: u128::MAX, policy: DuplicatePolicy::Shift })]);
+            }
+            other => panic!("expected row errors, got {:?}", other),
+        }
+
+        db.set_duplicate_policy(DuplicatePolicy::Merge).unwrap();
+        db.insert_batch(vec![(30, doc! { "store": 1.0 }), (30, doc! { "product": 2.0 })]).unwrap();
+        assert_eq!(db.get_one(30).unwrap(), doc! { "store": 1.0, "product": 2.0 });
+        assert_eq!(db.time_bounds(), Some((10, 30)));
+    }
+
+    #[test]
+    fn test_insert_batch_query_with_csv_and_ndjson() {
+        let mut db = Database::new(
+            vec![String::from("store"), String::from("number_sold"), String::from("city"), String::from("open")],
+            vec![String::from("number"), String::from("number"), String::from("string"), String::from("boolean")],
+        );
+
+        let body = "timestamp,store,city,open\n\
+                    10,1, \" Moncton, NB \" ,true\n\
+                    11,2,,false\n";
+        let result = db.query_with_body(String::from("INSERT BATCH CSV"), body);
+        assert_eq!(result.get_document("rows").unwrap(), &doc! { "inserted": 2_i64, "duplicates": 0_i64, "policy": "shift" });
+        assert_eq!(db.get_one(10).unwrap(), doc! { "store": 1.0, "city": "Moncton, NB", "open": true });
+        assert_eq!(db.get_one(11).unwrap(), doc! { "store": 2.0, "open": false });
+
+        // Bad lines are listed by line number and nothing is stored
+        let body = "timestamp,store,open\n\
+                    20,1,true\n\
+                    21,x,true\n\
+                    22,3\n\
+                    23,4,maybe\n";
+        let result = db.query_with_body(String::from("INSERT::BATCH::CSV"), body);
+        let rows = result.get_document("rows").unwrap();
+        let error = |line: &str| rows.get_document(line).unwrap().get_str("error").unwrap().to_string();
+        assert_eq!(rows.len(), 3);
+        assert_eq!(rows.get_document("line 3").unwrap().get_i64("line").unwrap(), 3);
+        assert_eq!(error("line 3"), "Column 'store' needs a number, found 'x'");
+        assert!(error("line 4").contains("expected 3 fields"));
+        assert_eq!(error("line 5"), "Column 'open' needs a boolean, found 'maybe'");
+        assert!(db.get_one(20).is_none());
+        assert_eq!(result.get_i32("status").unwrap(), 400);
+
+        // Taken timestamps alone are a conflict, as for a single insert
+        db.set_duplicate_policy(DuplicatePolicy::Reject).unwrap();
+        let result = db.query_with_body(String::from("INSERT BATCH CSV"), "timestamp,store\n10,1\n20,2\n");
+        assert_eq!(result.get_i32("status").unwrap(), 409);
+        let result = db.query_with_body(String::from("INSERT BATCH CSV"), "timestamp,store\n10,1\n20,x\n");
+        assert_eq!(result.get_i32("status").unwrap(), 400);
+        db.set_duplicate_policy(DuplicatePolicy::Shift).unwrap();
+
+        let result = db.query_with_body(String::from("INSERT BATCH CSV"), "timestamp,price\n30,1\n");
+        let rows = result.get_document("rows").unwrap();
+        assert!(rows.get_document("line 1").unwrap().get_str("error").unwrap().contains("price"));
+
+        // Rows whose timestamp was taken are counted
+        let body = "{\"timestamp\": 10, \"store\": 5}\n{\"timestamp\": 40, \"number_sold\": 1}\n";
+        let result = db.query_with_body(String::from("INSERT BATCH"), body);
+        assert_eq!(result.get_document("rows").unwrap(), &doc! { "inserted": 2_i64, "duplicates": 1_i64, "policy": "shift" });
+        assert_eq!(db.get_one(12).unwrap(), doc! { "store": 5.0 });
+
+        let result = db.query_with_body(String::from("INSERT BATCH"), "{\"timestamp\": 50, \"store\": 1}\nnot json\n");
+        assert!(result.get_document("rows").unwrap().contains_key("line 2"));
+        assert!(db.get_one(50).is_none());
+
+        // String columns take only JSON strings, not numbers or null
+        let body = "{\"timestamp\": 60, \"city\": 5}\n{\"timestamp\": 61, \"city\": null}\n";
+        let result = db.query_with_body(String::from("INSERT BATCH"), body);
+        let rows = result.get_document("rows").unwrap();
+        let error = |line: &str| rows.get_document(line).unwrap().get_str("error").unwrap().to_string();
+        assert_eq!(error("line 1"), "Column 'city' needs a string, found 5");
+        assert_eq!(error("line 2"), "Column 'city' needs a string, found null");
+        assert!(db.get_one(60).is_none());
+        assert!(db.get_one(61).is_none());
+
+        // Every bad column on a line is reported, not just the first
+        let body = "{\"timestamp\": 62, \"city\": 5, \"price\": 1, \"store\": \"x\"}\n";
+        let result = db.query_with_body(String::from("INSERT BATCH"), body);
+        let error = result.get_document("rows").unwrap().get_document("line 1").unwrap().get_str("error").unwrap().to_string();
+        assert_eq!(error, "Column 'city' needs a string, found 5; Column 'price' is not in the schema; Column 'store' needs a number, found \"x\"");
+        let result = db.query_with_body(String::from("INSERT BATCH CSV"), "timestamp,store,open\n-1,x,maybe\n");
+        let error = result.get_document("rows").unwrap().get_document("line 2").unwrap().get_str("error").unwrap().to_string();
+        assert_eq!(error, "timestamp must be a non-negative integer; Column 'store' needs a number, found 'x'; Column 'open' needs a boolean, found 'maybe'");
+        assert!(db.get_one(62).is_none());
+
+        // Rows without a timestamp don't collide with each other, even under Reject
+        db.set_duplicate_policy(DuplicatePolicy::Reject).unwrap();
+        let result = db.query_with_body(String::from("INSERT BATCH"), "{\"store\": 1}\n{\"store\": 2}\n{\"store\": 3}\n");
+        assert_eq!(result.get_document("rows").unwrap(), &doc! { "inserted": 3_i64, "duplicates": 0_i64, "policy": "reject" });
+        db.set_duplicate_policy(DuplicatePolicy::Shift).unwrap();
+
+        let result = db.query_with_body(String::from("INSERT BATCH"), "");
+        assert!(result.get_document("rows").unwrap().get_str("Notice").unwrap().contains("POST body"));
+    }
+
+    #[test]
+    fn test_insert_validation_errors() {
+        let mut db = Database::new(
+            vec![String::from("store"), String::from("number_sold"), String::from("city"), String::from("open")],
+            vec![String::from("number"), String::from("number"), String::from("string"), String::from("boolean")],
+        );
+        assert!(db.set_required_columns(vec![String::from("price")]).is_err());
+        db.set_required_columns(vec![String::from("store")]).unwrap();
+
+        // Every bad column is reported, not just the first
+        let result = db.insert_to_database(10, doc! { "number_sold": "many", "open": 1, "price": 2.0 });
+        assert_eq!(result, Err(InsertError::Columns(vec![
+            ColumnError::Type { column: String::from("number_sold"), expected: String::from("number"), found: String::from("'many'") },
+            ColumnError::Type { column: String::from("open"), expected: String::from("boolean"), found: String::from("1") },
+            ColumnError::Unknown { column: String::from("price") },
+            ColumnError::Missing { column: String::from("store") },
+        ])));
+        assert!(db.get_one(10).is_none());
+
+        // Numbers may still arrive as strings that hold one, and null fits any column
+        assert!(db.insert_to_database(10, doc! { "store": "3", "city": Bson::Null }).is_ok());
+
+        // Values from a query are no longer coerced: a bad one is an error with status 400
+        let result = db.query(String::from("INSERT::store=abc,open=yes,price=1::TIMESTAMP=20"));
+        assert_eq!(result.get_i32("status").unwrap(), 400);
+        let rows = result.get_document("rows").unwrap();
+        assert_eq!(rows.keys().collect::<Vec<&String>>(), vec!["store", "open", "price"]);
+        assert_eq!(rows.get_document("store").unwrap().get_str("error").unwrap(), "Column 'store' needs a number, found 'abc'");
+        assert_eq!(rows.get_document("open").unwrap().get_str("error").unwrap(), "Column 'open' needs a boolean, found 'yes'");
+        assert!(db.get_one(20).is_none());
+
+        let result = db.query(String::from("INSERT city = 4, open = true AT 20"));
+        let rows = result.get_document("rows").unwrap();
+        assert!(rows.get_document("city").unwrap().get_str("error").unwrap().contains("needs a string"));
+        assert!(rows.get_document("store").unwrap().get_str("error").unwrap().contains("is required"));
+
+        // A patch doesn't need the required columns, but its values are checked
+        assert_eq!(db.update(10, doc! { "open": true }), Ok(true));
+        let result = db.query(String::from("UPDATE number_sold = 'x' AT 10"));
+        assert_eq!(result.get_i32("status").unwrap(), 400);
+
+        // Errors are shown even for hidden queries
+        let result = db.query(String::from("INSERT::store=abc::HIDE"));
+        assert_eq!(result.get_i32("status").unwrap(), 400);
+
+        db.set_duplicate_policy(DuplicatePolicy::Reject).unwrap();
+        let result = db.query(String::from("INSERT store = 1 AT 10"));
+        assert_eq!(result.get_i32("status").unwrap(), 409);
+        assert!(result.get_document("rows").unwrap().get_str("Notice").unwrap().contains("already exists at 10"));
+
+        let result = db.query(String::from("INSERT store = 1 AT 30"));
+        assert!(result.get("status").is_none());
+        assert_eq!(result.get_document("rows").unwrap().get_str("timestamp").unwrap(), "30");
+
+        let result = db.query(String::from("SHOW METADATA"));
+        assert_eq!(result.get_document("rows").unwrap().get_array("required").unwrap(), &vec![Bson::from("store")]);
+    }
 }
@@ -0,0 +1,553 @@
+use r2d2p2::aggregate::{AggregateFunction, Fill};
+use r2d2p2::database::DuplicatePolicy;
+use r2d2p2::transform::{TransformFunction, Window};
+use r2d2p2::query::{
+    continuation_token, parse, Aggregate, AsOf, BatchFormat, CompareOp, Delete, Latest, Literal, Order, Page, Predicate, Projection, Select, Statement, TimeBucket,
+};
+
+#[cfg(test)]
+mod query_tests {
+    use super::*;
+
+    fn statement(input: &str) -> Statement {
+        parse(input).unwrap().statement
+    }
+
+    fn select_all(at: Option<u128>, range: Option<(u128, u128)>) -> Statement {
+        Statement::Select(Select { at, range, ..Select::default() })
+    }
+
+    #[test]
+    fn test_parse_select_forms() {
+        assert_eq!(statement("SELECT *"), select_all(None, None));
+        assert_eq!(statement("select * at 1733697225084;"), select_all(Some(1733697225084), None));
+
+        // Bounds are put in order, like LIST::RANGE always did
+        assert_eq!(statement("SELECT * RANGE 200, 100"), select_all(None, Some((100, 200))));
+        assert_eq!(
+            statement("SELECT * AT '2024-12-07 11:15:10'"),
+            select_all(Some(1733570110000), None)
+        );
+
+        assert_eq!(
+            statement("SELECT avg(number_sold)"),
+            Statement::Select(Select {
+                projection: Projection::Aggregates(vec![Aggregate::new(AggregateFunction::Avg, "number_sold")]),
+                ..Select::default()
+            })
+        );
+    }
+
+    #[test]
+    fn test_parse_insert_with_keyword_column_names() {
+        assert_eq!(
+            statement("INSERT at = 1.5, \"HIDE\" = 'it''s', ok = true AT 10"),
+            Statement::Insert {
+                values: vec![
+                    (String::from("at"), Literal::Number(1.5)),
+                    (String::from("HIDE"), Literal::Str(String::from("it's"))),
+                    (String::from("ok"), Literal::Bool(true)),
+                ],
+                at: Some(10),
+                overwrite: false,
+            }
+        );
+
+        assert_eq!(statement("DELETE ALL"), Statement::Delete(Delete::All));
+        assert_eq!(statement("SAVE CSV"), Statement::SaveCsv);
+        assert_eq!(statement("LOAD 'backup.r2seg'"), Statement::Load { filename: String::from("backup.r2seg") });
+    }
+
+    #[test]
+    fn test_parse_errors_point_at_column() {
+        let e = parse("SELECT * RANGE 1 2").unwrap_err();
+        assert_eq!(e.column, 18);
+        assert!(e.message.contains("expected ','"));
+
+        let e = parse("SELECT * # 2").unwrap_err();
+        assert_eq!(e.column, 10);
+
+        let e = parse("INSERT store = 'open").unwrap_err();
+        assert_eq!(e.column, 16);
+        assert!(e.message.contains("unterminated"));
+
+        let e = parse("SELECT * AT 5 6").unwrap_err();
+        assert_eq!(e.column, 15);
+        assert!(e.to_string().starts_with("syntax error at column 15"));
+
+        let e = parse("FETCH *").unwrap_err();
+        assert_eq!(e.column, 1);
+    }
+
+    #[test]
+    fn test_legacy_front_end_matches_new_syntax() {
+        let pairs = [
+            ("LIST::ALL", "SELECT *"),
+            ("LIST::ONE::5", "SELECT * AT 5"),
+            ("LIST::RANGE::9,3", "SELECT * RANGE 3, 9"),
+            ("LIST::METADATA", "SHOW METADATA"),
+            ("LIST::SAVED", "SHOW SAVED"),
+            ("AGGREGATE::number_sold::max", "SELECT MAX(number_sold)"),
+            ("AGGREGATE::number_sold::AVG::20,10", "SELECT AVG(number_sold) RANGE 10, 20"),
+            ("AGGREGATE::n::MIN::1,2::WHERE s = 'x'", "SELECT MIN(n) RANGE 1, 2 WHERE s = 'x'"),
+            ("AGGREGATE::n::MIN::WHERE s = 'x'", "SELECT MIN(n) WHERE s = 'x'"),
+            ("REMOVE::ONE::TIMESTAMP=5", "DELETE AT 5"),
+            ("REMOVE::ALL", "DELETE ALL"),
+            ("REMOVE::RANGE::9,3", "DELETE RANGE 3, 9"),
+            ("REMOVE::RANGE::3,9::WHERE s = 'x'", "DELETE RANGE 3, 9 WHERE s = 'x'"),
+            ("REMOVE::ALL::WHERE s = 'x'", "DELETE WHERE s = 'x'"),
+            ("TIME::2024-12-07%2011:15:10", "TIME '2024-12-07 11:15:10'"),
+            ("SAVE::CSV", "SAVE CSV"),
+            ("LOAD::SCHEMA::test.schema.r2d2", "LOAD SCHEMA 'test.schema.r2d2'"),
+            ("BULK", "BULK"),
+            ("INSERT::BATCH", "INSERT BATCH NDJSON"),
+            ("INSERT::BATCH::CSV", "INSERT BATCH CSV"),
+        ];
+
+        for (legacy, new) in pairs {
+            assert_eq!(statement(legacy), statement(new), "{} vs {}", legacy, new);
+        }
+
+        // '::' values arrive untyped and are converted against the schema later
+        assert_eq!(
+            statement("INSERT::store=1,name=Walmart::TIMESTAMP=7"),
+            Statement::Insert {
+                values: vec![
+                    (String::from("store"), Literal::Str(String::from("1"))),
+                    (String::from("name"), Literal::Str(String::from("Walmart"))),
+                ],
+                at: Some(7),
+                overwrite: false,
+            }
+        );
+    }
+
+    #[test]
+    fn test_legacy_hide_and_errors() {
+        assert!(parse("LIST::ALL::HIDE").unwrap().hide);
+        assert!(!parse("LIST::ALL").unwrap().hide);
+
+        // A column called HIDE is just a column
+        let query = parse("INSERT::HIDE=1").unwrap();
+        assert!(!query.hide);
+        assert_eq!(
+            query.statement,
+            Statement::Insert { values: vec![(String::from("HIDE"), Literal::Str(String::from("1")))], at: None, overwrite: false }
+        );
+
+        let e = parse("LIST::RANGE::1,x").unwrap_err();
+        assert_eq!(e.column, 16);
+
+        let e = parse("LIST::ONE").unwrap_err();
+        assert_eq!(e.column, 10);
+
+        let e = parse("LIST::EVERYTHING").unwrap_err();
+        assert_eq!(e.column, 7);
+    }
+
+    fn filter(input: &str) -> Predicate {
+        match statement(input) {
+            Statement::Select(select) => select.filter.unwrap(),
+            other => panic!("not a select: {:?}", other),
+        }
+    }
+
+    fn compare(column: &str, op: CompareOp, value: Literal) -> Predicate {
+        Predicate::Compare { column: String::from(column), op, value }
+    }
+
+    #[test]
+    fn test_parse_where_precedence() {
+        // AND binds tighter than OR, NOT tighter than AND
+        assert_eq!(
+            filter("SELECT * WHERE a = 1 OR NOT b < 2 AND c >= 3"),
+            Predicate::Or(
+                Box::new(compare("a", CompareOp::Eq, Literal::Number(1.0))),
+                Box::new(Predicate::And(
+                    Box::new(Predicate::Not(Box::new(compare("b", CompareOp::Lt, Literal::Number(2.0))))),
+                    Box::new(compare("c", CompareOp::Ge, Literal::Number(3.0))),
+                )),
+            )
+        );
+
+        assert_eq!(
+            filter("SELECT * RANGE 1, 2 WHERE (a <> 'x' OR a != 'y') AND store NOT IN (1, 2)"),
+            Predicate::And(
+                Box::new(Predicate::Or(
+                    Box::new(compare("a", CompareOp::Ne, Literal::Str(String::from("x")))),
+                    Box::new(compare("a", CompareOp::Ne, Literal::Str(String::from("y")))),
+                )),
+                Box::new(Predicate::Not(Box::new(Predicate::In {
+                    column: String::from("store"),
+                    values: vec![Literal::Number(1.0), Literal::Number(2.0)],
+                }))),
+            )
+        );
+
+        // The AND inside BETWEEN belongs to BETWEEN
+        assert_eq!(
+            filter("LIST::ALL::WHERE n BETWEEN 1 AND 5 AND ok = true"),
+            Predicate::And(
+                Box::new(Predicate::Between {
+                    column: String::from("n"),
+                    low: Literal::Number(1.0),
+                    high: Literal::Number(5.0),
+                }),
+                Box::new(compare("ok", CompareOp::Eq, Literal::Bool(true))),
+            )
+        );
+
+        let e = parse("SELECT * WHERE a = 1 AND").unwrap_err();
+        assert_eq!(e.column, 25);
+
+        let e = parse("LIST::RANGE::1,2::WHERE store ~ 3").unwrap_err();
+        assert_eq!(e.column, 31);
+
+        let e = parse("SELECT * WHERE store NOT = 3").unwrap_err();
+        assert!(e.message.contains("IN or BETWEEN"));
+    }
+
+    #[test]
+    fn test_parse_group_by_time() {
+        let select = match statement("SELECT avg(number_sold) RANGE 0, 100 WHERE store = 3 GROUP BY time(5m)") {
+            Statement::Select(select) => select,
+            other => panic!("not a select: {:?}", other),
+        };
+        assert_eq!(select.bucket, Some(TimeBucket { width: 300000, origin: 0 }));
+        assert_eq!(select.range, Some((0, 100)));
+        assert!(select.filter.is_some());
+
+        let bucket = |input: &str| match statement(input) {
+            Statement::Select(select) => select.bucket.unwrap(),
+            other => panic!("not a select: {:?}", other),
+        };
+        assert_eq!(bucket("SELECT SUM(n) GROUP BY time(250)").width, 250);
+        assert_eq!(bucket("SELECT SUM(n) GROUP BY TIME(1 h)").width, 3600000);
+        assert_eq!(
+            bucket("SELECT SUM(n) GROUP BY time(1d, '2024-12-07 11:15:10')"),
+            TimeBucket { width: 86400000, origin: 1733570110000 }
+        );
+
+        let e = parse("SELECT SUM(n) GROUP BY time(5 fortnights)").unwrap_err();
+        assert_eq!(e.column, 31);
+
+        let e = parse("SELECT SUM(n) GROUP BY time(0s)").unwrap_err();
+        assert_eq!(e.column, 29);
+
+        let e = parse("SELECT SUM(n) GROUP BY time(340282366920938463463374607431768211455w)").unwrap_err();
+        assert_eq!(e.column, 29);
+        assert!(e.message.contains("too long"));
+
+        let e = parse("SELECT * GROUP BY time(5m)").unwrap_err();
+        assert_eq!(e.column, 10);
+
+        let e = parse("SELECT SUM(n) AT 5").unwrap_err();
+        assert_eq!(e.column, 15);
+    }
+
+    #[test]
+    fn test_parse_group_by_columns() {
+        let select = match statement("SELECT SUM(n) GROUP BY store, time(1h), \"time\"") {
+            Statement::Select(select) => select,
+            other => panic!("not a select: {:?}", other),
+        };
+        assert_eq!(select.group_by, vec![String::from("store"), String::from("time")]);
+        assert_eq!(select.bucket, Some(TimeBucket { width: 3600000, origin: 0 }));
+
+        // A column called time is only a bucket when followed by '('
+        let select = match statement("SELECT SUM(n) RANGE 1, 2 GROUP BY time") {
+            Statement::Select(select) => select,
+            other => panic!("not a select: {:?}", other),
+        };
+        assert_eq!(select.group_by, vec![String::from("time")]);
+        assert_eq!(select.bucket, None);
+
+        let e = parse("SELECT SUM(n) GROUP BY time(1h), time(1m)").unwrap_err();
+        assert_eq!(e.column, 34);
+
+        let e = parse("SELECT SUM(n) GROUP BY store,").unwrap_err();
+        assert_eq!(e.column, 30);
+    }
+
+    #[test]
+    fn test_parse_aggregate_functions() {
+        let projection = |input: &str| match statement(input) {
+            Statement::Select(select) => select.projection,
+            other => panic!("not a select: {:?}", other),
+        };
+        let aggregate = |function, field: &str| Projection::Aggregates(vec![Aggregate::new(function, field)]);
+
+        assert_eq!(projection("SELECT count(DISTINCT store)"), aggregate(AggregateFunction::CountDistinct, "store"));
+        assert_eq!(projection("SELECT COUNT(distinct)"), aggregate(AggregateFunction::Count, "distinct"));
+        assert_eq!(projection("SELECT percentile(n, 99.5)"), aggregate(AggregateFunction::Percentile(99.5), "n"));
+        assert_eq!(
+            projection("SELECT APPROX_PERCENTILE(n, 50) GROUP BY time(1h)"),
+            aggregate(AggregateFunction::ApproxPercentile(50.0), "n")
+        );
+
+        assert_eq!(statement("AGGREGATE::n::PERCENTILE=95"), statement("SELECT PERCENTILE(n, 95)"));
+        assert_eq!(statement("AGGREGATE::store::count_distinct"), statement("SELECT COUNT(DISTINCT store)"));
+
+        // Unknown functions and bad arguments are errors, pointing at the function
+        let e = parse("SELECT MODE(n)").unwrap_err();
+        assert_eq!(e.column, 8);
+        assert!(e.message.contains("unknown aggregate MODE"));
+
+        assert_eq!(parse("SELECT percentile(n)").unwrap_err().column, 8);
+        assert_eq!(parse("SELECT percentile(n, 101)").unwrap_err().column, 8);
+        assert_eq!(parse("SELECT SUM(DISTINCT n)").unwrap_err().column, 8);
+        assert_eq!(parse("SELECT PERCENTILE(n, 'x')").unwrap_err().column, 22);
+        assert_eq!(parse("AGGREGATE::n::MODE").unwrap_err().column, 15);
+    }
+
+    #[test]
+    fn test_parse_aggregate_lists() {
+        let expected = Statement::Select(Select {
+            projection: Projection::Aggregates(vec![
+                Aggregate::new(AggregateFunction::Min, "number_sold"),
+                Aggregate::new(AggregateFunction::Max, "number_sold"),
+                Aggregate::new(AggregateFunction::Percentile(90.0), "price"),
+            ]),
+            range: Some((1, 2)),
+            ..Select::default()
+        });
+        assert_eq!(statement("SELECT MIN(number_sold), MAX(number_sold), PERCENTILE(price, 90) RANGE 1, 2"), expected);
+
+        // The '::' syntax takes several operations over one field
+        assert_eq!(
+            statement("AGGREGATE::n::min,MAX,PERCENTILE=95"),
+            statement("SELECT MIN(n), MAX(n), PERCENTILE(n, 95)")
+        );
+
+        assert_eq!(parse("SELECT MIN(n), MODE(n)").unwrap_err().column, 16);
+        assert_eq!(parse("SELECT MIN(n), *").unwrap_err().column, 16);
+        assert_eq!(parse("AGGREGATE::n::MIN,MODE").unwrap_err().column, 19);
+    }
+
+    #[test]
+    fn test_parse_transforms() {
+        let select = match statement("SELECT rate(requests, 1s) RANGE 0, 10 WHERE host = 'a' GROUP BY time(1m)") {
+            Statement::Select(select) => select,
+            other => panic!("not a select: {:?}", other),
+        };
+        assert_eq!(
+            select.projection,
+            Projection::Transform { function: TransformFunction::Rate { unit: 1000 }, field: String::from("requests") }
+        );
+        assert_eq!(select.bucket, Some(TimeBucket { width: 60000, origin: 0 }));
+
+        match statement("SELECT DIFFERENCE(n)") {
+            Statement::Select(select) => assert_eq!(
+                select.projection,
+                Projection::Transform { function: TransformFunction::Difference, field: String::from("n") }
+            ),
+            other => panic!("not a select: {:?}", other),
+        }
+
+        assert_eq!(parse("SELECT DIFFERENCE(n, 1s)").unwrap_err().column, 8);
+        assert_eq!(parse("SELECT RATE(n) GROUP BY host").unwrap_err().column, 25);
+        assert_eq!(parse("SELECT RATE(n), MAX(n)").unwrap_err().column, 15);
+        assert_eq!(parse("SELECT DERIVATIVE(n) AT 5").unwrap_err().column, 22);
+
+        // A number with a unit is a window of time, a bare number a count of rows
+        let projection = |input: &str| match statement(input) {
+            Statement::Select(select) => select.projection,
+            other => panic!("not a select: {:?}", other),
+        };
+        let transform = |function, field: &str| Projection::Transform { function, field: String::from(field) };
+        assert_eq!(
+            projection("SELECT MOVING_AVERAGE(t, 5 m)"),
+            transform(TransformFunction::MovingAverage(Window::Time(300000)), "t")
+        );
+        assert_eq!(projection("SELECT moving_average(t, 5)"), transform(TransformFunction::MovingAverage(Window::Rows(5)), "t"));
+        assert_eq!(projection("SELECT EWMA(t, 0.25) RANGE 1, 2"), transform(TransformFunction::Ewma { alpha: 0.25 }, "t"));
+        assert_eq!(projection("SELECT cumulative_sum(t)"), transform(TransformFunction::CumulativeSum, "t"));
+
+        assert_eq!(parse("SELECT EWMA(t, 'x')").unwrap_err().column, 16);
+        assert_eq!(parse("SELECT EWMA(t, 2)").unwrap_err().column, 8);
+        assert_eq!(parse("SELECT CUMULATIVE_SUM(t) GROUP BY time(1h)").unwrap_err().column, 35);
+
+        // A query that ends right after the comma is an error, not a panic
+        assert_eq!(parse("SELECT RATE(x,").unwrap_err().column, 15);
+        assert_eq!(parse("SELECT MOVING_AVERAGE(x,").unwrap_err().column, 25);
+    }
+
+    #[test]
+    fn test_parse_fill() {
+        let fill = |input: &str| match statement(input) {
+            Statement::Select(select) => select.fill,
+            other => panic!("not a select: {:?}", other),
+        };
+        assert_eq!(fill("SELECT AVG(t) GROUP BY time(1m)"), None);
+        assert_eq!(fill("SELECT AVG(t) GROUP BY time(1m) FILL(none)"), None);
+        assert_eq!(fill("SELECT AVG(t) GROUP BY time(1m) fill(null)"), Some(Fill::Null));
+        assert_eq!(fill("SELECT AVG(t) GROUP BY time(1m), host FILL(PREVIOUS)"), Some(Fill::Previous));
+        assert_eq!(fill("SELECT AVG(t), MAX(t) GROUP BY time(1m) FILL(LINEAR)"), Some(Fill::Linear));
+        assert_eq!(fill("SELECT AVG(t) GROUP BY time(1m) FILL(-2.5)"), Some(Fill::Value(-2.5)));
+
+        assert_eq!(parse("SELECT AVG(t) GROUP BY host FILL(0)").unwrap_err().column, 29);
+        assert_eq!(parse("SELECT RATE(t) GROUP BY time(1m) FILL(0)").unwrap_err().column, 34);
+        assert_eq!(parse("SELECT AVG(t) GROUP BY time(1m) FILL(zero)").unwrap_err().column, 38);
+        assert_eq!(parse("SELECT AVG(t) GROUP BY time(1m) FILL(0").unwrap_err().column, 39);
+    }
+
+    #[test]
+    fn test_parse_paging() {
+        let page = |input: &str| match statement(input) {
+            Statement::Select(select) => select.page,
+            other => panic!("not a select: {:?}", other),
+        };
+        assert_eq!(page("SELECT *"), Page::default());
+        assert_eq!(page("SELECT * LIMIT 10"), Page { limit: Some(10), ..Page::default() });
+        assert_eq!(
+            page("SELECT * RANGE 1, 2 WHERE x = 1 order by time desc LIMIT 10 OFFSET 20"),
+            Page { order: Order::Descending, limit: Some(10), offset: 20, after: None }
+        );
+        assert_eq!(page("SELECT * ORDER BY time ASC OFFSET 5"), Page { offset: 5, ..Page::default() });
+
+        // Tokens round trip, and only work in the order they were handed out for
+        let token = continuation_token(1733697225084, Order::Descending);
+        assert_eq!(
+            page(&format!("SELECT * ORDER BY time DESC LIMIT 2 AFTER '{}'", token)),
+            Page { order: Order::Descending, limit: Some(2), offset: 0, after: Some(1733697225084) }
+        );
+        assert_eq!(parse(&format!("SELECT * LIMIT 2 AFTER '{}'", token)).unwrap_err().column, 24);
+        assert_eq!(parse("SELECT * AFTER 'zz'").unwrap_err().column, 16);
+        assert_eq!(parse("SELECT * AFTER 5").unwrap_err().column, 16);
+
+        assert_eq!(parse("SELECT * LIMIT -1").unwrap_err().column, 16);
+        assert_eq!(parse("SELECT * LIMIT 1.5").unwrap_err().column, 16);
+        assert_eq!(parse("SELECT * ORDER BY store").unwrap_err().column, 19);
+        assert_eq!(parse("SELECT * OFFSET 1 LIMIT 1").unwrap_err().column, 19);
+        assert_eq!(parse("SELECT * AT 5 LIMIT 1").unwrap_err().column, 15);
+        assert_eq!(parse("SELECT MAX(x) LIMIT 1").unwrap_err().column, 15);
+    }
+
+    #[test]
+    fn test_parse_columns() {
+        let columns = |names: &[&str]| Projection::Columns(names.iter().map(|n| n.to_string()).collect());
+        let projection = |input: &str| match statement(input) {
+            Statement::Select(select) => select.projection,
+            other => panic!("not a select: {:?}", other),
+        };
+
+        assert_eq!(projection("SELECT store"), columns(&["store"]));
+        assert_eq!(projection("SELECT number_sold, \"unit price\" RANGE 1, 2 LIMIT 5"), columns(&["number_sold", "unit price"]));
+        // A column may share a function's name when it isn't called
+        assert_eq!(projection("SELECT rate, store AT 5"), columns(&["rate", "store"]));
+        assert_eq!(projection("LIST::ALL::COLUMNS=store,number_sold"), columns(&["store", "number_sold"]));
+        assert_eq!(projection("LIST::ONE::5::COLUMNS=store"), columns(&["store"]));
+
+        match statement("LIST::RANGE::1,2::COLUMNS=store::WHERE store = 1") {
+            Statement::Select(select) => {
+                assert_eq!(select.projection, columns(&["store"]));
+                assert_eq!(select.range, Some((1, 2)));
+                assert!(select.filter.is_some());
+            }
+            other => panic!("not a select: {:?}", other),
+        }
+
+        assert_eq!(parse("SELECT store,").unwrap_err().column, 14);
+        assert_eq!(parse("SELECT store GROUP BY store").unwrap_err().column, 14);
+        assert_eq!(parse("LIST::ALL::COLUMNS=store,,product").unwrap_err().column, 26);
+    }
+
+    #[test]
+    fn test_parse_latest() {
+        assert_eq!(statement("LATEST"), Statement::Latest(Latest { count: 1, before: None, filter: None, by: None }));
+        assert_eq!(
+            statement("latest 5 before 1000 by host"),
+            Statement::Latest(Latest { count: 5, before: Some(1000), filter: None, by: Some(String::from("host")) })
+        );
+        match statement("LATEST 2 WHERE cpu > 0.5 BY \"data center\"") {
+            Statement::Latest(latest) => {
+                assert_eq!(latest.count, 2);
+                assert!(latest.filter.is_some());
+                assert_eq!(latest.by, Some(String::from("data center")));
+            }
+            other => panic!("not latest: {:?}", other),
+        }
+
+        assert_eq!(parse("LATEST -1").unwrap_err().column, 8);
+        assert_eq!(parse("LATEST 1 BEFORE").unwrap_err().column, 16);
+        assert_eq!(parse("LATEST BY host WHERE cpu > 1").unwrap_err().column, 16);
+    }
+
+    #[test]
+    fn test_parse_as_of() {
+        let lookup = |input: &str| match statement(input) {
+            Statement::Select(select) => (select.as_of, select.at, select.tolerance),
+            other => panic!("not a select: {:?}", other),
+        };
+        assert_eq!(lookup("SELECT * AT 5"), (AsOf::Exact, Some(5), None));
+        assert_eq!(lookup("SELECT * at or before 5"), (AsOf::AtOrBefore, Some(5), None));
+        assert_eq!(lookup("SELECT store AT OR AFTER 5 WITHIN 2s WHERE store = 1"), (AsOf::AtOrAfter, Some(5), Some(2000)));
+        assert_eq!(lookup("SELECT * NEAREST '2024-12-07 11:15:10' WITHIN 500"), (AsOf::Nearest, Some(1733570110000), Some(500)));
+
+        assert_eq!(parse("SELECT * AT OR 5").unwrap_err().column, 16);
+        assert_eq!(parse("SELECT * AT 5 WITHIN 1s").unwrap_err().column, 15);
+        assert_eq!(parse("SELECT * NEAREST 5 WITHIN 0").unwrap_err().column, 27);
+        assert_eq!(parse("SELECT MAX(x) NEAREST 5").unwrap_err().column, 15);
+    }
+
+    #[test]
+    fn test_parse_delete() {
+        assert_eq!(statement("DELETE RANGE 9, 3"), Statement::Delete(Delete::Where { range: Some((3, 9)), filter: None }));
+        match statement("delete where store = 1 or product = 2") {
+            Statement::Delete(Delete::Where { range: None, filter: Some(Predicate::Or(_, _)) }) => {}
+            other => panic!("not a predicate delete: {:?}", other),
+        }
+
+        assert_eq!(parse("DELETE").unwrap_err().column, 7);
+        assert_eq!(parse("DELETE RANGE 3").unwrap_err().column, 15);
+        assert_eq!(parse("DELETE WHERE").unwrap_err().column, 13);
+        assert_eq!(parse("REMOVE::RANGE::3").unwrap_err().column, 16);
+    }
+
+    #[test]
+    fn test_parse_update_and_upsert() {
+        assert_eq!(
+            statement("UPDATE number_sold = 4, at = 'x' AT 10"),
+            Statement::Update {
+                values: vec![(String::from("number_sold"), Literal::Number(4.0)), (String::from("at"), Literal::Str(String::from("x")))],
+                at: 10,
+            }
+        );
+        assert_eq!(
+            statement("upsert store = 1 AT 10"),
+            Statement::Insert { values: vec![(String::from("store"), Literal::Number(1.0))], at: Some(10), overwrite: true }
+        );
+
+        assert_eq!(parse("UPDATE store = 1").unwrap_err().column, 17);
+        assert_eq!(parse("UPDATE AT 5").unwrap_err().column, 11);
+    }
+
+    #[test]
+    fn test_parse_insert_batch() {
+        assert_eq!(statement("INSERT BATCH"), Statement::InsertBatch { format: BatchFormat::Ndjson });
+        assert_eq!(statement("insert batch csv"), Statement::InsertBatch { format: BatchFormat::Csv });
+
+        // A column named batch can still be assigned
+        assert_eq!(
+            statement("INSERT batch = 2"),
+            Statement::Insert { values: vec![(String::from("batch"), Literal::Number(2.0))], at: None, overwrite: false }
+        );
+
+        assert_eq!(parse("INSERT BATCH XML").unwrap_err().column, 14);
+        assert_eq!(parse("INSERT::BATCH::XML").unwrap_err().column, 16);
+    }
+
+    #[test]
+    fn test_parse_set_duplicates() {
+        assert_eq!(statement("SET DUPLICATES reject"), Statement::SetDuplicatePolicy { policy: DuplicatePolicy::Reject });
+        assert_eq!(statement("set duplicates keep first"), Statement::SetDuplicatePolicy { policy: DuplicatePolicy::KeepFirst });
+        assert_eq!(statement("SET DUPLICATES KEEP_FIRST"), Statement::SetDuplicatePolicy { policy: DuplicatePolicy::KeepFirst });
+        assert_eq!(statement("SET::DUPLICATES::MERGE"), Statement::SetDuplicatePolicy { policy: DuplicatePolicy::Merge });
+        assert_eq!(statement("SET::DUPLICATES::KEEP%20FIRST"), Statement::SetDuplicatePolicy { policy: DuplicatePolicy::KeepFirst });
+
+        let e = parse("SET DUPLICATES ignore").unwrap_err();
+        assert_eq!(e.column, 16);
+        assert!(e.message.contains("must be one of: shift, reject, overwrite, keep first, merge"));
+        assert_eq!(parse("SET::COLOUR::RED").unwrap_err().column, 6);
+    }
+}
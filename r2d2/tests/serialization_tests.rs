@@ -1,4 +1,5 @@
-use r2d2p2::database::{Database, DATA_PATH};
+use r2d2p2::bson_stream::BsonSaveReader;
+use r2d2p2::database::{Database, DuplicatePolicy, DATA_PATH};
 use bson::doc;
 use std::fs;
 use std::path::Path;
@@ -34,7 +35,7 @@ mod serialization_tests {
                 "product": 100 + i,
                 "number_sold": 5 * i
             };
-            db.insert_to_database(timestamp, doc);
+            db.insert_to_database(timestamp, doc).unwrap();
         }
     }
 
@@ -94,4 +95,189 @@ mod serialization_tests {
             let _ = fs::remove_file(csv_path);
         }
     }
+
+    // Mixed-type schema, so every column encoding gets exercised
+    fn create_mixed_db() -> Database {
+        Database::new(
+            vec![
+                String::from("store"),
+                String::from("temperature"),
+                String::from("in_stock"),
+                String::from("count")
+            ],
+            vec![
+                String::from("string"),
+                String::from("number"),
+                String::from("boolean"),
+                String::from("number")
+            ]
+        )
+    }
+
+    // Splitting a FAN_OUT-sized leaf needs more stack than a default test thread has,
+    // so tests that go past one leaf run on a thread of their own.
+    fn with_large_stack(f: fn()) {
+        std::thread::Builder::new()
+            .stack_size(64 * 1024 * 1024)
+            .spawn(f)
+            .unwrap()
+            .join()
+            .unwrap();
+    }
+
+    #[test]
+    fn test_save_and_load_segment() {
+        with_large_stack(save_and_load_segment);
+    }
+
+    fn save_and_load_segment() {
+        let mut original_db = create_mixed_db();
+        let base_time: u128 = 1733697225000;
+        let stores = ["Walmart", "Costco", "Sobeys"];
+
+        // More rows than fit in one block, with irregular spacing and some missing columns
+        for i in 0..4500u32 {
+            let timestamp = base_time + (i as u128) * 1000 + (i % 7) as u128;
+            let mut row = doc! {
+                "store": stores[(i % 3) as usize],
+                "temperature": 20.0 + (i as f64 * 0.37).sin(),
+                "in_stock": i % 5 != 0,
+            };
+            // Integers in a number column have to survive as integers
+            if i % 4 != 0 {
+                row.insert("count", i as i32);
+            }
+            original_db.insert_to_database(timestamp, row).unwrap();
+        }
+
+        let test_filename = format!("test_segment_{}", chrono::Utc::now().timestamp_nanos_opt().unwrap());
+        original_db.save(format!("{}.r2seg", test_filename));
+
+        let segment_path = format!("{}/{}.r2seg", DATA_PATH, test_filename);
+        assert!(Path::new(&segment_path).exists());
+
+        let mut loaded_db = create_test_db();
+        assert!(loaded_db.load(format!("{}.r2seg", test_filename)).is_ok());
+
+        let original_rows = original_db.get_range(u128::MIN, u128::MAX);
+        let loaded_rows = loaded_db.get_range(u128::MIN, u128::MAX);
+        assert_eq!(loaded_rows.len(), 4500);
+        assert_eq!(original_rows, loaded_rows);
+
+        let metadata = loaded_db.query(String::from("LIST::METADATA"));
+        let schema = metadata.get_document("rows").unwrap().get_document("schema").unwrap();
+        assert_eq!(schema.get_str("in_stock").unwrap(), "boolean");
+
+        // Same data as a BSON save, for comparison
+        original_db.save(test_filename.clone());
+        let bson_path = format!("{}/{}.r2d2", DATA_PATH, test_filename);
+        assert!(fs::metadata(&segment_path).unwrap().len() * 3 < fs::metadata(&bson_path).unwrap().len());
+
+        let _ = fs::remove_file(segment_path);
+        let _ = fs::remove_file(bson_path);
+    }
+
+    #[test]
+    fn test_load_corrupt_segment_fails() {
+        let mut db = create_mixed_db();
+        db.insert_to_database(1, doc! { "store": "Walmart", "temperature": 1.5 }).unwrap();
+
+        let test_filename = format!("test_segment_corrupt_{}", chrono::Utc::now().timestamp_nanos_opt().unwrap());
+        db.save(format!("{}.r2seg", test_filename));
+
+        // Flip a byte in the last block's payload
+        let segment_path = format!("{}/{}.r2seg", DATA_PATH, test_filename);
+        let mut bytes = fs::read(&segment_path).unwrap();
+        let last = bytes.len() - 6;
+        bytes[last] ^= 0xff;
+        fs::write(&segment_path, bytes).unwrap();
+
+        let mut loaded_db = create_mixed_db();
+        assert!(loaded_db.load(format!("{}.r2seg", test_filename)).is_err());
+
+        let _ = fs::remove_file(segment_path);
+    }
+
+    #[test]
+    fn test_streaming_reader_yields_rows_in_order() {
+        let mut db = create_test_db();
+        populate_test_db(&mut db);
+
+        let test_filename = format!("test_stream_{}", chrono::Utc::now().timestamp_nanos_opt().unwrap());
+        db.save(test_filename.clone());
+        let file_path = format!("{}/{}.r2d2", DATA_PATH, test_filename);
+
+        let reader = BsonSaveReader::open(fs::File::open(&file_path).unwrap()).unwrap();
+        assert!(reader.header().get_document("schema").is_ok());
+        assert_eq!(reader.header().get_str("min_timestamp").unwrap(), "1733697225000");
+
+        let keys = reader.map(|row| row.unwrap().0).collect::<Vec<u128>>();
+        assert_eq!(keys, (0..5).map(|i| 1733697225000 + i).collect::<Vec<u128>>());
+
+        let _ = fs::remove_file(file_path);
+    }
+
+    #[test]
+    fn test_load_empty_and_truncated_files() {
+        // The database shipped in the repo still loads
+        let mut db = create_test_db();
+        assert!(db.load(String::from("empty.r2d2")).is_ok());
+        assert_eq!(db.get_range(u128::MIN, u128::MAX).len(), 0);
+
+        let mut original_db = create_test_db();
+        populate_test_db(&mut original_db);
+
+        let test_filename = format!("test_truncated_{}", chrono::Utc::now().timestamp_nanos_opt().unwrap());
+        original_db.save(test_filename.clone());
+        let file_path = format!("{}/{}.r2d2", DATA_PATH, test_filename);
+
+        // Cut the file off partway through the rows
+        let bytes = fs::read(&file_path).unwrap();
+        fs::write(&file_path, &bytes[..bytes.len() - 40]).unwrap();
+
+        let mut loaded_db = create_test_db();
+        assert!(loaded_db.load(format!("{}.r2d2", test_filename)).is_err());
+
+        let _ = fs::remove_file(file_path);
+    }
+
+    #[test]
+    fn test_save_and_load_keep_duplicate_policy() {
+        let mut original_db = create_test_db();
+        populate_test_db(&mut original_db);
+        original_db.set_duplicate_policy(DuplicatePolicy::Merge).unwrap();
+
+        let test_filename = format!("test_policy_{}", chrono::Utc::now().timestamp_nanos_opt().unwrap());
+        for extension in ["r2d2", "r2seg"] {
+            original_db.save(format!("{}.{}", test_filename, extension));
+
+            let mut loaded_db = create_test_db();
+            loaded_db.load(format!("{}.{}", test_filename, extension)).unwrap();
+            assert_eq!(loaded_db.duplicate_policy(), DuplicatePolicy::Merge);
+
+            let _ = fs::remove_file(format!("{}/{}.{}", DATA_PATH, test_filename, extension));
+        }
+    }
+
+    #[test]
+    fn test_load_ignores_stale_saved_bounds() {
+        // Saves from before deletes kept the bounds right can have ones that no row matches
+        let test_filename = format!("test_stale_bounds_{}.r2d2", chrono::Utc::now().timestamp_nanos_opt().unwrap());
+        let file_path = format!("{}/{}", DATA_PATH, test_filename);
+        let saved = doc! {
+            "schema": { "store": "number", "product": "number", "number_sold": "number" },
+            "min_timestamp": "1",
+            "max_timestamp": "999",
+            "rows": { "10": { "store": 1.0 }, "20": { "store": 2.0 } },
+        };
+        let mut bytes = Vec::new();
+        saved.to_writer(&mut bytes).unwrap();
+        fs::write(&file_path, bytes).unwrap();
+
+        let mut db = create_test_db();
+        db.load(test_filename).unwrap();
+        assert_eq!(db.time_bounds(), Some((10, 20)));
+
+        let _ = fs::remove_file(file_path);
+    }
 }
@@ -0,0 +1,124 @@
+use r2d2p2::transform::{Argument, Change, Differ, TransformFunction, Transformer, Window};
+
+#[cfg(test)]
+mod transform_tests {
+    use super::*;
+
+    // Applies function to each reading's change from the one before
+    fn series(function: TransformFunction, readings: &[(u128, f64)]) -> Vec<Option<f64>> {
+        let mut differ = Differ::default();
+        readings.iter()
+            .filter_map(|(key, value)| differ.push(*key, *value))
+            .map(|change| function.apply(&change))
+            .collect()
+    }
+
+    #[test]
+    fn test_counter_with_reset() {
+        // A counter that resets to 0 between the third and fourth reading
+        let readings = [(0, 10.0), (1000, 30.0), (2000, 60.0), (4000, 5.0), (5000, 25.0)];
+
+        assert_eq!(
+            series(TransformFunction::Difference, &readings),
+            vec![Some(20.0), Some(30.0), Some(-55.0), Some(20.0)]
+        );
+        assert_eq!(
+            series(TransformFunction::Derivative { unit: 1000 }, &readings),
+            vec![Some(20.0), Some(30.0), Some(-27.5), Some(20.0)]
+        );
+        assert_eq!(
+            series(TransformFunction::NonNegativeDerivative { unit: 1000 }, &readings),
+            vec![Some(20.0), Some(30.0), None, Some(20.0)]
+        );
+
+        // After a reset the counter counted up from 0 to 5 over those two seconds
+        assert_eq!(
+            series(TransformFunction::Rate { unit: 1000 }, &readings),
+            vec![Some(20.0), Some(30.0), Some(2.5), Some(20.0)]
+        );
+
+        // Per millisecond by default
+        assert_eq!(series(TransformFunction::Rate { unit: 1 }, &readings[..2]), vec![Some(0.02)]);
+    }
+
+    #[test]
+    fn test_changes_add_up() {
+        let mut total = Change { delta: 10.0, increase: 10.0, elapsed: 500 };
+        total.add(Change { delta: -4.0, increase: 2.0, elapsed: 1500 });
+
+        assert_eq!(TransformFunction::Difference.apply(&total), Some(6.0));
+        assert_eq!(TransformFunction::Rate { unit: 1000 }.apply(&total), Some(6.0));
+        assert_eq!(TransformFunction::NonNegativeDerivative { unit: 1 }.apply(&total), Some(0.003));
+    }
+
+    #[test]
+    fn test_names_and_labels() {
+        assert_eq!(TransformFunction::from_parts("rate", None), Ok(TransformFunction::Rate { unit: 1 }));
+        assert_eq!(
+            TransformFunction::from_parts("NON_NEGATIVE_DERIVATIVE", Some(Argument::Duration(1000))),
+            Ok(TransformFunction::NonNegativeDerivative { unit: 1000 })
+        );
+        assert!(TransformFunction::from_parts("DIFFERENCE", Some(Argument::Duration(1000))).is_err());
+        assert!(TransformFunction::from_parts("INTEGRAL", None).is_err());
+
+        assert_eq!(TransformFunction::Rate { unit: 1000 }.label("requests"), "RATE(requests, 1000ms)");
+        assert_eq!(TransformFunction::Derivative { unit: 1 }.label("requests"), "DERIVATIVE(requests)");
+    }
+
+    fn transformed(function: TransformFunction, readings: &[(u128, f64)]) -> Vec<Option<f64>> {
+        let mut transformer = Transformer::new(function);
+        readings.iter().map(|(key, value)| transformer.push(*key, *value)).collect()
+    }
+
+    #[test]
+    fn test_moving_windows() {
+        let readings = [(0, 2.0), (1000, 4.0), (2000, 6.0), (5000, 8.0), (5500, 10.0)];
+
+        // Over rows, once there are enough of them
+        assert_eq!(
+            transformed(TransformFunction::MovingAverage(Window::Rows(3)), &readings),
+            vec![None, None, Some(4.0), Some(6.0), Some(8.0)]
+        );
+
+        // Over the last 2 seconds, however many readings that is
+        assert_eq!(
+            transformed(TransformFunction::MovingAverage(Window::Time(2000)), &readings),
+            vec![Some(2.0), Some(3.0), Some(5.0), Some(8.0), Some(9.0)]
+        );
+
+        assert_eq!(
+            transformed(TransformFunction::Ewma { alpha: 0.5 }, &readings),
+            vec![Some(2.0), Some(3.0), Some(4.5), Some(6.25), Some(8.125)]
+        );
+        assert_eq!(
+            transformed(TransformFunction::CumulativeSum, &readings),
+            vec![Some(2.0), Some(6.0), Some(12.0), Some(20.0), Some(30.0)]
+        );
+
+        // Change functions give the same through a Transformer
+        assert_eq!(
+            transformed(TransformFunction::Difference, &readings),
+            vec![None, Some(2.0), Some(2.0), Some(2.0), Some(2.0)]
+        );
+    }
+
+    #[test]
+    fn test_window_arguments() {
+        assert_eq!(
+            TransformFunction::from_parts("moving_average", Some(Argument::Number(5.0))),
+            Ok(TransformFunction::MovingAverage(Window::Rows(5)))
+        );
+        assert_eq!(
+            TransformFunction::from_parts("MOVING_AVERAGE", Some(Argument::Duration(300000))),
+            Ok(TransformFunction::MovingAverage(Window::Time(300000)))
+        );
+        assert!(TransformFunction::from_parts("MOVING_AVERAGE", None).is_err());
+        assert!(TransformFunction::from_parts("MOVING_AVERAGE", Some(Argument::Number(2.5))).is_err());
+        assert!(TransformFunction::from_parts("EWMA", Some(Argument::Number(1.5))).is_err());
+        assert!(TransformFunction::from_parts("CUMULATIVE_SUM", Some(Argument::Number(1.0))).is_err());
+
+        assert_eq!(TransformFunction::MovingAverage(Window::Time(60000)).label("t"), "MOVING_AVERAGE(t, 60000ms)");
+        assert_eq!(TransformFunction::Ewma { alpha: 0.3 }.label("t"), "EWMA(t, 0.3)");
+        assert!(!TransformFunction::CumulativeSum.is_change());
+    }
+}
@@ -0,0 +1,312 @@
+use r2d2p2::database::{Database, DuplicatePolicy, DATA_PATH};
+use r2d2p2::wal::{CheckpointPolicy, SyncPolicy};
+use bson::doc;
+use std::fs;
+use std::fs::OpenOptions;
+use std::io::Write;
+
+#[cfg(test)]
+mod wal_tests {
+    use super::*;
+
+    // Helper function to create a test database
+    fn create_test_db() -> Database {
+        Database::new(
+            vec![
+                String::from("store"),
+                String::from("product"),
+                String::from("number_sold")
+            ],
+            vec![
+                String::from("number"),
+                String::from("number"),
+                String::from("number")
+            ]
+        )
+    }
+
+    // Each test gets its own log so they can run in parallel
+    fn unique_wal_name(prefix: &str) -> String {
+        format!("{}_{}.wal", prefix, chrono::Utc::now().timestamp_nanos_opt().unwrap())
+    }
+
+    // Removes the log and every checkpoint taken from it
+    fn remove_wal_files(wal_name: &str) {
+        for entry in fs::read_dir(DATA_PATH).unwrap().flatten() {
+            if entry.file_name().to_string_lossy().starts_with(wal_name) {
+                let _ = fs::remove_file(entry.path());
+            }
+        }
+    }
+
+    #[test]
+    fn test_wal_replays_inserts_and_removes() {
+        let wal_name = unique_wal_name("test_wal_replay");
+        let base_time: u128 = 1733697225000;
+
+        let mut db = create_test_db();
+        assert_eq!(db.open_wal(wal_name.clone(), SyncPolicy::Always).unwrap(), 0);
+
+        for i in 0..3 {
+            db.insert_to_database(base_time + i as u128, doc! { "store": i, "product": 100 + i }).unwrap();
+        }
+        db.query(format!("REMOVE::ONE::TIMESTAMP={}", base_time + 1));
+        drop(db);
+
+        // A fresh process would start from an empty tree and rebuild it from the log
+        let mut recovered = create_test_db();
+        assert_eq!(recovered.open_wal(wal_name.clone(), SyncPolicy::Always).unwrap(), 4);
+
+        assert!(recovered.get_one(base_time).is_some());
+        assert!(recovered.get_one(base_time + 1).is_none());
+        assert_eq!(recovered.get_one(base_time + 2).unwrap().get_i32("product").unwrap(), 102);
+
+        // Mutations after replay keep going to the same log
+        recovered.insert_to_database(base_time + 3, doc! { "store": 3 }).unwrap();
+        drop(recovered);
+
+        let mut recovered_again = create_test_db();
+        assert_eq!(recovered_again.open_wal(wal_name.clone(), SyncPolicy::Never).unwrap(), 5);
+        assert!(recovered_again.get_one(base_time + 3).is_some());
+
+        let _ = fs::remove_file(format!("{}/{}", DATA_PATH, wal_name));
+    }
+
+    #[test]
+    fn test_wal_discards_torn_tail() {
+        let wal_name = unique_wal_name("test_wal_torn");
+        let wal_path = format!("{}/{}", DATA_PATH, wal_name);
+
+        let mut db = create_test_db();
+        db.open_wal(wal_name.clone(), SyncPolicy::EveryN(2)).unwrap();
+        db.insert_to_database(1, doc! { "store": 1 }).unwrap();
+        db.insert_to_database(2, doc! { "store": 2 }).unwrap();
+        drop(db);
+
+        let intact_len = fs::metadata(&wal_path).unwrap().len();
+
+        // Simulate a crash halfway through writing a third record
+        let mut file = OpenOptions::new().append(true).open(&wal_path).unwrap();
+        file.write_all(&[0x30, 0x00, 0x00, 0x00, 0xde, 0xad]).unwrap();
+        drop(file);
+
+        let mut recovered = create_test_db();
+        assert_eq!(recovered.open_wal(wal_name.clone(), SyncPolicy::Always).unwrap(), 2);
+        assert!(recovered.get_one(1).is_some());
+        assert!(recovered.get_one(2).is_some());
+        assert_eq!(fs::metadata(&wal_path).unwrap().len(), intact_len);
+
+        let _ = fs::remove_file(wal_path);
+    }
+
+    #[test]
+    fn test_checkpoint_truncates_log_and_replays_only_tail() {
+        let wal_name = unique_wal_name("test_wal_checkpoint");
+        let wal_path = format!("{}/{}", DATA_PATH, wal_name);
+
+        let mut db = create_test_db();
+        db.open_wal(wal_name.clone(), SyncPolicy::Always).unwrap();
+        for i in 0..3 {
+            db.insert_to_database(i, doc! { "store": i as i32 }).unwrap();
+        }
+
+        db.checkpoint().unwrap();
+        assert_eq!(fs::metadata(&wal_path).unwrap().len(), 0);
+        assert!(fs::read_dir(DATA_PATH).unwrap().flatten()
+            .any(|e| e.file_name().to_string_lossy() == format!("{}.3.checkpoint", wal_name)));
+
+        db.insert_to_database(3, doc! { "store": 3 }).unwrap();
+        drop(db);
+
+        // Only the insert after the checkpoint should need replaying
+        let mut recovered = create_test_db();
+        assert_eq!(recovered.open_wal(wal_name.clone(), SyncPolicy::Always).unwrap(), 1);
+        for i in 0..4 {
+            assert!(recovered.get_one(i).is_some());
+        }
+
+        // Sequence numbers carry on after the checkpoint, so the next one sorts after it
+        recovered.checkpoint().unwrap();
+        recovered.insert_to_database(4, doc! { "store": 4 }).unwrap();
+        recovered.checkpoint().unwrap();
+        let checkpoints = fs::read_dir(DATA_PATH).unwrap().flatten()
+            .filter(|e| e.file_name().to_string_lossy().starts_with(&format!("{}.", wal_name)))
+            .count();
+        assert_eq!(checkpoints, 2);
+        drop(recovered);
+
+        let mut recovered_again = create_test_db();
+        assert_eq!(recovered_again.open_wal(wal_name.clone(), SyncPolicy::Always).unwrap(), 0);
+        assert!(recovered_again.get_one(4).is_some());
+
+        remove_wal_files(&wal_name);
+    }
+
+    #[test]
+    fn test_checkpoint_policy_after_n_mutations() {
+        let wal_name = unique_wal_name("test_wal_policy");
+        let wal_path = format!("{}/{}", DATA_PATH, wal_name);
+
+        let mut db = create_test_db();
+        db.open_wal(wal_name.clone(), SyncPolicy::Always).unwrap();
+        db.set_checkpoint_policy(CheckpointPolicy { every_mutations: Some(2), interval: None });
+
+        db.insert_to_database(1, doc! { "store": 1 }).unwrap();
+        db.maybe_checkpoint();
+        assert!(fs::metadata(&wal_path).unwrap().len() > 0);
+
+        db.insert_to_database(2, doc! { "store": 2 }).unwrap();
+        db.maybe_checkpoint();
+        assert_eq!(fs::metadata(&wal_path).unwrap().len(), 0);
+
+        remove_wal_files(&wal_name);
+    }
+
+    #[test]
+    fn test_bulk_load_is_logged() {
+        let wal_name = unique_wal_name("test_wal_bulk");
+
+        let mut db = create_test_db();
+        db.open_wal(wal_name.clone(), SyncPolicy::Always).unwrap();
+        db.bulk_load((0..10).map(|i| (i as u128, doc! { "store": i })));
+        drop(db);
+
+        let mut recovered = create_test_db();
+        assert_eq!(recovered.open_wal(wal_name.clone(), SyncPolicy::Always).unwrap(), 10);
+        assert_eq!(recovered.get_range(u128::MIN, u128::MAX).len(), 10);
+
+        remove_wal_files(&wal_name);
+    }
+
+    #[test]
+    fn test_wal_replays_range_delete() {
+        let wal_name = unique_wal_name("test_wal_range_delete");
+
+        let mut db = create_test_db();
+        db.open_wal(wal_name.clone(), SyncPolicy::Always).unwrap();
+        for i in 0..5 {
+            db.insert_to_database(i, doc! { "store": i as i32 }).unwrap();
+        }
+        db.query(String::from("DELETE RANGE 2, 4"));
+        drop(db);
+
+        // Each removed row was logged, and replaying them leaves the bounds where they were
+        let mut recovered = create_test_db();
+        assert_eq!(recovered.open_wal(wal_name.clone(), SyncPolicy::Always).unwrap(), 8);
+        assert_eq!(recovered.get_range(0, u128::MAX).len(), 2);
+        assert_eq!(recovered.time_bounds(), Some((0, 1)));
+
+        remove_wal_files(&wal_name);
+    }
+
+    #[test]
+    fn test_wal_replays_updates() {
+        let wal_name = unique_wal_name("test_wal_updates");
+
+        let mut db = create_test_db();
+        db.open_wal(wal_name.clone(), SyncPolicy::Always).unwrap();
+        db.insert_to_database(1, doc! { "store": 1.0, "product": 7.0 }).unwrap();
+        db.query(String::from("UPDATE product = 8 AT 1"));
+        db.query(String::from("UPSERT store = 2 AT 1"));
+        drop(db);
+
+        let mut recovered = create_test_db();
+        assert_eq!(recovered.open_wal(wal_name.clone(), SyncPolicy::Always).unwrap(), 3);
+        assert_eq!(recovered.get_one(1).unwrap(), doc! { "store": 2.0 });
+        assert_eq!(recovered.get_range(0, u128::MAX).len(), 1);
+
+        remove_wal_files(&wal_name);
+    }
+
+    #[test]
+    fn test_wal_replays_duplicate_policy_outcomes() {
+        let wal_name = unique_wal_name("test_wal_duplicates");
+
+        let mut db = create_test_db();
+        db.open_wal(wal_name.clone(), SyncPolicy::Always).unwrap();
+        db.set_duplicate_policy(DuplicatePolicy::Merge).unwrap();
+        db.insert_to_database(1, doc! { "store": 1.0, "product": 7.0 }).unwrap();
+        db.insert_to_database(1, doc! { "number_sold": 4.0 }).unwrap();
+        db.set_duplicate_policy(DuplicatePolicy::Shift).unwrap();
+        db.insert_to_database(1, doc! { "store": 2.0 }).unwrap();
+        drop(db);
+
+        // Replay lands rows where they ended up, whatever the recovering database's policy, and
+        // leaves the policy as it was last set
+        let mut recovered = create_test_db();
+        recovered.set_duplicate_policy(DuplicatePolicy::Reject).unwrap();
+        assert_eq!(recovered.open_wal(wal_name.clone(), SyncPolicy::Always).unwrap(), 5);
+        assert_eq!(recovered.get_one(1).unwrap(), doc! { "store": 1.0, "product": 7.0, "number_sold": 4.0 });
+        assert_eq!(recovered.get_one(2).unwrap(), doc! { "store": 2.0 });
+        assert_eq!(recovered.duplicate_policy(), DuplicatePolicy::Shift);
+
+        remove_wal_files(&wal_name);
+    }
+
+    #[test]
+    fn test_checkpoint_streams_segment_blocks() {
+        // Splitting a leaf of FAN_OUT rows needs more than the default test thread's stack in a
+        // debug build, so this runs on its own thread.
+        std::thread::Builder::new().stack_size(64 << 20).spawn(|| {
+            let wal_name = unique_wal_name("test_wal_segment_checkpoint");
+            let rows: u128 = 5000;
+
+            let mut db = create_test_db();
+            db.open_wal(wal_name.clone(), SyncPolicy::Never).unwrap();
+            for i in 0..rows {
+                db.insert_to_database(i, doc! { "store": i as f64 }).unwrap();
+            }
+            db.checkpoint().unwrap();
+            drop(db);
+
+            // Checkpoints are segment files, so a table past one block is written a block at a time
+            let checkpoint = fs::read(format!("{}/{}.{}.checkpoint", DATA_PATH, wal_name, rows)).unwrap();
+            assert!(checkpoint.starts_with(b"R2SEG"));
+
+            let mut recovered = create_test_db();
+            assert_eq!(recovered.open_wal(wal_name.clone(), SyncPolicy::Always).unwrap(), 0);
+            assert_eq!(recovered.time_bounds(), Some((0, rows - 1)));
+            assert_eq!(recovered.get_one(rows - 1).unwrap(), doc! { "store": (rows - 1) as f64 });
+
+            remove_wal_files(&wal_name);
+        }).unwrap().join().unwrap();
+    }
+
+    #[test]
+    fn test_checkpoint_keeps_duplicate_policy() {
+        let wal_name = unique_wal_name("test_wal_policy_checkpoint");
+
+        let mut db = create_test_db();
+        db.open_wal(wal_name.clone(), SyncPolicy::Always).unwrap();
+        db.set_duplicate_policy(DuplicatePolicy::Reject).unwrap();
+        db.checkpoint().unwrap();
+        drop(db);
+
+        let mut recovered = create_test_db();
+        assert_eq!(recovered.open_wal(wal_name.clone(), SyncPolicy::Always).unwrap(), 0);
+        assert_eq!(recovered.duplicate_policy(), DuplicatePolicy::Reject);
+
+        remove_wal_files(&wal_name);
+    }
+
+    #[test]
+    fn test_wal_replays_batch_insert() {
+        let wal_name = unique_wal_name("test_wal_batch");
+
+        let mut db = create_test_db();
+        db.open_wal(wal_name.clone(), SyncPolicy::Always).unwrap();
+        db.insert_to_database(1, doc! { "store": 1.0 }).unwrap();
+        db.query_with_body(String::from("INSERT BATCH CSV"), "timestamp,store\n1,2\n3,3\n");
+        // Rejected as a whole, so nothing is logged
+        db.query_with_body(String::from("INSERT BATCH CSV"), "timestamp,store\n4,4\n5,x\n");
+        drop(db);
+
+        let mut recovered = create_test_db();
+        assert_eq!(recovered.open_wal(wal_name.clone(), SyncPolicy::Always).unwrap(), 3);
+        assert_eq!(recovered.get_one(2).unwrap(), doc! { "store": 2.0 });
+        assert_eq!(recovered.get_one(3).unwrap(), doc! { "store": 3.0 });
+        assert!(recovered.get_one(4).is_none());
+
+        remove_wal_files(&wal_name);
+    }
+}